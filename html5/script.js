@@ -4,6 +4,8 @@ const host = window.location.host;
 const loginOverlay = document.getElementById('login-overlay');
 const loginButton = document.getElementById('login-button');
 const registerButton = document.getElementById('register-button');
+const forgotButton = document.getElementById('forgot-button');
+const oauthButtons = document.getElementById('oauth-buttons');
 const usernameInput = document.getElementById('username');
 const passwordInput = document.getElementById('password');
 const loginError = document.getElementById('login-error');
@@ -15,6 +17,16 @@ const logoutButton = document.getElementById('logout-button');
 
 let socket;
 let currentSessionToken = null;
+let currentRoom = 'global';
+// username -> timeout clearing their typing indicator
+const typers = new Map();
+let lastTypingSent = 0;
+
+function renderTypers() {
+    input.placeholder = typers.size > 0
+        ? `${[...typers.keys()].join(', ')} typing...`
+        : 'Type a message...';
+}
 
 async function checkSession() {
     try {
@@ -37,6 +49,51 @@ async function checkSession() {
     }
 }
 
+// Leading zero bits of a SHA-256 digest
+function leadingZeroBits(bytes) {
+    let bits = 0;
+    for (const byte of bytes) {
+        if (byte === 0) {
+            bits += 8;
+            continue;
+        }
+        bits += Math.clz32(byte) - 24;
+        break;
+    }
+    return bits;
+}
+
+// Find a nonce so SHA-256("<challenge>:<nonce>") starts with `difficulty` zero bits
+async function solveProofOfWork(challenge, difficulty) {
+    const encoder = new TextEncoder();
+    for (let nonce = 0; ; nonce++) {
+        const digest = await crypto.subtle.digest('SHA-256', encoder.encode(`${challenge}:${nonce}`));
+        if (leadingZeroBits(new Uint8Array(digest)) >= difficulty) {
+            return String(nonce);
+        }
+    }
+}
+
+// Invite code and proof of work, when the server asks for them
+async function registerExtras() {
+    const response = await fetch('/api/register/requirements');
+    const requirements = await response.json();
+    const extras = {};
+    const email = prompt("Email address, optional (needed to reset a forgotten password):");
+    if (email) {
+        extras.email = email.trim();
+    }
+    if (requirements.invite_code_required) {
+        extras.invite_code = prompt("Invite code:") || "";
+    }
+    if (requirements.pow_challenge) {
+        showSuccess("Checking you're not a bot...");
+        extras.pow_challenge = requirements.pow_challenge;
+        extras.pow_nonce = await solveProofOfWork(requirements.pow_challenge, requirements.pow_difficulty);
+    }
+    return extras;
+}
+
 async function handleAuth(endpoint) {
     const username = usernameInput.value;
     const password = passwordInput.value;
@@ -47,18 +104,41 @@ async function handleAuth(endpoint) {
     }
 
     try {
+        const extras = endpoint === 'register' ? await registerExtras() : {};
         const response = await fetch(`/api/${endpoint}`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
-            body: JSON.stringify({ username, password })
+            body: JSON.stringify({ username, password, ...extras })
         });
 
-        const data = await response.json();
+        let data = await response.json();
+
+        // Accounts with two-factor authentication need a code before getting a session
+        if (response.ok && data.challenge_token) {
+            const code = prompt(data.message);
+            if (!code) {
+                showError("Login cancelled");
+                return;
+            }
+            const totpResponse = await fetch('/api/login/totp', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ challenge_token: data.challenge_token, code: code.trim() })
+            });
+            data = await totpResponse.json();
+            if (!totpResponse.ok) {
+                showError(data || "Authentication failed");
+                return;
+            }
+        }
 
         if (response.ok) {
             showSuccess(data.message);
             currentSessionToken = data.session_token;
             startChat();
+        } else if (data && data.errors) {
+            // 422 from registering: what's wrong with the username and password
+            showError(data.errors.map(e => e.message).join('. '));
         } else {
             showError(data || "Authentication failed");
         }
@@ -70,6 +150,108 @@ async function handleAuth(endpoint) {
 loginButton.onclick = () => handleAuth('login');
 registerButton.onclick = () => handleAuth('register');
 
+forgotButton.onclick = async () => {
+    const login = usernameInput.value || prompt("Username or email address:");
+    if (!login) {
+        return;
+    }
+    try {
+        const response = await fetch('/api/password/forgot', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ login })
+        });
+        const data = await response.json();
+        if (response.ok) {
+            showSuccess(data);
+        } else {
+            showError(data);
+        }
+    } catch (err) {
+        showError("Server error, please try again later");
+    }
+};
+
+// One button per single sign-on provider the server has
+async function loadOAuthProviders() {
+    try {
+        const response = await fetch('/api/oauth/providers');
+        const providers = await response.json();
+        for (const provider of providers) {
+            const button = document.createElement('button');
+            button.className = 'secondary';
+            button.textContent = `Sign in with ${provider}`;
+            button.onclick = () => { window.location.href = `/api/oauth/${encodeURIComponent(provider)}/start`; };
+            oauthButtons.appendChild(button);
+        }
+    } catch (err) {
+        console.error("Failed to load sign-in providers", err);
+    }
+}
+
+// Back from a single sign-on provider: an error to show, or a 2FA code to ask for
+async function handleOAuthReturn() {
+    const params = new URLSearchParams(window.location.search);
+    const error = params.get('login_error');
+    const challenge_token = params.get('totp_challenge');
+    if (!error && !challenge_token) {
+        return;
+    }
+    history.replaceState(null, '', window.location.pathname);
+    if (error) {
+        showError(error);
+        return;
+    }
+    const code = prompt("Enter the code from your authenticator app");
+    if (!code) {
+        showError("Login cancelled");
+        return;
+    }
+    try {
+        const response = await fetch('/api/login/totp', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ challenge_token, code: code.trim() })
+        });
+        if (!response.ok) {
+            showError(await response.json() || "Authentication failed");
+        }
+    } catch (err) {
+        showError("Server error, please try again later");
+    }
+}
+
+// Opened from a password reset mail
+async function handleResetLink() {
+    const params = new URLSearchParams(window.location.search);
+    const token = params.get('reset_token');
+    if (!token) {
+        return;
+    }
+    history.replaceState(null, '', window.location.pathname);
+    const new_password = prompt("New password:");
+    if (!new_password) {
+        return;
+    }
+    try {
+        const response = await fetch('/api/password/reset', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ token, new_password })
+        });
+        const data = await response.json();
+        if (response.ok) {
+            showSuccess(data);
+        } else if (data && data.errors) {
+            showError(data.errors.map(e => e.message).join('. '));
+        } else {
+            showError(data || "Password reset failed");
+        }
+    } catch (err) {
+        showError("Server error, please try again later");
+    }
+}
+
 logoutButton.onclick = async () => {
     try {
         await fetch('/api/logout', { method: 'POST' });
@@ -92,19 +274,56 @@ function showSuccess(msg) {
     loginError.classList.add('hidden');
 }
 
-async function load_history(limit) {
+function renderReactions(messageDiv, reactions) {
+    let span = messageDiv.querySelector('.reactions');
+    if (!span) {
+        span = document.createElement('span');
+        span.className = 'reactions';
+        messageDiv.appendChild(span);
+    }
+    span.textContent = reactions.map(r => ` ${r.emoji} ${r.count}`).join('');
+}
+
+async function load_history(limit, room) {
     try {
-        const response = await fetch('/api/get_chat_history?limit=' + limit, {
+        const response = await fetch('/api/v2/chat_history?limit=' + limit + '&room=' + encodeURIComponent(room), {
             method: 'GET',
         });
 
-        const data = await response.json();
+        let data = await response.json();
+
+        // Accounts with two-factor authentication need a code before getting a session
+        if (response.ok && data.challenge_token) {
+            const code = prompt(data.message);
+            if (!code) {
+                showError("Login cancelled");
+                return;
+            }
+            const totpResponse = await fetch('/api/login/totp', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ challenge_token: data.challenge_token, code: code.trim() })
+            });
+            data = await totpResponse.json();
+            if (!totpResponse.ok) {
+                showError(data || "Authentication failed");
+                return;
+            }
+        }
 
         if (response.ok) {
-            data.forEach(current_message => {
+            // Pages come newest first, the chat reads top to bottom
+            data.messages.reverse().forEach(current_message => {
                 const messageDiv = document.createElement('div');
                 messageDiv.className = 'message';
-                messageDiv.innerHTML = `<strong>${current_message.username}:</strong> ${current_message.content}`;
+                messageDiv.dataset.messageId = current_message.message_id;
+                if (current_message.deleted) {
+                    messageDiv.innerHTML = `<em>message deleted</em>`;
+                } else {
+                    const edited = current_message.edited_at ? ' <em>(edited)</em>' : '';
+                    messageDiv.innerHTML = `<strong>${current_message.username}:</strong> ${current_message.content}${edited}`;
+                    renderReactions(messageDiv, current_message.reactions);
+                }
                 messages.appendChild(messageDiv);
                 messages.scrollTop = messages.scrollHeight;
             });
@@ -123,10 +342,43 @@ function startChat() {
 
     socket = new WebSocket(`${protocol}//${host}/ws`);
 
-    load_history(50);
+    load_history(50, currentRoom);
 
     socket.onmessage = function (event) {
         const msg = JSON.parse(event.data);
+
+        if (msg.type === 'typing') {
+            if (msg.room !== currentRoom) {
+                return;
+            }
+            clearTimeout(typers.get(msg.username));
+            const ttl = Math.max(0, new Date(msg.expires_at) - Date.now());
+            typers.set(msg.username, setTimeout(() => {
+                typers.delete(msg.username);
+                renderTypers();
+            }, ttl));
+            renderTypers();
+            return;
+        }
+
+        // Edits, deletes and reactions update the message in place
+        if (msg.type === 'reaction') {
+            const existing = messagesDiv.querySelector(`[data-message-id="${msg.message_id}"]`);
+            if (existing) {
+                renderReactions(existing, msg.reactions);
+            }
+            return;
+        }
+        if (msg.type === 'edit' || msg.type === 'delete') {
+            const existing = messagesDiv.querySelector(`[data-message-id="${msg.message_id}"]`);
+            if (existing) {
+                existing.innerHTML = msg.type === 'edit'
+                    ? `<strong>${msg.username}:</strong> ${msg.content} <em>(edited)</em>`
+                    : `<em>message deleted</em>`;
+            }
+            return;
+        }
+
         const messageElement = document.createElement('div');
         messageElement.className = 'message';
 
@@ -144,17 +396,35 @@ function startChat() {
                 }
                 break;
             case 'who':
-                // Internal server probe — display as system info
+                // Answer to /who — the online member list
                 messageElement.classList.add('system-message');
-                messageElement.innerHTML = `<strong>[SYSTEM]</strong> ${msg.content}`;
+                messageElement.innerHTML = `<strong>[SYSTEM]</strong> Online: ${(msg.users || []).join(', ')}`;
                 break;
             case 'error':
                 messageElement.classList.add('error-message');
                 messageElement.innerHTML = `<strong>[ERROR]</strong> ${msg.content}`;
                 break;
+            case 'presence':
+                messageElement.classList.add('system-message');
+                messageElement.innerHTML = `<strong>[SYSTEM]</strong> ${msg.username} is ${msg.content}`;
+                break;
+            case 'rename':
+                messageElement.classList.add('system-message');
+                messageElement.innerHTML = `<strong>[SYSTEM]</strong> ${msg.username} is now ${msg.content}`;
+                break;
+            case 'join':
+            case 'leave':
+                messageElement.classList.add('system-message');
+                messageElement.innerHTML = `<strong>[#${msg.room}]</strong> ${msg.username} ${msg.type === 'join' ? 'joined' : 'left'}`;
+                break;
             case 'broadcast':
             default:
-                messageElement.innerHTML = `<strong>${msg.username}:</strong> ${msg.content}`;
+                // Tag messages from rooms other than the one we're typing into
+                const roomTag = msg.room && msg.room !== currentRoom ? `[#${msg.room}] ` : '';
+                if (msg.message_id) {
+                    messageElement.dataset.messageId = msg.message_id;
+                }
+                messageElement.innerHTML = `<strong>${roomTag}${msg.username}:</strong> ${msg.content}`;
                 break;
         }
 
@@ -186,13 +456,48 @@ input.addEventListener('keypress', (event) => {
         } else if (text.startsWith('/ephemeral ')) {
             msg = {
                 type: "ephemeral",
-                metadata: { session_id: currentSessionToken },
+                metadata: { session_id: currentSessionToken, room: currentRoom },
                 content: text.substring(11)
             };
+        } else if (text.startsWith('/join ')) {
+            // Format: /join room — also switches the room we type into
+            const room = text.substring(6).trim().replace(/^#/, '');
+            msg = {
+                type: "join",
+                metadata: { session_id: currentSessionToken, room: room },
+                content: ""
+            };
+            currentRoom = room;
+        } else if (text.startsWith('/leave ')) {
+            const room = text.substring(7).trim().replace(/^#/, '');
+            msg = {
+                type: "leave",
+                metadata: { session_id: currentSessionToken, room: room },
+                content: ""
+            };
+            if (room === currentRoom) {
+                currentRoom = 'global';
+            }
+        } else if (text.startsWith('/status ')) {
+            // Format: /status online|away|dnd
+            msg = {
+                type: "presence",
+                metadata: { session_id: currentSessionToken },
+                content: text.substring(8).trim()
+            };
+        } else if (text === '/who') {
+            msg = {
+                type: "who",
+                metadata: { session_id: currentSessionToken },
+                content: ""
+            };
+        } else if (text.startsWith('/room ')) {
+            // Format: /room name — switch between rooms already joined
+            currentRoom = text.substring(6).trim().replace(/^#/, '');
         } else {
             msg = {
                 type: "broadcast",
-                metadata: { session_id: currentSessionToken },
+                metadata: { session_id: currentSessionToken, room: currentRoom },
                 content: text
             };
         }
@@ -203,6 +508,19 @@ input.addEventListener('keypress', (event) => {
         input.value = '';
     }
 });
+// Let the room know we're typing, the server drops anything more frequent anyway
+input.addEventListener('input', () => {
+    const now = Date.now();
+    if (!socket || input.value.startsWith('/') || now - lastTypingSent < 2000) {
+        return;
+    }
+    lastTypingSent = now;
+    socket.send(JSON.stringify({
+        type: "typing",
+        metadata: { session_id: currentSessionToken, room: currentRoom },
+        content: ""
+    }));
+});
 logoutButton.addEventListener('click', async () => {
     try {
         await fetch('/api/logout', { method: 'POST' });
@@ -213,4 +531,5 @@ logoutButton.addEventListener('click', async () => {
     }
 });
 
-checkSession();
+loadOAuthProviders();
+handleResetLink().then(handleOAuthReturn).then(checkSession);
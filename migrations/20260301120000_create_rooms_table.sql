@@ -0,0 +1,17 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS rooms (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    created_by INT NULL,                -- NULL for rooms the server creates itself
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (created_by) REFERENCES app_users(id) ON DELETE SET NULL
+);
+
+-- Everything sent before rooms existed lived in the single global channel
+INSERT IGNORE INTO rooms (id, name) VALUES (1, 'global');
+
+ALTER TABLE messages
+    ADD COLUMN room_id INT NOT NULL DEFAULT 1 AFTER user_id,
+    ADD INDEX (room_id, created_at),
+    ADD FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
@@ -0,0 +1,16 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS private_messages (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    sender_id INT NOT NULL,
+    recipient_id INT NOT NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered_at TIMESTAMP NULL DEFAULT NULL,   -- NULL until it reached one of the recipient's sockets
+
+    -- Pending lookup on connect, and conversation history
+    INDEX (recipient_id, delivered_at),
+    INDEX (sender_id, recipient_id, created_at),
+    FOREIGN KEY (sender_id) REFERENCES app_users(id) ON DELETE CASCADE,
+    FOREIGN KEY (recipient_id) REFERENCES app_users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,19 @@
+-- Add migration script here
+
+ALTER TABLE messages
+    ADD COLUMN edited_at TIMESTAMP NULL DEFAULT NULL,     -- Last edit, NULL if never edited
+    ADD COLUMN deleted_at TIMESTAMP NULL DEFAULT NULL;    -- Set when the message became a tombstone
+
+CREATE TABLE IF NOT EXISTS message_edits (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    message_id BIGINT NOT NULL,
+    editor_id INT NULL,                 -- NULL once the editor's account is gone
+    action ENUM('edit', 'delete') NOT NULL,
+    old_content TEXT NOT NULL,          -- What the message said before this change
+    new_content TEXT NULL,              -- NULL for deletes
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    INDEX (message_id, created_at),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (editor_id) REFERENCES app_users(id) ON DELETE SET NULL
+);
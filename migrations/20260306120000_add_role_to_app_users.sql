@@ -0,0 +1,4 @@
+-- Add migration script here
+
+ALTER TABLE app_users
+    ADD COLUMN role ENUM('user', 'moderator', 'admin') NOT NULL DEFAULT 'user';
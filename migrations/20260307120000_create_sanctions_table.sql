@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- Mutes and bans. Kicks are instant and leave no row behind
+CREATE TABLE IF NOT EXISTS sanctions (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    kind ENUM('mute', 'ban') NOT NULL,
+    reason VARCHAR(255) NULL,
+    issued_by INT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- NULL means permanent
+    expires_at TIMESTAMP NULL,
+    -- Set when a moderator lifts it early
+    lifted_at TIMESTAMP NULL,
+    FOREIGN KEY (user_id) REFERENCES app_users(id) ON DELETE CASCADE,
+    FOREIGN KEY (issued_by) REFERENCES app_users(id) ON DELETE SET NULL,
+    INDEX idx_sanctions_user_kind (user_id, kind)
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- The token is a credential, `id` is what users refer to their sessions by
+ALTER TABLE sessions
+    ADD COLUMN id BIGINT NOT NULL AUTO_INCREMENT UNIQUE FIRST,
+    ADD COLUMN last_seen TIMESTAMP NULL,
+    ADD COLUMN user_agent VARCHAR(255) NULL,
+    ADD COLUMN ip VARCHAR(45) NULL,
+    ADD INDEX idx_sessions_user (user_id);
@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- Personal API keys for bots. The key is `gck_<prefix>_<secret>`, only the argon2 hash of the secret is kept
+CREATE TABLE IF NOT EXISTS api_keys (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    name VARCHAR(64) NOT NULL,
+    prefix CHAR(12) NOT NULL UNIQUE,
+    key_hash VARCHAR(255) NOT NULL,
+    -- Comma separated, e.g. 'read_history,broadcast'
+    scopes VARCHAR(64) NOT NULL,
+    -- '<count>/<seconds>' over every HTTP request and WebSocket message made with the key
+    rate_limit VARCHAR(32) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP NULL,
+    FOREIGN KEY (user_id) REFERENCES app_users(id) ON DELETE CASCADE,
+    INDEX idx_api_keys_user (user_id)
+);
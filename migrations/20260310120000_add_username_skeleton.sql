@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- See validation::skeleton. Filled in for existing users at startup, NULL where two of them collide
+ALTER TABLE app_users
+    ADD COLUMN username_skeleton VARCHAR(50) NULL UNIQUE AFTER username;
@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- Optional TOTP second factor. The secret is set when enrolling and only counts once enabled_at is
+ALTER TABLE app_users
+    ADD COLUMN totp_secret VARCHAR(64) NULL,
+    ADD COLUMN totp_enabled_at TIMESTAMP NULL,
+    -- Last 30s time step a code was accepted for, so a code can't be used twice
+    ADD COLUMN totp_last_step BIGINT NULL;
+
+-- Single use codes for when the authenticator is lost, only their SHA-256 is kept
+CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    used_at TIMESTAMP NULL,
+    FOREIGN KEY (user_id) REFERENCES app_users(id) ON DELETE CASCADE,
+    INDEX idx_totp_recovery_codes_user (user_id)
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- Signed session tokens are checked without looking their session up, so a session
+-- ended early is remembered here until its token would have expired anyway
+CREATE TABLE revoked_sessions (
+    token VARCHAR(255) PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL,
+    INDEX idx_revoked_sessions_expiry (expires_at)
+);
@@ -0,0 +1,20 @@
+-- Add migration script here
+
+-- Optional, only a verified address gets password reset links
+ALTER TABLE app_users
+    ADD COLUMN email VARCHAR(254) NULL,
+    ADD COLUMN email_verified_at TIMESTAMP NULL,
+    ADD INDEX idx_app_users_email (email);
+
+-- Single use links sent by mail, stored hashed like TOTP recovery codes
+CREATE TABLE email_tokens (
+    token_hash CHAR(64) PRIMARY KEY,
+    user_id INT NOT NULL,
+    purpose ENUM('verify', 'reset') NOT NULL,
+    -- The address a `verify` token confirms, in case it's changed meanwhile
+    email VARCHAR(254) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    INDEX idx_email_tokens_user (user_id, purpose),
+    FOREIGN KEY (user_id) REFERENCES app_users(id) ON DELETE CASCADE
+);
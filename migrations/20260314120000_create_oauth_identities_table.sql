@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- Accounts at outside sign-in providers, each linked to one user
+CREATE TABLE oauth_identities (
+    provider VARCHAR(64) NOT NULL,
+    -- The provider's stable id for the account (`sub`)
+    subject VARCHAR(255) NOT NULL,
+    user_id INT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_login_at TIMESTAMP NULL,
+    PRIMARY KEY (provider, subject),
+    UNIQUE KEY uq_oauth_identities_user (user_id, provider),
+    FOREIGN KEY (user_id) REFERENCES app_users(id) ON DELETE CASCADE
+);
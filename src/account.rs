@@ -0,0 +1,127 @@
+use serde::Deserialize;
+
+use crate::moderation;
+use crate::sessions;
+use crate::tables::user_db::{self, User};
+use crate::validation::{self, FieldError, Policy};
+use crate::ws_handler::{self, ChatState};
+use crate::ws_types::{OutgoingType, WsOutgoing};
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    WrongPassword,
+    /// The new password or username breaks the policy
+    Invalid(FieldError),
+    UsernameTaken,
+    Database,
+}
+
+impl AccountError {
+    pub fn message(&self) -> &str {
+        match self {
+            AccountError::WrongPassword => "Wrong password",
+            AccountError::Invalid(e) => &e.message,
+            AccountError::UsernameTaken => "User already exists",
+            AccountError::Database => "Database is not online, please try again later",
+        }
+    }
+}
+
+/// Set a new password once the old one checks out. Every session of the user is
+/// ended, `api::handle_change_password` then gives the caller a fresh one.
+pub async fn change_password(
+    state: &ChatState,
+    policy: &Policy,
+    user: &User,
+    request: &ChangePasswordRequest,
+) -> Result<(), AccountError> {
+    if user_db::verify_password(&request.old_password, &user.password_hash).is_err() {
+        return Err(AccountError::WrongPassword);
+    }
+    policy
+        .check_password(&request.new_password, &user.username)
+        .map_err(|e| AccountError::Invalid(FieldError { field: "new_password", ..e }))?;
+    user_db::update_password(&state.pool, user.id, &request.new_password)
+        .await
+        .map_err(|_| AccountError::Database)?;
+    // Whoever else knew the old password is logged out
+    let tokens = user_db::delete_user_sessions(&state.pool, user.id)
+        .await
+        .map_err(|_| AccountError::Database)?;
+    sessions::revoke(state, user.id, &tokens).await;
+    println!("User {} changed their password", user.username);
+    Ok(())
+}
+
+/// Rename a user and tell every client, so they can update names on screen. With signed
+/// session tokens every session of the user is ended, they hold the old name.
+pub async fn rename(
+    state: &ChatState,
+    policy: &Policy,
+    user: &User,
+    request: &RenameRequest,
+) -> Result<String, AccountError> {
+    let new_username = request.username.trim();
+    policy.check_username(new_username).map_err(AccountError::Invalid)?;
+    match user_db::find_user_by_skeleton(&state.pool, new_username).await {
+        // Changing the case of your own name and the like is fine
+        Ok(Some(other)) if other.id == user.id => {}
+        Ok(Some(other)) if other.username.eq_ignore_ascii_case(new_username) => {
+            return Err(AccountError::UsernameTaken);
+        }
+        Ok(Some(other)) => return Err(AccountError::Invalid(validation::lookalike(new_username, &other.username))),
+        Ok(None) => {}
+        Err(_) => return Err(AccountError::Database),
+    }
+    match user_db::rename_user(&state.pool, user.id, new_username).await {
+        Ok(()) => {}
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(AccountError::UsernameTaken),
+        Err(_) => return Err(AccountError::Database),
+    }
+
+    sessions::rename(state, user.id, new_username).await;
+
+    let out = WsOutgoing::new(OutgoingType::Rename, &user.username, new_username);
+    if let Ok(json) = serde_json::to_string(&out) {
+        ws_handler::send_to_everyone(&state.connected, &state.cluster, json).await;
+    }
+    println!("User {} is now {}", user.username, new_username);
+    Ok(new_username.to_string())
+}
+
+/// Delete a user once their password checks out. Their messages, sessions, keys and
+/// the rest go with them through `ON DELETE CASCADE`, and their sockets are closed.
+pub async fn delete(state: &ChatState, user: &User, request: &DeleteAccountRequest) -> Result<(), AccountError> {
+    if user_db::verify_password(&request.password, &user.password_hash).is_err() {
+        return Err(AccountError::WrongPassword);
+    }
+    let tokens = user_db::delete_user_sessions(&state.pool, user.id)
+        .await
+        .map_err(|_| AccountError::Database)?;
+    user_db::delete_user(&state.pool, user.id)
+        .await
+        .map_err(|_| AccountError::Database)?;
+    sessions::revoke(state, user.id, &tokens).await;
+    // Its API keys went with it
+    sessions::standing_changed(state, user.id).await;
+    // Sockets opened with an API key too
+    moderation::disconnect(state, user.id).await;
+    println!("User {} deleted their account", user.username);
+    Ok(())
+}
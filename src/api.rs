@@ -1,25 +1,93 @@
-use crate::tables::user_db::{create_session, create_user, delete_session};
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::account::{self, AccountError, ChangePasswordRequest, DeleteAccountRequest, RenameRequest};
+use crate::api_keys::{self, ApiKeyView, Scope};
+use crate::auth;
+use crate::auth_throttle::{self, AuthThrottle, KeyKind};
+use crate::connected_users::{self, ClientInfo, ConnId, ConnectedUsers};
+use crate::email::{self, ChangeEmailRequest, EmailError, Emails, ForgotPasswordRequest, ResetPasswordRequest, VerifyEmailQuery};
+use crate::moderation::{self, ModerationError, SanctionRequest};
+use crate::oauth::{self, CallbackQuery, OAuth};
+use crate::permissions::{Permission, Role};
+use crate::register_gate::RegisterGate;
+use crate::validation::{self, Policy, ValidationErrors};
+use crate::sessions;
+use crate::totp::{self, DisableTotpRequest, Totp, TotpCodeRequest, TotpError, TotpLoginRequest};
+use crate::tables::sanction_db::{self, SanctionKind};
+use crate::ws_handler::{self, ChatState};
+use crate::tables::user_db::{create_user, delete_session, SessionInfo, User};
+use crate::sessions::SessionStore;
 use warp::Filter;
 
 #[derive(serde::Deserialize)]
 pub struct LimitMessages {
     pub limit: i32,
+    /// Room to read from, `global` when omitted
+    pub room: Option<String>,
 }
+#[derive(serde::Deserialize)]
+pub struct HistoryPageQuery {
+    /// Room to read from, `global` when omitted
+    pub room: Option<String>,
+    /// Only messages older than this message id
+    pub before: Option<i64>,
+    /// Only messages newer than this message id
+    pub after: Option<i64>,
+    pub limit: Option<i32>,
+}
+
+#[derive(serde::Serialize)]
+pub struct HistoryPage {
+    /// Newest first
+    pub messages: Vec<crate::db::ReactedChatMessage>,
+    /// Pass as `before` (or `after`, if that's what you paged with) to get the next
+    /// page. `null` once there is nothing more in that direction
+    pub next_cursor: Option<i64>,
+}
+
+/// Page size bounds for the chat history endpoints
+const CHAT_HISTORY_DEFAULT_LIMIT: i32 = 50;
+const CHAT_HISTORY_MAX_LIMIT: i32 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct DmHistoryQuery {
+    /// Username of the other side of the conversation
+    pub with: String,
+    pub limit: Option<i32>,
+}
+
+/// Page size bounds for `/api/dm_history`
+const DM_HISTORY_DEFAULT_LIMIT: i32 = 50;
+const DM_HISTORY_MAX_LIMIT: i32 = 200;
+
 #[derive(serde::Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
+#[derive(serde::Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    /// Required when the server has invite codes configured
+    pub invite_code: Option<String>,
+    /// Required when the server asks for proof of work, see `/api/register/requirements`
+    pub pow_challenge: Option<String>,
+    pub pow_nonce: Option<String>,
+    /// Optional, mailed a verification link. Needed to reset a forgotten password
+    pub email: Option<String>,
+}
+
 #[derive(serde::Serialize)]
 pub struct AuthResponse {
     pub message: String,
     pub session_token: String,
 }
 
+#[derive(serde::Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
+}
+
 #[derive(serde::Serialize)]
 pub struct MeResponse {
     pub valid: bool,
@@ -28,39 +96,92 @@ pub struct MeResponse {
 
 pub fn login_route(
     pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+    totp: Totp,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("api")
         .and(warp::path("login"))
+        .and(warp::path::end())
         .and(warp::post()) // Intercept only POST requests
         .and(warp::body::json()) // Automatically parse JSON into LoginRequest
+        .and(client_info())
         .and(warp::any().map(move || pool.clone())) // Inject the database pool
-        .and(warp::any().map(move || session_cache.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and(warp::any().map(move || totp.clone()))
         .and_then(handle_login) // Pass the data to your logic function
 }
 
 pub async fn handle_login(
     auth: LoginRequest,
+    client: ClientInfo,
     pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+    totp: Totp,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &auth.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
     let user_result = crate::tables::user_db::find_user_by_username(&pool, &auth.username).await;
 
     if let Ok(user) = user_result {
         if crate::tables::user_db::verify_password(&auth.password, &user.password_hash).is_ok() {
-            let user_id = user.id;
-            match create_session(&pool, user_id).await {
+            auth_throttle::record_success(&throttle, &keys[0]).await;
+            // Only told to someone who knows the password
+            match sanction_db::get_active(&pool, user.id, SanctionKind::Ban).await {
+                Ok(None) => {}
+                Ok(Some(ban)) => {
+                    return Ok(warp::reply::with_header(
+                        warp::reply::with_status(
+                            warp::reply::json(&format!("You are banned {}", ban.describe())),
+                            warp::http::StatusCode::FORBIDDEN,
+                        ),
+                        "Set-Cookie",
+                        "",
+                    ));
+                }
+                Err(_) => {
+                    return Ok(warp::reply::with_header(
+                        warp::reply::with_status(
+                            warp::reply::json(&"Database is not online, please try again later"),
+                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        ),
+                        "Set-Cookie",
+                        "",
+                    ));
+                }
+            }
+            // The session waits for the second factor, see `/api/login/totp`
+            match totp::is_enabled(&pool, user.id).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    return Ok(warp::reply::with_header(
+                        warp::reply::with_status(
+                            warp::reply::json(&totp.issue_challenge(user.id)),
+                            warp::http::StatusCode::OK,
+                        ),
+                        "Set-Cookie",
+                        "",
+                    ));
+                }
+                Err(_) => {
+                    return Ok(warp::reply::with_header(
+                        warp::reply::with_status(
+                            warp::reply::json(&"Database is not online, please try again later"),
+                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        ),
+                        "Set-Cookie",
+                        "",
+                    ));
+                }
+            }
+            match session_store.create(&pool, user.id, &user.username, &client).await {
                 Ok(token) => {
-                    // Add to cache
-                    {
-                        let mut cache = session_cache.write().await;
-                        cache.insert(token.clone());
-                    }
-
-                    let cookie = format!(
-                        "session_token={}; Path=/; HttpOnly; SameSite=Lax; Max-Age=604800",
-                        token
-                    );
+                    let cookie = session_store.cookie(&token);
                     return Ok(warp::reply::with_header(
                         warp::reply::with_status(
                             warp::reply::json(&AuthResponse {
@@ -88,6 +209,7 @@ pub async fn handle_login(
     }
 
     // Default failure case
+    auth_throttle::record_failure(&throttle, &keys).await;
     Ok(warp::reply::with_header(
         warp::reply::with_status(
             warp::reply::json(&"Invalid username or password"),
@@ -98,25 +220,125 @@ pub async fn handle_login(
     ))
 }
 
+/// Who is on the other end, as far as nginx tells us.
+fn client_info() -> impl Filter<Extract = (ClientInfo,), Error = warp::Rejection> + Clone {
+    // Set by nginx, the TCP peer is always the proxy
+    warp::header::optional::<String>("x-real-ip")
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(|remote_addr, user_agent| ClientInfo { remote_addr, user_agent })
+}
+
+/// Answer with `429 Too Many Requests` and when to try again.
+fn too_many_attempts(wait: std::time::Duration) -> warp::reply::WithHeader<warp::reply::WithStatus<warp::reply::Json>> {
+    let secs = wait.as_secs().max(1);
+    warp::reply::with_header(
+        warp::reply::with_status(
+            warp::reply::json(&format!("Too many attempts, try again in {}s", secs)),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        ),
+        "Retry-After",
+        secs.to_string(),
+    )
+}
+
+pub fn register_requirements_route(
+    gate: RegisterGate,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("register"))
+        .and(warp::path("requirements"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(&gate.requirements()))
+}
+
 pub fn register_route(
-    pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    state: ChatState,
+    emails: Emails,
+    throttle: AuthThrottle,
+    gate: RegisterGate,
+    policy: Policy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("api")
         .and(warp::path("register"))
+        .and(warp::path::end())
         .and(warp::post()) // Intercept only POST requests
-        .and(warp::body::json()) // Automatically parse JSON into LoginRequest
-        .and(warp::any().map(move || pool.clone())) // Inject the database pool
-        .and(warp::any().map(move || session_cache.clone()))
+        .and(warp::body::json()) // Automatically parse JSON into RegisterRequest
+        .and(client_info())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || emails.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and(warp::any().map(move || gate.clone()))
+        .and(warp::any().map(move || policy.clone()))
         .and_then(handle_register) // Pass the data to your logic function
 }
 
 pub async fn handle_register(
-    auth: LoginRequest,
-    pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    auth: RegisterRequest,
+    client: ClientInfo,
+    state: ChatState,
+    emails: Emails,
+    throttle: AuthThrottle,
+    gate: RegisterGate,
+    policy: Policy,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let user_result = crate::tables::user_db::find_user_by_username(&pool, &auth.username).await;
+    // Every attempt counts, successful signups included
+    let keys = [(KeyKind::RegisterIp, client.remote_addr.clone().unwrap_or_else(|| "unknown".to_string()))];
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+    auth_throttle::record_failure(&throttle, &keys).await;
+    let pool = &state.pool;
+    let session_store = &state.session_store;
+    let email = auth.email.as_deref().map(str::trim).filter(|email| !email.is_empty());
+
+    // Before the gate, so a rejected password doesn't use up the proof of work
+    let mut errors = policy.check_registration(&auth.username, &auth.password);
+    if let Some(Err(e)) = email.map(validation::check_email) {
+        errors.push(e);
+    }
+    if errors.is_empty() {
+        match crate::tables::user_db::find_user_by_skeleton(pool, &auth.username).await {
+            // The same name is a conflict, answered below
+            Ok(Some(other)) if !other.username.eq_ignore_ascii_case(&auth.username) => {
+                errors.push(validation::lookalike(&auth.username, &other.username));
+            }
+            Ok(_) => {}
+            Err(_) => {
+                return Ok(warp::reply::with_header(
+                    warp::reply::with_status(
+                        warp::reply::json(&"Database is not online, please try again later"),
+                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ),
+                    "Set-Cookie",
+                    "",
+                ));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&ValidationErrors { errors }),
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    }
+
+    if let Err(e) = gate
+        .verify(auth.invite_code.as_deref(), auth.pow_challenge.as_deref(), auth.pow_nonce.as_deref())
+        .await
+    {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&e), warp::http::StatusCode::FORBIDDEN),
+            "Set-Cookie",
+            "",
+        ));
+    }
+
+    let user_result = crate::tables::user_db::find_user_by_username(pool, &auth.username).await;
 
     match user_result {
         Ok(_) => {
@@ -131,25 +353,22 @@ pub async fn handle_register(
             ))
         }
         _ => {
-            match create_user(&pool, &auth.username, &auth.password).await {
+            match create_user(pool, &auth.username, &auth.password).await {
                 Ok(_) => {
                     // Fetch the user to get the ID
                     if let Ok(user) =
-                        crate::tables::user_db::find_user_by_username(&pool, &auth.username).await
+                        crate::tables::user_db::find_user_by_username(pool, &auth.username).await
                     {
-                        let user_id = user.id;
-                        match create_session(&pool, user_id).await {
+                        // The account is there either way, the address can be set again later
+                        if let Some(email) = email {
+                            let set = emails.set_email(pool, user.id, &user.username, email).await;
+                            if let Err(e) = set {
+                                println!("Failed to set the email address of {}: {}", user.username, e.message());
+                            }
+                        }
+                        match session_store.create(pool, user.id, &user.username, &client).await {
                             Ok(token) => {
-                                // Add to cache
-                                {
-                                    let mut cache = session_cache.write().await;
-                                    cache.insert(token.clone());
-                                }
-
-                                let cookie = format!(
-                                    "session_token={}; Path=/; HttpOnly; SameSite=Lax; Max-Age=604800",
-                                    token
-                                );
+                                let cookie = session_store.cookie(&token);
                                 Ok(warp::reply::with_header(
                                     warp::reply::with_status(
                                         warp::reply::json(&AuthResponse {
@@ -182,6 +401,15 @@ pub async fn handle_register(
                         ))
                     }
                 }
+                // Someone took the name, or a lookalike, in the meantime
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(warp::reply::with_header(
+                    warp::reply::with_status(
+                        warp::reply::json(&"User already exists"),
+                        warp::http::StatusCode::CONFLICT,
+                    ),
+                    "Set-Cookie",
+                    "",
+                )),
                 Err(_) => Ok(warp::reply::with_header(
                     warp::reply::with_status(
                         warp::reply::json(&"Database is not online, please try again later"),
@@ -210,14 +438,152 @@ pub async fn handle_chat_history(
     limit: LimitMessages,
     pool: sqlx::MySqlPool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let chat_history = crate::tables::user_db::get_chat_history(&pool, limit.limit).await;
+    let room = limit.room.as_deref().unwrap_or(crate::rooms::DEFAULT_ROOM);
+    let limit = limit.limit.clamp(1, CHAT_HISTORY_MAX_LIMIT);
+    let chat_history = match crate::tables::user_db::get_chat_history_before(&pool, room, None, limit).await {
+        Ok(messages) => crate::db::with_reactions(&pool, messages).await,
+        Err(e) => Err(e),
+    };
 
     match chat_history {
+        Ok(mut messages_vector) => {
+            // This endpoint has always answered oldest first
+            messages_vector.reverse();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&messages_vector),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        _ => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn chat_history_page_route(
+    pool: sqlx::MySqlPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("v2"))
+        .and(warp::path("chat_history"))
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(handle_chat_history_page)
+}
+
+pub async fn handle_chat_history_page(
+    query: HistoryPageQuery,
+    pool: sqlx::MySqlPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room = query.room.as_deref().unwrap_or(crate::rooms::DEFAULT_ROOM);
+    let limit = query
+        .limit
+        .unwrap_or(CHAT_HISTORY_DEFAULT_LIMIT)
+        .clamp(1, CHAT_HISTORY_MAX_LIMIT);
+
+    // One row past the page tells us whether there is a next one
+    let chat_history = match (query.before, query.after) {
+        (Some(_), Some(_)) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Use either 'before' or 'after', not both"),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        (before, None) => {
+            crate::tables::user_db::get_chat_history_before(&pool, room, before, limit + 1).await
+        }
+        (None, Some(after)) => {
+            crate::tables::user_db::get_chat_history_after(&pool, room, after, limit + 1).await
+        }
+    };
+
+    let mut messages = match chat_history {
+        Ok(messages) => messages,
+        Err(_) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Database is not online, please try again later"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+    let has_more = messages.len() > limit as usize;
+    messages.truncate(limit as usize);
+    // `after` pages come back oldest first, the cursor is their newest message
+    let next_cursor = if has_more {
+        messages.last().map(|m| m.message_id)
+    } else {
+        None
+    };
+    if query.after.is_some() {
+        messages.reverse();
+    }
+
+    match crate::db::with_reactions(&pool, messages).await {
+        Ok(messages) => Ok(warp::reply::with_status(
+            warp::reply::json(&HistoryPage {
+                messages,
+                next_cursor,
+            }),
+            warp::http::StatusCode::OK,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn dm_history_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("dm_history"))
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_dm_history)
+}
+
+pub async fn handle_dm_history(
+    query: DmHistoryQuery,
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let pool = &state.pool;
+    // Bots may read with a `read_history` API key
+    let me = match user_from_token_or_key(&state, token, Some(Scope::ReadHistory)).await {
+        Ok(me) => me,
+        Err(e) => return Ok(access_error_reply(e)),
+    };
+    let other = match crate::tables::user_db::find_user_by_username(pool, &query.with).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("User '{}' not found", query.with)),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+        Err(_) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Database is not online, please try again later"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DM_HISTORY_DEFAULT_LIMIT)
+        .clamp(1, DM_HISTORY_MAX_LIMIT);
+    match crate::tables::private_message_db::get_dm_history(pool, me.id, other.id, limit).await {
         Ok(messages_vector) => Ok(warp::reply::with_status(
             warp::reply::json(&messages_vector),
             warp::http::StatusCode::OK,
         )),
-        _ => Ok(warp::reply::with_status(
+        Err(_) => Ok(warp::reply::with_status(
             warp::reply::json(&"Database is not online, please try again later"),
             warp::http::StatusCode::INTERNAL_SERVER_ERROR,
         )),
@@ -225,27 +591,28 @@ pub async fn handle_chat_history(
 }
 
 pub fn get_me_route(
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("api")
         .and(warp::path("me"))
+        .and(warp::path::end())
         .and(warp::get())
-        .and(warp::header::optional::<String>("cookie"))
-        .and(warp::any().map(move || session_cache.clone()))
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
         .and_then(handle_get_me)
 }
 
 pub async fn handle_get_me(
-    cookie_header: Option<String>,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let mut session_token = None;
-    if let Some(cookie_str) = cookie_header {
-        if let Some(token) = extract_session_token(&cookie_str) {
-            let cache = session_cache.read().await;
-            if cache.contains(&token) {
-                session_token = Some(token);
-            }
+    if let Some(token) = token {
+        if session_store.get(&pool, &token).await.is_some() {
+            session_token = Some(token);
         }
     }
 
@@ -268,47 +635,1703 @@ pub async fn handle_get_me(
     }
 }
 
-pub fn logout_route(
+pub fn list_connections_route(
     pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    session_store: SessionStore,
+    connected: ConnectedUsers,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("api")
-        .and(warp::path("logout"))
-        .and(warp::post())
-        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::path("connections"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
         .and(warp::any().map(move || pool.clone()))
-        .and(warp::any().map(move || session_cache.clone()))
-        .and_then(handle_logout)
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || connected.clone()))
+        .and_then(handle_list_connections)
 }
 
-pub async fn handle_logout(
-    cookie_header: Option<String>,
+pub async fn handle_list_connections(
+    token: Option<String>,
     pool: sqlx::MySqlPool,
-    session_cache: Arc<RwLock<HashSet<String>>>,
+    session_store: SessionStore,
+    connected: ConnectedUsers,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    let connections = connected_users::list_connections(&connected, me.id).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&connections),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub fn close_connection_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("connections"))
+        .and(warp::path::param::<ConnId>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_close_connection)
+}
+
+pub async fn handle_close_connection(
+    conn_id: ConnId,
+    token: Option<String>,
+    state: ChatState,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if let Some(cookie_str) = cookie_header {
-        if let Some(token) = extract_session_token(&cookie_str) {
-            let _ = delete_session(&pool, &token).await;
-            // Remove from cachea
-            let mut cache = session_cache.write().await;
-            cache.remove(&token);
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    // Scoped to the caller, nobody can close someone else's socket by guessing ids
+    if let Some(last) = connected_users::close_connection(&state.connected, me.id, conn_id).await {
+        if last {
+            ws_handler::went_offline(&state.connected, &state.cluster, &state.presence, &state.pool, me.id, Some(me.username)).await;
         }
+        Ok(warp::reply::with_status(
+            warp::reply::json(&"Connection closed"),
+            warp::http::StatusCode::OK,
+        ))
+    } else {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&"Connection not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        ))
     }
+}
 
-    let cookie = "session_token=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0";
-    Ok(warp::reply::with_header(
-        warp::reply::with_status(warp::reply::json(&"Logged out"), warp::http::StatusCode::OK),
-        "Set-Cookie",
-        cookie,
-    ))
+/// Like `who`, open to API keys of any scope.
+pub fn online_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("online"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_online)
+}
+
+pub async fn handle_online(
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = user_from_token_or_key(&state, token, None).await {
+        return Ok(access_error_reply(e));
+    }
+
+    match crate::presence::online_usernames(&state.pool, &state.connected, &state.cluster).await {
+        Ok(users) => Ok(warp::reply::with_status(
+            warp::reply::json(&users),
+            warp::http::StatusCode::OK,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn set_role_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("admin"))
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("role"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(auth::session_token())
+        .and(warp::body::json())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_set_role)
+}
+
+pub async fn handle_set_role(
+    username: String,
+    token: Option<String>,
+    body: SetRoleRequest,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+    if !me.role.can(Permission::ManageRoles) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"You are not allowed to change roles"),
+            warp::http::StatusCode::FORBIDDEN,
+        ));
+    }
+    // Keeps the last admin from locking everyone out by accident
+    if me.username == username {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"You can't change your own role"),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match crate::tables::user_db::set_role(&state.pool, &username, body.role).await {
+        Ok(Some(user_id)) => {
+            sessions::standing_changed(&state, user_id).await;
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Role updated"),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&"User not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn moderate_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("admin"))
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth::session_token())
+        .and(warp::body::json())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_moderate)
+}
+
+/// `action` is `mute`, `kick` or `ban`.
+pub async fn handle_moderate(
+    username: String,
+    action: String,
+    token: Option<String>,
+    body: SanctionRequest,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    let result = match action.as_str() {
+        "kick" => moderation::kick(&state, me.role, &username).await,
+        "mute" => moderation::sanction(&state, me.id, me.role, &username, SanctionKind::Mute, &body).await,
+        "ban" => moderation::sanction(&state, me.id, me.role, &username, SanctionKind::Ban, &body).await,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Unknown action, use mute, kick or ban"),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+    match result {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Done"),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(moderation_error_reply(e)),
+    }
+}
+
+pub fn lift_sanction_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("admin"))
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_lift_sanction)
 }
 
-fn extract_session_token(cookie_str: &str) -> Option<String> {
-    for cookie in cookie_str.split(';') {
-        let parts: Vec<&str> = cookie.trim().splitn(2, '=').collect();
-        if parts.len() == 2 && parts[0] == "session_token" {
-            return Some(parts[1].to_string());
+/// `kind` is `mute` or `ban`.
+pub async fn handle_lift_sanction(
+    username: String,
+    kind: String,
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+    let kind = match kind.as_str() {
+        "mute" => SanctionKind::Mute,
+        "ban" => SanctionKind::Ban,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Unknown sanction, use mute or ban"),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
         }
+    };
+
+    match moderation::lift(&state, me.role, &username, kind).await {
+        Ok(true) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Sanction lifted"),
+            warp::http::StatusCode::OK,
+        )),
+        Ok(false) => Ok(warp::reply::with_status(
+            warp::reply::json(&"No active sanction of that kind"),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Ok(moderation_error_reply(e)),
+    }
+}
+
+fn moderation_error_reply(e: ModerationError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e {
+        ModerationError::NotAllowed | ModerationError::Outranked => warp::http::StatusCode::FORBIDDEN,
+        ModerationError::UserNotFound => warp::http::StatusCode::NOT_FOUND,
+        ModerationError::InvalidDuration | ModerationError::ReasonTooLong => warp::http::StatusCode::BAD_REQUEST,
+        ModerationError::Database => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&e.message()), status)
+}
+
+/// One of the caller's sessions, flagged if it's the one making the request.
+#[derive(serde::Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    pub session: SessionInfo,
+    pub current: bool,
+}
+
+pub fn list_sessions_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("sessions"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(handle_list_sessions)
+}
+
+pub async fn handle_list_sessions(
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some((my_token, me)) = session_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match crate::tables::user_db::list_sessions(&pool, me.id).await {
+        Ok(list) => {
+            let views: Vec<SessionView> = list
+                .into_iter()
+                .map(|session| SessionView {
+                    current: session.token == my_token,
+                    session,
+                })
+                .collect();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&views),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn revoke_session_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("sessions"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_revoke_session)
+}
+
+pub async fn handle_revoke_session(
+    session_id: i64,
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    // Scoped to the caller like connections, ids are guessable
+    match crate::tables::user_db::delete_session_by_id(&state.pool, me.id, session_id).await {
+        Ok(Some(token)) => {
+            sessions::revoke(&state, me.id, &[token]).await;
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Session revoked"),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Session not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// Log out everywhere: every session of the caller, this one included.
+pub fn revoke_all_sessions_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("sessions"))
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_revoke_all_sessions)
+}
+
+pub async fn handle_revoke_all_sessions(
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+
+    match crate::tables::user_db::delete_user_sessions(&state.pool, me.id).await {
+        Ok(tokens) => {
+            sessions::revoke(&state, me.id, &tokens).await;
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&format!("Logged out of {} sessions", tokens.len())),
+                    warp::http::StatusCode::OK,
+                ),
+                "Set-Cookie",
+                "session_token=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+            ))
+        }
+        Err(_) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Database is not online, please try again later"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+    }
+}
+
+pub fn logout_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("logout"))
+        .and(warp::post())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_logout)
+}
+
+pub async fn handle_logout(
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(token) = token {
+        let owner = crate::tables::user_db::get_user_by_token(&state.pool, &token).await;
+        let _ = delete_session(&state.pool, &token).await;
+        match owner {
+            // Also closes the sockets opened with this session
+            Ok(user) => sessions::revoke(&state, user.id, &[token]).await,
+            Err(_) => state.session_store.forget(&[token]).await,
+        }
+    }
+
+    let cookie = "session_token=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0";
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&"Logged out"), warp::http::StatusCode::OK),
+        "Set-Cookie",
+        cookie,
+    ))
+}
+
+pub fn refresh_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("refresh"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_refresh)
+}
+
+/// Swap the caller's session token for a new one, the session keeps its absolute lifetime.
+/// Sockets opened with the old token are closed, to reconnect with the new one.
+pub async fn handle_refresh(
+    token: Option<String>,
+    client: ClientInfo,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let rotated = match token {
+        Some(token) => match state.session_store.rotate(&state.pool, &token, &client).await {
+            Ok(Some((new_token, session))) => {
+                sessions::revoke(&state, session.user_id, &[token]).await;
+                Ok(Some(new_token))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        },
+        None => Ok(None),
+    };
+
+    match rotated {
+        Ok(Some(token)) => {
+            let cookie = state.session_store.cookie(&token);
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&AuthResponse {
+                        message: "Session refreshed".to_string(),
+                        session_token: token,
+                    }),
+                    warp::http::StatusCode::OK,
+                ),
+                "Set-Cookie",
+                cookie,
+            ))
+        }
+        Ok(None) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            String::new(),
+        )),
+        Err(_) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Database is not online, please try again later"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            "Set-Cookie",
+            String::new(),
+        )),
+    }
+}
+
+pub fn change_password_route(
+    state: ChatState,
+    throttle: AuthThrottle,
+    policy: Policy,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("password"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and(warp::any().map(move || policy.clone()))
+        .and_then(handle_change_password)
+}
+
+/// Change the password, logging out every session and handing the caller a new one.
+pub async fn handle_change_password(
+    body: ChangePasswordRequest,
+    token: Option<String>,
+    client: ClientInfo,
+    state: ChatState,
+    throttle: AuthThrottle,
+    policy: Policy,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    // Guessing the old password through here counts like failed logins
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &me.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    if let Err(e) = account::change_password(&state, &policy, &me, &body).await {
+        if matches!(e, AccountError::WrongPassword) {
+            auth_throttle::record_failure(&throttle, &keys).await;
+        }
+        return Ok(warp::reply::with_header(account_error_reply(e), "Set-Cookie", ""));
+    }
+    auth_throttle::record_success(&throttle, &keys[0]).await;
+
+    match state.session_store.create(&state.pool, me.id, &me.username, &client).await {
+        Ok(token) => {
+            let cookie = state.session_store.cookie(&token);
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&AuthResponse {
+                        message: "Password changed, every other session was logged out".to_string(),
+                        session_token: token,
+                    }),
+                    warp::http::StatusCode::OK,
+                ),
+                "Set-Cookie",
+                cookie.as_str(),
+            ))
+        }
+        Err(_) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Password changed, but creating a new session failed, please log in again"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            "Set-Cookie",
+            "session_token=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+        )),
+    }
+}
+
+pub fn rename_route(
+    state: ChatState,
+    policy: Policy,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("username"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || policy.clone()))
+        .and_then(handle_rename)
+}
+
+pub async fn handle_rename(
+    body: RenameRequest,
+    token: Option<String>,
+    state: ChatState,
+    policy: Policy,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match account::rename(&state, &policy, &me, &body).await {
+        Ok(new_username) => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("You are now {}", new_username)),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(account_error_reply(e)),
+    }
+}
+
+pub fn delete_account_route(
+    state: ChatState,
+    throttle: AuthThrottle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and_then(handle_delete_account)
+}
+
+pub async fn handle_delete_account(
+    body: DeleteAccountRequest,
+    token: Option<String>,
+    client: ClientInfo,
+    state: ChatState,
+    throttle: AuthThrottle,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &me.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    match account::delete(&state, &me, &body).await {
+        Ok(()) => Ok(warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&"Account deleted"), warp::http::StatusCode::OK),
+            "Set-Cookie",
+            "session_token=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+        )),
+        Err(e) => {
+            if matches!(e, AccountError::WrongPassword) {
+                auth_throttle::record_failure(&throttle, &keys).await;
+            }
+            Ok(warp::reply::with_header(account_error_reply(e), "Set-Cookie", ""))
+        }
+    }
+}
+
+fn account_error_reply(e: AccountError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e {
+        AccountError::WrongPassword => warp::http::StatusCode::FORBIDDEN,
+        AccountError::Invalid(e) => {
+            return warp::reply::with_status(
+                warp::reply::json(&ValidationErrors { errors: vec![e] }),
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+            );
+        }
+        AccountError::UsernameTaken => warp::http::StatusCode::CONFLICT,
+        AccountError::Database => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&e.message()), status)
+}
+
+pub fn login_totp_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+    totp: Totp,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("login"))
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(client_info())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and(warp::any().map(move || totp.clone()))
+        .and_then(handle_login_totp)
+}
+
+/// Second step of logging in with 2FA: the challenge from `/api/login` plus a code for the session.
+pub async fn handle_login_totp(
+    body: TotpLoginRequest,
+    client: ClientInfo,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+    totp: Totp,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = match totp.check_challenge(&body.challenge_token).await {
+        Some(user_id) => crate::tables::user_db::get_user_by_id(&pool, user_id).await.ok(),
+        None => None,
+    };
+    let Some(user) = user else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired challenge, log in again"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    // Wrong codes count like wrong passwords
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &user.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    match totp::verify(&pool, user.id, &body.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            auth_throttle::record_failure(&throttle, &keys).await;
+            return Ok(warp::reply::with_header(totp_error_reply(TotpError::WrongCode), "Set-Cookie", ""));
+        }
+        Err(e) => return Ok(warp::reply::with_header(totp_error_reply(e), "Set-Cookie", "")),
+    }
+    auth_throttle::record_success(&throttle, &keys[0]).await;
+    totp.redeem_challenge(&body.challenge_token).await;
+
+    match session_store.create(&pool, user.id, &user.username, &client).await {
+        Ok(token) => {
+            let cookie = session_store.cookie(&token);
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&AuthResponse {
+                        message: "Login successful!".to_string(),
+                        session_token: token,
+                    }),
+                    warp::http::StatusCode::OK,
+                ),
+                "Set-Cookie",
+                cookie.as_str(),
+            ))
+        }
+        Err(_) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Failed to create session"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+    }
+}
+
+pub fn totp_status_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(handle_totp_status)
+}
+
+pub async fn handle_totp_status(
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match totp::status(&pool, me.id).await {
+        Ok(status) => Ok(warp::reply::with_status(
+            warp::reply::json(&status),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(totp_error_reply(e)),
+    }
+}
+
+/// Start enrolling in 2FA, confirmed with `/api/me/totp/confirm`.
+pub fn enroll_totp_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    totp: Totp,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || totp.clone()))
+        .and_then(handle_enroll_totp)
+}
+
+pub async fn handle_enroll_totp(
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    totp: Totp,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match totp.enroll(&pool, &me).await {
+        Ok(enrollment) => Ok(warp::reply::with_status(
+            warp::reply::json(&enrollment),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(totp_error_reply(e)),
+    }
+}
+
+/// `confirm` turns 2FA on, `recovery_codes` replaces the recovery codes. Both take a code, wrong ones
+/// count as failed logins.
+pub fn totp_code_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("totp"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and_then(handle_totp_code)
+}
+
+pub async fn handle_totp_code(
+    action: String,
+    body: TotpCodeRequest,
+    token: Option<String>,
+    client: ClientInfo,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &me.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    let result = match action.as_str() {
+        "confirm" => totp::confirm(&pool, &me, &body.code).await,
+        "recovery_codes" => totp::regenerate_recovery_codes(&pool, &me, &body.code).await,
+        _ => {
+            return Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&"Expected 'confirm' or 'recovery_codes'"),
+                    warp::http::StatusCode::NOT_FOUND,
+                ),
+                "Set-Cookie",
+                "",
+            ));
+        }
+    };
+    match result {
+        Ok(codes) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&codes),
+                warp::http::StatusCode::OK,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+        Err(e) => {
+            if matches!(e, TotpError::WrongCode) {
+                auth_throttle::record_failure(&throttle, &keys).await;
+            }
+            Ok(warp::reply::with_header(totp_error_reply(e), "Set-Cookie", ""))
+        }
+    }
+}
+
+pub fn disable_totp_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and_then(handle_disable_totp)
+}
+
+pub async fn handle_disable_totp(
+    body: DisableTotpRequest,
+    token: Option<String>,
+    client: ClientInfo,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    throttle: AuthThrottle,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &me.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    match totp::disable(&pool, &me, &body).await {
+        Ok(()) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Two-factor authentication turned off"),
+                warp::http::StatusCode::OK,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+        Err(e) => {
+            if matches!(e, TotpError::WrongCode | TotpError::WrongPassword) {
+                auth_throttle::record_failure(&throttle, &keys).await;
+            }
+            Ok(warp::reply::with_header(totp_error_reply(e), "Set-Cookie", ""))
+        }
+    }
+}
+
+fn totp_error_reply(e: TotpError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e {
+        TotpError::AlreadyEnabled | TotpError::NotEnrolled | TotpError::NotEnabled => warp::http::StatusCode::CONFLICT,
+        TotpError::WrongCode | TotpError::WrongPassword => warp::http::StatusCode::FORBIDDEN,
+        TotpError::Database => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&e.message()), status)
+}
+
+pub fn email_status_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("email"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(handle_email_status)
+}
+
+pub async fn handle_email_status(
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match email::status(&pool, me.id).await {
+        Ok(status) => Ok(warp::reply::with_status(
+            warp::reply::json(&status),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(email_error_reply(e)),
+    }
+}
+
+/// Set or change the caller's address, which stays unverified until the link mailed to it is opened.
+pub fn change_email_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    emails: Emails,
+    throttle: AuthThrottle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("me"))
+        .and(warp::path("email"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || emails.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and_then(handle_change_email)
+}
+
+pub async fn handle_change_email(
+    body: ChangeEmailRequest,
+    token: Option<String>,
+    client: ClientInfo,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+    emails: Emails,
+    throttle: AuthThrottle,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"Invalid or expired session"),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "Set-Cookie",
+            "",
+        ));
+    };
+    // Guessing the password through here counts like failed logins
+    let keys = auth_throttle::login_keys(client.remote_addr.as_deref().unwrap_or("unknown"), &me.username);
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+
+    match emails.change_email(&pool, &me, &body).await {
+        Ok(()) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&format!("A verification link was sent to {}", body.email.trim())),
+                warp::http::StatusCode::OK,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+        Err(e) => {
+            if matches!(e, EmailError::WrongPassword) {
+                auth_throttle::record_failure(&throttle, &keys).await;
+            }
+            Ok(warp::reply::with_header(email_error_reply(e), "Set-Cookie", ""))
+        }
+    }
+}
+
+/// Where the link in the verification mail leads. Only shows a button that posts the token back,
+/// so link scanners in mail clients opening it don't verify the address for the user.
+pub fn verify_email_page_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("email"))
+        .and(warp::path("verify"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<VerifyEmailQuery>())
+        .and_then(handle_verify_email_page)
+}
+
+pub async fn handle_verify_email_page(query: VerifyEmailQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    // Tokens are plain hex, anything else can't be one and isn't put in the page
+    if query.token.is_empty() || !query.token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(warp::reply::with_status(
+            warp::reply::html(INVALID_VERIFY_LINK.to_string()),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    let page = format!(
+        "<form method=\"post\" action=\"/api/email/verify\">\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\
+         <button type=\"submit\">Confirm my email address</button></form>",
+        query.token
+    );
+    Ok(warp::reply::with_status(warp::reply::html(page), warp::http::StatusCode::OK))
+}
+
+/// The confirm button of the verification page, this is what uses up the link.
+pub fn verify_email_route(
+    pool: sqlx::MySqlPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("email"))
+        .and(warp::path("verify"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::form::<VerifyEmailQuery>())
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(handle_verify_email)
+}
+
+pub async fn handle_verify_email(
+    form: VerifyEmailQuery,
+    pool: sqlx::MySqlPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (message, status) = match email::verify(&pool, &form.token).await {
+        Ok(address) => (
+            format!("{} is verified, you can close this page", escape_html(&address)),
+            warp::http::StatusCode::OK,
+        ),
+        Err(EmailError::InvalidLink) => (INVALID_VERIFY_LINK.to_string(), warp::http::StatusCode::BAD_REQUEST),
+        Err(e) => (e.message().to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok(warp::reply::with_status(warp::reply::html(message), status))
+}
+
+const INVALID_VERIFY_LINK: &str = "This link is invalid or expired, set your email address again for a new one";
+
+/// Addresses may hold about anything, so they're escaped before going into a page.
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Mail a reset link. Answers the same whether or not the account exists or has a verified address.
+pub fn forgot_password_route(
+    pool: sqlx::MySqlPool,
+    emails: Emails,
+    throttle: AuthThrottle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("password"))
+        .and(warp::path("forgot"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(client_info())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || emails.clone()))
+        .and(warp::any().map(move || throttle.clone()))
+        .and_then(handle_forgot_password)
+}
+
+pub async fn handle_forgot_password(
+    body: ForgotPasswordRequest,
+    client: ClientInfo,
+    pool: sqlx::MySqlPool,
+    emails: Emails,
+    throttle: AuthThrottle,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Every request counts, so one IP can't mail a long list of accounts
+    let keys = [(KeyKind::ResetIp, client.remote_addr.clone().unwrap_or_else(|| "unknown".to_string()))];
+    if let Some(wait) = auth_throttle::check(&throttle, &keys).await {
+        return Ok(too_many_attempts(wait));
+    }
+    auth_throttle::record_failure(&throttle, &keys).await;
+
+    match emails.request_reset(&pool, &body.login).await {
+        Ok(()) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"If that account has a verified email address, a reset link is on its way"),
+                warp::http::StatusCode::ACCEPTED,
+            ),
+            "Set-Cookie",
+            "",
+        )),
+        Err(e) => Ok(warp::reply::with_header(email_error_reply(e), "Set-Cookie", "")),
+    }
+}
+
+/// Set a new password with the token from a reset mail. Every session of the account is logged out.
+pub fn reset_password_route(
+    state: ChatState,
+    policy: Policy,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("password"))
+        .and(warp::path("reset"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || policy.clone()))
+        .and_then(handle_reset_password)
+}
+
+pub async fn handle_reset_password(
+    body: ResetPasswordRequest,
+    state: ChatState,
+    policy: Policy,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match email::reset_password(&state, &policy, &body).await {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Password changed, log in with the new one"),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(email_error_reply(e)),
+    }
+}
+
+fn email_error_reply(e: EmailError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e {
+        EmailError::WrongPassword => warp::http::StatusCode::FORBIDDEN,
+        EmailError::Invalid(e) => {
+            return warp::reply::with_status(
+                warp::reply::json(&ValidationErrors { errors: vec![e] }),
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+            );
+        }
+        EmailError::InvalidLink => warp::http::StatusCode::BAD_REQUEST,
+        EmailError::TooSoon => warp::http::StatusCode::TOO_MANY_REQUESTS,
+        EmailError::Database => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&e.message()), status)
+}
+
+pub fn oauth_providers_route(
+    oauth: OAuth,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("oauth"))
+        .and(warp::path("providers"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(&oauth.providers()))
+}
+
+/// Send the browser to the provider to sign in, see `oauth_callback_route` for the way back.
+pub fn oauth_start_route(
+    oauth: OAuth,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("oauth"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("start"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || oauth.clone()))
+        .and_then(handle_oauth_start)
+}
+
+pub async fn handle_oauth_start(provider: String, oauth: OAuth) -> Result<impl warp::Reply, warp::Rejection> {
+    match oauth.start(&provider) {
+        Ok((url, cookie)) => Ok(redirect(&url, &[cookie])),
+        Err(e) => Ok(redirect(&oauth.page_url(&[("login_error", e.message())]), &[])),
+    }
+}
+
+/// Where the provider sends the browser back. Signs in with the same session cookie as `/api/login`,
+/// or links the outside account to the caller when they are signed in already. Either way the
+/// browser lands on the web page, with `login_error` or `totp_challenge` for its script when needed.
+pub fn oauth_callback_route(
+    state: ChatState,
+    oauth: OAuth,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("oauth"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("callback"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<CallbackQuery>())
+        .and(warp::cookie::optional::<String>(oauth::FLOW_COOKIE))
+        .and(auth::session_token())
+        .and(client_info())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || oauth.clone()))
+        .and_then(handle_oauth_callback)
+}
+
+pub async fn handle_oauth_callback(
+    provider: String,
+    query: CallbackQuery,
+    flow: Option<String>,
+    token: Option<String>,
+    client: ClientInfo,
+    state: ChatState,
+    oauth: OAuth,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // The flow is over whichever way it went
+    let clear_flow = oauth::clear_flow_cookie();
+    let fail = |message: &str| redirect(&oauth.page_url(&[("login_error", message)]), &[clear_flow.clone()]);
+
+    let identity = match oauth.finish(&provider, &query, flow.as_deref()).await {
+        Ok(identity) => identity,
+        Err(e) => return Ok(fail(e.message())),
+    };
+    let current = match &token {
+        Some(token) => auth::user_for_token(&state.pool, &state.session_store, token).await,
+        None => None,
+    };
+    let linking = current.is_some();
+    let user = match oauth.sign_in(&state.pool, &provider, &identity, current).await {
+        Ok(user) => user,
+        Err(e) => return Ok(fail(e.message())),
+    };
+    if linking {
+        return Ok(redirect(&oauth.page_url(&[]), &[clear_flow.clone()]));
+    }
+
+    match sanction_db::get_active(&state.pool, user.id, SanctionKind::Ban).await {
+        Ok(None) => {}
+        Ok(Some(ban)) => return Ok(fail(&format!("You are banned {}", ban.describe()))),
+        Err(_) => return Ok(fail("Database is not online, please try again later")),
+    }
+    // The session waits for the second factor, see `/api/login/totp`
+    match oauth.second_factor(&state.pool, user.id).await {
+        Ok(None) => {}
+        Ok(Some(challenge)) => {
+            return Ok(redirect(&oauth.page_url(&[("totp_challenge", &challenge)]), &[clear_flow.clone()]));
+        }
+        Err(e) => return Ok(fail(e.message())),
+    }
+    match state.session_store.create(&state.pool, user.id, &user.username, &client).await {
+        Ok(token) => {
+            let cookie = state.session_store.cookie(&token);
+            Ok(redirect(&oauth.page_url(&[]), &[clear_flow.clone(), cookie]))
+        }
+        Err(_) => Ok(fail("Failed to create session")),
+    }
+}
+
+/// `303 See Other` to `location`, setting `cookies`.
+fn redirect(location: &str, cookies: &[String]) -> warp::http::Response<String> {
+    let mut response = warp::http::Response::builder()
+        .status(warp::http::StatusCode::SEE_OTHER)
+        .header("Location", location);
+    for cookie in cookies {
+        response = response.header("Set-Cookie", cookie.as_str());
+    }
+    response.body(String::new()).expect("redirect headers are valid")
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// `<count>/<seconds>`, `60/60` when omitted
+    pub rate_limit: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKeyView,
+    /// Shown this once, only a hash is stored
+    pub api_key: String,
+}
+
+pub fn create_api_key_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("keys"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(handle_create_api_key)
+}
+
+pub async fn handle_create_api_key(
+    body: CreateApiKeyRequest,
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Keys can't make more keys, this takes a login session
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > api_keys::MAX_NAME_CHARS {
+        let e = format!("'name' must be 1 to {} characters", api_keys::MAX_NAME_CHARS);
+        return Ok(warp::reply::with_status(warp::reply::json(&e), warp::http::StatusCode::BAD_REQUEST));
+    }
+    let mut scopes = Vec::new();
+    for scope in body.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"A key needs at least one scope"),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    let rate_limit = match crate::rate_limit::Rule::parse(body.rate_limit.as_deref().unwrap_or(api_keys::DEFAULT_RATE_LIMIT)) {
+        Some(rule) => rule.to_string(),
+        None => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"'rate_limit' must look like <count>/<seconds>, e.g. 60/60"),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    match crate::tables::api_key_db::count_api_keys(&pool, me.id).await {
+        Ok(count) if count >= api_keys::MAX_KEYS_PER_USER => {
+            let e = format!("You can have at most {} API keys", api_keys::MAX_KEYS_PER_USER);
+            return Ok(warp::reply::with_status(warp::reply::json(&e), warp::http::StatusCode::CONFLICT));
+        }
+        Ok(_) => {}
+        Err(_) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Database is not online, please try again later"),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+
+    let (prefix, secret, api_key) = api_keys::generate();
+    let key_hash = crate::tables::user_db::hash_password(&secret);
+    let created = match crate::tables::api_key_db::create_api_key(
+        &pool,
+        me.id,
+        name,
+        &prefix,
+        &key_hash,
+        &Scope::join(&scopes),
+        &rate_limit,
+    )
+    .await
+    {
+        Ok(id) => crate::tables::api_key_db::get_api_key(&pool, id).await,
+        Err(e) => Err(e),
+    };
+    match created {
+        Ok(key) => Ok(warp::reply::with_status(
+            warp::reply::json(&CreatedApiKey { key: key.into(), api_key }),
+            warp::http::StatusCode::CREATED,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn list_api_keys_route(
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("keys"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::session_token())
+        .and(warp::any().map(move || pool.clone()))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(handle_list_api_keys)
+}
+
+pub async fn handle_list_api_keys(
+    token: Option<String>,
+    pool: sqlx::MySqlPool,
+    session_store: SessionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&pool, &session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match crate::tables::api_key_db::list_api_keys(&pool, me.id).await {
+        Ok(keys) => {
+            let views: Vec<ApiKeyView> = keys.into_iter().map(ApiKeyView::from).collect();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&views),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn revoke_api_key_route(
+    state: ChatState,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("keys"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth::session_token())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(handle_revoke_api_key)
+}
+
+pub async fn handle_revoke_api_key(
+    key_id: i64,
+    token: Option<String>,
+    state: ChatState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(me) = user_from_token(&state.pool, &state.session_store, token).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
+
+    match crate::tables::api_key_db::delete_api_key(&state.pool, me.id, key_id).await {
+        Ok(Some(prefix)) => {
+            state.api_keys.forget(&prefix).await;
+            // Sockets opened with the key are registered under its label, closed like a session's
+            sessions::revoke(&state, me.id, &[api_keys::connection_label(&prefix)]).await;
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"API key revoked"),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&"API key not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+        Err(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&"Database is not online, please try again later"),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// Why `user_from_token_or_key` turned a request away.
+enum AccessError {
+    Invalid,
+    /// An API key without the scope the endpoint needs
+    MissingScope(Scope),
+    /// An API key over its own rate limit, for this long
+    RateLimited(std::time::Duration),
+}
+
+fn access_error_reply(e: AccessError) -> warp::reply::WithStatus<warp::reply::Json> {
+    match e {
+        AccessError::Invalid => warp::reply::with_status(
+            warp::reply::json(&"Invalid or expired session"),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ),
+        AccessError::MissingScope(scope) => warp::reply::with_status(
+            warp::reply::json(&format!("This API key doesn't have the '{}' scope", scope.as_str())),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        AccessError::RateLimited(wait) => warp::reply::with_status(
+            warp::reply::json(&format!("Too many requests with this API key, retry in {}s", wait.as_secs() + 1)),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        ),
+    }
+}
+
+/// `user_from_token` for the endpoints bots may use too: an API key is accepted in place of the
+/// session if it has `scope` (any key when `None`), counting towards its rate limit.
+async fn user_from_token_or_key(
+    state: &ChatState,
+    token: Option<String>,
+    scope: Option<Scope>,
+) -> Result<User, AccessError> {
+    let Some(key) = token.as_deref().filter(|t| api_keys::is_api_key(t)) else {
+        return user_from_token(&state.pool, &state.session_store, token)
+            .await
+            .ok_or(AccessError::Invalid);
+    };
+    let (user, grant) = state.api_keys.authenticate(&state.pool, key).await.ok_or(AccessError::Invalid)?;
+    if let Some(scope) = scope.filter(|scope| !grant.allows(*scope)) {
+        return Err(AccessError::MissingScope(scope));
+    }
+    state
+        .rate_limiter
+        .check_api_key(grant.key_id, grant.rate_limit)
+        .await
+        .map_err(AccessError::RateLimited)?;
+    Ok(user)
+}
+
+/// Resolve the user behind the request's session token, if that session is still valid.
+/// API keys aren't accepted, see `user_from_token_or_key` for the endpoints they may use.
+async fn user_from_token(
+    pool: &sqlx::MySqlPool,
+    session_store: &SessionStore,
+    token: Option<String>,
+) -> Option<User> {
+    session_from_token(pool, session_store, token)
+        .await
+        .map(|(_, user)| user)
+}
+
+/// Like `user_from_token`, also returning the token.
+async fn session_from_token(
+    pool: &sqlx::MySqlPool,
+    session_store: &SessionStore,
+    token: Option<String>,
+) -> Option<(String, User)> {
+    let token = token?;
+    let user = auth::user_for_token(pool, session_store, &token).await?;
+    session_store.touch(pool, &token).await;
+    Some((token, user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_escaped_for_the_page() {
+        assert_eq!(
+            escape_html("<img/src=x/onerror=alert('1')>\"&\"@x.y"),
+            "&lt;img/src=x/onerror=alert(&#39;1&#39;)&gt;&quot;&amp;&quot;@x.y"
+        );
+        assert_eq!(escape_html("someone@example.com"), "someone@example.com");
     }
-    None
 }
@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::rate_limit::Rule;
+use crate::tables::api_key_db::{self, ApiKey};
+use crate::tables::sanction_db::{self, SanctionKind};
+use crate::tables::user_db::{self, User};
+use crate::ws_types::MessageType;
+
+/// Every key starts with this, which is how they're told apart from session tokens.
+pub const KEY_PREFIX: &str = "gck_";
+/// Limit of a key created without one.
+pub const DEFAULT_RATE_LIMIT: &str = "60/60";
+pub const MAX_KEYS_PER_USER: i64 = 20;
+pub const MAX_NAME_CHARS: usize = 64;
+/// A key's secret is argon2-checked again after this long, its owner looked up and its `last_used_at` updated.
+const VERIFIED_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// What a key may be used for. Keys can never manage sessions, keys, roles or moderate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `/api/dm_history`
+    ReadHistory,
+    /// Talk in rooms: `broadcast`, `ephemeral`, `edit`, `delete`, `reaction` and `typing`
+    Broadcast,
+    /// Send `private` messages
+    Private,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadHistory => "read_history",
+            Scope::Broadcast => "broadcast",
+            Scope::Private => "private",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "read_history" => Some(Scope::ReadHistory),
+            "broadcast" => Some(Scope::Broadcast),
+            "private" => Some(Scope::Private),
+            _ => None,
+        }
+    }
+
+    /// Scopes as stored in `api_keys.scopes`.
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn split(value: &str) -> Vec<Scope> {
+        value.split(',').filter_map(|s| Scope::parse(s.trim())).collect()
+    }
+}
+
+/// A key as listed to its owner. The key itself is only ever shown when it's created.
+#[derive(Debug, Serialize)]
+pub struct ApiKeyView {
+    pub id: i64,
+    pub name: String,
+    /// Identifies the key, `gck_<prefix>_...`
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub rate_limit: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyView {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyView {
+            id: key.id,
+            scopes: Scope::split(&key.scopes),
+            name: key.name,
+            prefix: key.prefix,
+            rate_limit: key.rate_limit,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+/// What a request made with an API key may do.
+#[derive(Debug, Clone)]
+pub struct KeyGrant {
+    pub key_id: i64,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub rate_limit: Rule,
+}
+
+impl KeyGrant {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Whether a WebSocket message of `msg_type` may be sent with this key.
+    pub fn allows_message(&self, msg_type: MessageType) -> bool {
+        match msg_type {
+            MessageType::Broadcast
+            | MessageType::Ephemeral
+            | MessageType::Edit
+            | MessageType::Delete
+            | MessageType::Reaction
+            | MessageType::Typing => self.allows(Scope::Broadcast),
+            MessageType::Private => self.allows(Scope::Private),
+            MessageType::Join | MessageType::Leave | MessageType::Presence | MessageType::Who => true,
+            MessageType::Mute | MessageType::Kick | MessageType::Ban => false,
+        }
+    }
+}
+
+/// What sockets opened with key `prefix` are registered under, instead of the secret key.
+pub fn connection_label(prefix: &str) -> String {
+    format!("{}{}", KEY_PREFIX, prefix)
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+/// A new random key, as `(prefix, secret, full key)`.
+pub fn generate() -> (String, String, String) {
+    let prefix = uuid::Uuid::new_v4().simple().to_string()[..12].to_string();
+    let secret = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let key = format!("{}{}_{}", KEY_PREFIX, prefix, secret);
+    (prefix, secret, key)
+}
+
+/// A key that passed the argon2 check recently, and the owner it had then, who wasn't banned.
+struct Verified {
+    /// SHA-256 of the whole key, cheap to compare on every use
+    digest: Vec<u8>,
+    grant: KeyGrant,
+    owner: User,
+    checked_at: Instant,
+}
+
+/// Checks API keys, remembering the ones verified lately so argon2 doesn't run on every message.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    verified: Arc<RwLock<HashMap<String, Verified>>>,
+}
+
+impl ApiKeys {
+    /// The user a key acts for and what it may do, if it exists and they aren't banned.
+    /// Both are remembered for `VERIFIED_TTL`, so messages sent with the key cost no query.
+    /// Revoking the key, and banning, renaming or changing the role of its owner, make every
+    /// instance `forget` it straight away.
+    pub async fn authenticate(&self, pool: &sqlx::MySqlPool, key: &str) -> Option<(User, KeyGrant)> {
+        let (prefix, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+        let digest = Sha256::digest(key.as_bytes()).to_vec();
+
+        let cached = {
+            let verified = self.verified.read().await;
+            verified
+                .get(prefix)
+                .filter(|v| v.digest == digest && v.checked_at.elapsed() < VERIFIED_TTL)
+                .map(|v| (v.owner.clone(), v.grant.clone()))
+        };
+        if cached.is_some() {
+            return cached;
+        }
+
+        let row = api_key_db::get_api_key_by_prefix(pool, prefix).await.ok()?;
+        user_db::verify_password(secret, &row.key_hash).ok()?;
+        let owner = api_key_db::get_api_key_owner(pool, row.id).await.ok()?;
+        if !matches!(sanction_db::get_active(pool, owner.id, SanctionKind::Ban).await, Ok(None)) {
+            return None;
+        }
+        if api_key_db::touch_api_key(pool, row.id).await.is_err() {
+            println!("Failed to update last_used_at of an API key");
+        }
+        let grant = KeyGrant {
+            key_id: row.id,
+            prefix: row.prefix,
+            scopes: Scope::split(&row.scopes),
+            rate_limit: Rule::parse(&row.rate_limit)
+                .or_else(|| Rule::parse(DEFAULT_RATE_LIMIT))?,
+        };
+        self.verified.write().await.insert(
+            prefix.to_string(),
+            Verified { digest, grant: grant.clone(), owner: owner.clone(), checked_at: Instant::now() },
+        );
+        Some((owner, grant))
+    }
+
+    /// Drop a revoked key from what's been verified here.
+    pub async fn forget(&self, prefix: &str) {
+        self.verified.write().await.remove(prefix);
+    }
+
+    /// Drop the keys of `user_id`, whose name, role or standing changed.
+    pub async fn forget_user(&self, user_id: i32) {
+        self.verified.write().await.retain(|_, v| v.owner.id != user_id);
+    }
+
+    /// Drop verifications that would be redone anyway.
+    pub async fn prune(&self) {
+        self.verified
+            .write()
+            .await
+            .retain(|_, v| v.checked_at.elapsed() < VERIFIED_TTL);
+    }
+}
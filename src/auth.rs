@@ -0,0 +1,143 @@
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::api_keys::{self, KeyGrant};
+use crate::sessions::SessionStore;
+use crate::tables::sanction_db::{self, SanctionKind};
+use crate::tables::user_db::User;
+use crate::ws_handler::ChatState;
+
+/// Who a request acts as: a user logged in with a session, or through one of their API keys.
+pub struct Principal {
+    pub user: User,
+    /// `None` for a session
+    pub key: Option<KeyGrant>,
+}
+
+impl Principal {
+    /// What a socket opened with `token` is registered under, see `ConnHandle::session_token`.
+    pub fn connection_label(&self, token: &str) -> String {
+        match &self.key {
+            Some(key) => api_keys::connection_label(&key.prefix),
+            None => token.to_string(),
+        }
+    }
+}
+
+/// Who a chat message comes from, all a WebSocket frame needs to know.
+pub struct Identity {
+    pub user_id: i32,
+    pub username: String,
+    /// `None` for a session
+    pub key: Option<KeyGrant>,
+}
+
+impl Identity {
+    /// See `Principal::connection_label`.
+    pub fn connection_label(&self, token: &str) -> String {
+        match &self.key {
+            Some(key) => api_keys::connection_label(&key.prefix),
+            None => token.to_string(),
+        }
+    }
+}
+
+impl From<Principal> for Identity {
+    fn from(principal: Principal) -> Self {
+        Identity {
+            user_id: principal.user.id,
+            username: principal.user.username,
+            key: principal.key,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionQuery {
+    session_id: Option<String>,
+}
+
+/// The session token or API key a request carries, looked for in order in `Authorization: Bearer <token>`
+/// and the `session_token` cookie. Cookies suit browsers, the header CLI and bot clients.
+pub fn session_token() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("cookie"))
+        .map(|authorization: Option<String>, cookie: Option<String>| {
+            authorization
+                .as_deref()
+                .and_then(extract_bearer_token)
+                .or_else(|| extract_session_token(&cookie?))
+        })
+}
+
+/// Like `session_token`, also taking `?session_id=<token>` after the header. Only for the WebSocket
+/// upgrade, which browsers can't add headers to. URLs end up in access logs, so nothing else accepts it.
+pub fn ws_session_token() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<SessionQuery>())
+        .and(warp::header::optional::<String>("cookie"))
+        .map(|authorization: Option<String>, query: SessionQuery, cookie: Option<String>| {
+            authorization
+                .as_deref()
+                .and_then(extract_bearer_token)
+                .or(query.session_id.filter(|token| !token.is_empty()))
+                .or_else(|| extract_session_token(&cookie?))
+        })
+}
+
+fn extract_bearer_token(authorization: &str) -> Option<String> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") || token.trim().is_empty() {
+        return None;
+    }
+    Some(token.trim().to_string())
+}
+
+fn extract_session_token(cookie_str: &str) -> Option<String> {
+    for cookie in cookie_str.split(';') {
+        let parts: Vec<&str> = cookie.trim().splitn(2, '=').collect();
+        if parts.len() == 2 && parts[0] == "session_token" {
+            return Some(parts[1].to_string());
+        }
+    }
+    None
+}
+
+/// The user a session token belongs to, if it's still valid and they aren't banned.
+pub async fn user_for_token(
+    pool: &sqlx::MySqlPool,
+    session_store: &SessionStore,
+    token: &str,
+) -> Option<User> {
+    let session = session_store.get(pool, token).await?;
+    let user = crate::tables::user_db::get_user_by_id(pool, session.user_id).await.ok()?;
+    // Banning deletes the sessions too, this catches a request racing with it
+    match sanction_db::get_active(pool, user.id, SanctionKind::Ban).await {
+        Ok(None) => Some(user),
+        _ => None,
+    }
+}
+
+/// Resolve a session token or an API key.
+pub async fn authenticate(state: &ChatState, token: &str) -> Option<Principal> {
+    if api_keys::is_api_key(token) {
+        let (user, key) = state.api_keys.authenticate(&state.pool, token).await?;
+        return Some(Principal { user, key: Some(key) });
+    }
+    let user = user_for_token(&state.pool, &state.session_store, token).await?;
+    Some(Principal { user, key: None })
+}
+
+/// Like `authenticate`, but sessions are taken from the session store, revocations aside,
+/// so the messages of a session cost no database lookup. API keys still need one.
+pub async fn identify(state: &ChatState, token: &str) -> Option<Identity> {
+    if api_keys::is_api_key(token) {
+        return authenticate(state, token).await.map(Identity::from);
+    }
+    let session = state.session_store.get(&state.pool, token).await?;
+    Some(Identity {
+        user_id: session.user_id,
+        username: session.username,
+        key: None,
+    })
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Failures are forgotten after this long without a new one.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+/// First backoff once the free attempts are used up, doubled on every further failure.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How long a key stays locked once it reaches its lockout threshold.
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// What an attempt is counted against. IPs get more slack since many users can share one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    /// Failed logins for a username, whatever IP they come from
+    LoginUsername,
+    /// Failed logins from an IP, whatever username they try
+    LoginIp,
+    /// Every registration attempt from an IP
+    RegisterIp,
+    /// Every password reset request from an IP
+    ResetIp,
+}
+
+impl KeyKind {
+    /// Attempts allowed before backing off, and before locking out.
+    fn thresholds(self) -> (u32, u32) {
+        match self {
+            KeyKind::LoginUsername => (3, 10),
+            KeyKind::LoginIp => (10, 50),
+            KeyKind::RegisterIp => (3, 10),
+            KeyKind::ResetIp => (5, 20),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Attempt counters per (kind, key), keys being a lowercased username or a client IP.
+pub type AuthThrottle = Arc<Mutex<HashMap<(KeyKind, String), Attempts>>>;
+
+/// Create an empty throttle.
+pub fn new_registry() -> AuthThrottle {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+/// How long until every key may try again, `None` if all of them may right now.
+pub async fn check(throttle: &AuthThrottle, keys: &[(KeyKind, String)]) -> Option<Duration> {
+    let now = Instant::now();
+    let map = throttle.lock().await;
+    keys.iter()
+        .filter_map(|key| map.get(key)?.blocked_until)
+        .filter(|until| *until > now)
+        .map(|until| until - now)
+        .max()
+}
+
+/// Count a failed attempt against every key, backing off or locking out the ones over their threshold.
+pub async fn record_failure(throttle: &AuthThrottle, keys: &[(KeyKind, String)]) {
+    let now = Instant::now();
+    let mut map = throttle.lock().await;
+    for (kind, key) in keys {
+        let attempts = map.entry((*kind, key.clone())).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            blocked_until: None,
+        });
+        if now.duration_since(attempts.last_failure) > FORGET_AFTER {
+            attempts.failures = 0;
+        }
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        let (free, lockout) = kind.thresholds();
+        if attempts.failures >= lockout {
+            attempts.blocked_until = Some(now + LOCKOUT);
+        } else if attempts.failures > free {
+            let doublings = (attempts.failures - free - 1).min(16);
+            let backoff = (BASE_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF);
+            attempts.blocked_until = Some(now + backoff);
+        }
+    }
+}
+
+/// Forget the failures of a key, after a successful login.
+pub async fn record_success(throttle: &AuthThrottle, key: &(KeyKind, String)) {
+    throttle.lock().await.remove(key);
+}
+
+/// Drop counters that no longer block anything and would be forgotten anyway.
+pub async fn prune(throttle: &AuthThrottle) {
+    let now = Instant::now();
+    throttle.lock().await.retain(|_, attempts| {
+        attempts.blocked_until.is_some_and(|until| until > now)
+            || now.duration_since(attempts.last_failure) <= FORGET_AFTER
+    });
+}
+
+/// Keys for a login attempt.
+pub fn login_keys(client_ip: &str, username: &str) -> [(KeyKind, String); 2] {
+    [
+        (KeyKind::LoginUsername, username.to_lowercase()),
+        (KeyKind::LoginIp, client_ip.to_string()),
+    ]
+}
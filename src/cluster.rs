@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+
+use crate::api_keys::{self, ApiKeys};
+use crate::connected_users::{self, ConnectedUsers};
+use crate::db::secrets::get_secret;
+use crate::rooms::{self, Rooms};
+use crate::presence::Presence;
+use crate::sessions::SessionStore;
+use crate::ws_handler;
+
+pub use local_bus::LocalBus;
+pub use redis_bus::RedisBus;
+
+mod local_bus;
+mod redis_bus;
+
+/// How long a WHO probe waits for another instance to claim the user.
+pub const WHO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long an online probe collects answers. Every instance answers, so unlike
+/// WHO it always waits the whole window.
+pub const ONLINE_PROBE_WINDOW: std::time::Duration = std::time::Duration::from_millis(300);
+
+/// What instances tell each other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    /// An already serialized `WsOutgoing` for everyone in `room`, on every instance
+    RoomBroadcast { room: String, frame: String },
+    /// An already serialized `WsOutgoing` for every connected socket, on every instance
+    AllUsers { frame: String },
+    /// Does anyone have a socket open for this user?
+    WhoProbe { request_id: String, user_id: i32 },
+    /// Answer to a `WhoProbe`: the publishing instance has the user online
+    WhoReply { request_id: String, user_id: i32 },
+    /// Which users are connected to you?
+    OnlineProbe { request_id: String },
+    /// Answer to an `OnlineProbe`: the users the publishing instance has sockets for
+    OnlineReply { request_id: String, user_ids: Vec<i32> },
+    /// Close every socket held for this user
+    Kick { user_id: i32 },
+    /// Close the sockets of this user that authenticated with one of `tokens`
+    SessionsRevoked { user_id: i32, tokens: Vec<String> },
+    /// `user_id` is now called `username`
+    Renamed { user_id: i32, username: String },
+    /// `user_id` got a new role, was muted, unmuted or banned, or is gone
+    StandingChanged { user_id: i32 },
+    /// Deliver private message `pm_id` to the sockets `target` holds for `user_id`
+    PrivateDelivery {
+        target: String,
+        user_id: i32,
+        pm_id: i64,
+        frame: String,
+    },
+}
+
+/// An event plus the instance that published it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterMessage {
+    pub origin: String,
+    #[serde(flatten)]
+    pub event: ClusterEvent,
+}
+
+/// Transport that carries `ClusterEvent`s between server instances.
+pub trait ClusterBus: Send + Sync {
+    /// Unique id of this instance, stamped as `origin` on everything it publishes.
+    fn instance_id(&self) -> &str;
+    /// Send an event to every instance. Fire and forget, failures are only logged.
+    fn publish(&self, event: ClusterEvent);
+    /// Every message on the bus, this instance's own included.
+    fn subscribe(&self) -> broadcast::Receiver<ClusterMessage>;
+    /// Whether nobody else can be listening, so probes needn't wait for answers.
+    fn is_standalone(&self) -> bool {
+        false
+    }
+}
+
+/// Shared handle to the bus plus the probes this instance is waiting on.
+#[derive(Clone)]
+pub struct Cluster {
+    bus: Arc<dyn ClusterBus>,
+    pending_who: Arc<Mutex<HashMap<String, oneshot::Sender<String>>>>,
+    pending_online: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Vec<i32>>>>>,
+}
+
+impl Cluster {
+    pub fn new(bus: Arc<dyn ClusterBus>) -> Self {
+        Cluster {
+            bus,
+            pending_who: Arc::new(Mutex::new(HashMap::new())),
+            pending_online: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn instance_id(&self) -> &str {
+        self.bus.instance_id()
+    }
+
+    pub fn publish(&self, event: ClusterEvent) {
+        self.bus.publish(event);
+    }
+
+    /// Ask every other instance whether `user_id` is connected there.
+    /// Returns the id of the first instance that answers within `WHO_TIMEOUT`.
+    pub async fn who(&self, user_id: i32) -> Option<String> {
+        if self.bus.is_standalone() {
+            return None;
+        }
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending_who
+            .lock()
+            .await
+            .insert(request_id.clone(), reply_tx);
+
+        self.publish(ClusterEvent::WhoProbe {
+            request_id: request_id.clone(),
+            user_id,
+        });
+        let answer = tokio::time::timeout(WHO_TIMEOUT, reply_rx).await;
+
+        self.pending_who.lock().await.remove(&request_id);
+        answer.ok()?.ok()
+    }
+
+    /// Ask every other instance which users are connected there.
+    /// Returns whatever arrived within `ONLINE_PROBE_WINDOW`, duplicates included.
+    pub async fn online_elsewhere(&self) -> Vec<i32> {
+        if self.bus.is_standalone() {
+            return Vec::new();
+        }
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        self.pending_online
+            .lock()
+            .await
+            .insert(request_id.clone(), reply_tx);
+
+        self.publish(ClusterEvent::OnlineProbe {
+            request_id: request_id.clone(),
+        });
+        let mut user_ids = Vec::new();
+        let _ = tokio::time::timeout(ONLINE_PROBE_WINDOW, async {
+            while let Some(ids) = reply_rx.recv().await {
+                user_ids.extend(ids);
+            }
+        })
+        .await;
+
+        self.pending_online.lock().await.remove(&request_id);
+        user_ids
+    }
+}
+
+/// Pick the bus from the environment: Redis pub/sub when `REDIS_URL_NAME` names a
+/// secret holding the redis url, otherwise a single in-process instance.
+pub async fn create_bus() -> Result<Arc<dyn ClusterBus>, redis::RedisError> {
+    match env::var("REDIS_URL_NAME") {
+        Ok(name) => Ok(Arc::new(RedisBus::connect(&get_secret(&name)).await?)),
+        Err(_) => Ok(Arc::new(LocalBus::new())),
+    }
+}
+
+/// Apply what other instances publish to this one.
+pub fn spawn_listener(
+    cluster: Cluster,
+    pool: sqlx::MySqlPool,
+    rooms: Rooms,
+    connected: ConnectedUsers,
+    session_store: SessionStore,
+    api_keys: ApiKeys,
+    presence: Presence,
+) {
+    let mut bus_rx = cluster.bus.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let msg = match bus_rx.recv().await {
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    println!("Cluster listener lagged, {} events dropped", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if msg.origin == cluster.instance_id() {
+                continue;
+            }
+
+            match msg.event {
+                ClusterEvent::RoomBroadcast { room, frame } => {
+                    // Only rooms someone here has loaded can have local listeners
+                    if let Some(room) = rooms::get(&rooms, &room).await {
+                        let _ = room.tx.send(frame);
+                    }
+                }
+                ClusterEvent::AllUsers { frame } => {
+                    connected_users::send_to_all(&connected, &frame).await;
+                }
+                ClusterEvent::WhoProbe { request_id, user_id } => {
+                    if connected_users::is_online(&connected, user_id).await {
+                        cluster.publish(ClusterEvent::WhoReply { request_id, user_id });
+                    }
+                }
+                ClusterEvent::WhoReply { request_id, .. } => {
+                    if let Some(reply_tx) = cluster.pending_who.lock().await.remove(&request_id) {
+                        let _ = reply_tx.send(msg.origin);
+                    }
+                }
+                ClusterEvent::OnlineProbe { request_id } => {
+                    let user_ids = connected_users::get_online_user_ids(&connected).await;
+                    cluster.publish(ClusterEvent::OnlineReply { request_id, user_ids });
+                }
+                ClusterEvent::OnlineReply { request_id, user_ids } => {
+                    if let Some(reply_tx) = cluster.pending_online.lock().await.get(&request_id) {
+                        let _ = reply_tx.send(user_ids);
+                    }
+                }
+                ClusterEvent::Kick { user_id } => {
+                    if connected_users::close_all_connections(&connected, user_id).await > 0 {
+                        ws_handler::went_offline(&connected, &cluster, &presence, &pool, user_id, None).await;
+                    }
+                }
+                ClusterEvent::SessionsRevoked { user_id, tokens } => {
+                    session_store.forget(&tokens).await;
+                    // Revoked keys come as their connection label
+                    for prefix in tokens.iter().filter_map(|token| token.strip_prefix(api_keys::KEY_PREFIX)) {
+                        api_keys.forget(prefix).await;
+                    }
+                    if connected_users::close_session_connections(&connected, user_id, &tokens).await {
+                        ws_handler::went_offline(&connected, &cluster, &presence, &pool, user_id, None).await;
+                    }
+                }
+                ClusterEvent::Renamed { user_id, username } => {
+                    session_store.rename(user_id, &username).await;
+                    api_keys.forget_user(user_id).await;
+                }
+                ClusterEvent::StandingChanged { user_id } => {
+                    session_store.forget_standing(user_id).await;
+                    api_keys.forget_user(user_id).await;
+                }
+                ClusterEvent::PrivateDelivery { target, user_id, pm_id, frame } => {
+                    if target != cluster.instance_id() {
+                        continue;
+                    }
+                    // Same claim as a local delivery, the user may have reconnected elsewhere
+                    if matches!(crate::tables::private_message_db::mark_delivered(&pool, pm_id).await, Ok(true))
+                        && connected_users::send_to_user(&connected, user_id, &frame).await == 0
+                    {
+                        let _ = crate::tables::private_message_db::mark_undelivered(&pool, pm_id).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_the_wire() {
+        let msg = ClusterMessage {
+            origin: "instance".to_string(),
+            event: ClusterEvent::SessionsRevoked { user_id: 7, tokens: vec!["a".to_string()] },
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""kind":"sessions_revoked""#));
+        let back: ClusterMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.origin, "instance");
+        assert!(matches!(back.event, ClusterEvent::SessionsRevoked { user_id: 7, ref tokens } if tokens == &["a"]));
+    }
+
+    /// Pretends to have company, but nobody ever answers.
+    struct SilentBus(broadcast::Sender<ClusterMessage>);
+
+    impl ClusterBus for SilentBus {
+        fn instance_id(&self) -> &str {
+            "silent"
+        }
+
+        fn publish(&self, _event: ClusterEvent) {}
+
+        fn subscribe(&self) -> broadcast::Receiver<ClusterMessage> {
+            self.0.subscribe()
+        }
+    }
+
+    #[tokio::test]
+    async fn who_gives_up_when_nobody_answers() {
+        let cluster = Cluster::new(Arc::new(SilentBus(broadcast::channel(1).0)));
+        assert_eq!(cluster.who(1).await, None);
+        assert!(cluster.pending_who.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn standalone_instances_skip_the_probes() {
+        let cluster = Cluster::new(Arc::new(LocalBus::new()));
+        let who = tokio::time::timeout(std::time::Duration::from_millis(100), cluster.who(1)).await;
+        assert_eq!(who, Ok(None));
+        assert!(cluster.online_elsewhere().await.is_empty());
+    }
+}
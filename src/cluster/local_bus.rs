@@ -0,0 +1,77 @@
+use tokio::sync::broadcast;
+
+use super::{ClusterBus, ClusterEvent, ClusterMessage};
+
+/// In-process bus: everything published is only seen by this process.
+/// Used when running a single instance, and to exercise the cluster logic without Redis.
+pub struct LocalBus {
+    instance_id: String,
+    tx: broadcast::Sender<ClusterMessage>,
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        LocalBus {
+            instance_id: uuid::Uuid::new_v4().to_string(),
+            tx: broadcast::channel::<ClusterMessage>(100).0,
+        }
+    }
+}
+
+impl Default for LocalBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClusterBus for LocalBus {
+    fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    fn publish(&self, event: ClusterEvent) {
+        let _ = self.tx.send(ClusterMessage {
+            origin: self.instance_id.clone(),
+            event,
+        });
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ClusterMessage> {
+        self.tx.subscribe()
+    }
+
+    fn is_standalone(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_get_events_in_order_with_the_origin() {
+        let bus = LocalBus::new();
+        let mut rx = bus.subscribe();
+        bus.publish(ClusterEvent::Kick { user_id: 1 });
+        bus.publish(ClusterEvent::Renamed { user_id: 1, username: "bob".to_string() });
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.origin, bus.instance_id());
+        assert!(matches!(first.event, ClusterEvent::Kick { user_id: 1 }));
+        let second = rx.recv().await.unwrap();
+        assert!(matches!(second.event, ClusterEvent::Renamed { user_id: 1, ref username } if username == "bob"));
+    }
+
+    #[test]
+    fn publishing_without_subscribers_is_fine() {
+        let bus = LocalBus::new();
+        bus.publish(ClusterEvent::Kick { user_id: 1 });
+        assert!(bus.is_standalone());
+    }
+
+    #[test]
+    fn instances_have_their_own_ids() {
+        assert_ne!(LocalBus::new().instance_id(), LocalBus::new().instance_id());
+    }
+}
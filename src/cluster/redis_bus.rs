@@ -0,0 +1,126 @@
+use futures_util::StreamExt as _;
+use redis::AsyncCommands;
+use tokio::sync::{broadcast, mpsc};
+
+use super::{ClusterBus, ClusterEvent, ClusterMessage};
+
+/// Redis channel every instance publishes to and listens on.
+const CHANNEL: &str = "chat-global:cluster";
+
+/// Redis pub/sub bus, lets several instances share one deployment.
+pub struct RedisBus {
+    instance_id: String,
+    /// Serialized messages for the one task publishing them, so they go out in order
+    outbox: mpsc::UnboundedSender<String>,
+    tx: broadcast::Sender<ClusterMessage>,
+}
+
+impl RedisBus {
+    /// Connect to `url` and start relaying the cluster channel into this process.
+    pub async fn connect(url: &str) -> Result<Self, redis::RedisError> {
+        let client = redis::Client::open(url)?;
+        let mut publisher = client.get_multiplexed_async_connection().await?;
+        let (tx, _rx) = broadcast::channel::<ClusterMessage>(100);
+
+        // Subscribe before returning so nothing published after startup is missed
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(CHANNEL).await?;
+
+        let relay_tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut messages = pubsub.into_on_message();
+                while let Some(msg) = messages.next().await {
+                    let Ok(payload) = msg.get_payload::<String>() else {
+                        continue;
+                    };
+                    match serde_json::from_str::<ClusterMessage>(&payload) {
+                        Ok(cluster_msg) => {
+                            let _ = relay_tx.send(cluster_msg);
+                        }
+                        Err(_) => println!("Ignoring malformed cluster message: {}", payload),
+                    }
+                }
+
+                // Connection dropped, keep retrying until redis is back
+                println!("Lost redis subscription, reconnecting");
+                pubsub = loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    if let Ok(mut pubsub) = client.get_async_pubsub().await {
+                        if pubsub.subscribe(CHANNEL).await.is_ok() {
+                            break pubsub;
+                        }
+                    }
+                };
+            }
+        });
+
+        // Ends with the bus, once every message handed to it is out
+        let (outbox, mut outbox_rx) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(payload) = outbox_rx.recv().await {
+                if let Err(e) = publisher.publish::<_, _, ()>(CHANNEL, payload).await {
+                    println!("Failed to publish cluster event: {}", e);
+                }
+            }
+        });
+
+        Ok(RedisBus {
+            instance_id: uuid::Uuid::new_v4().to_string(),
+            outbox,
+            tx,
+        })
+    }
+}
+
+impl ClusterBus for RedisBus {
+    fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    fn publish(&self, event: ClusterEvent) {
+        let msg = ClusterMessage {
+            origin: self.instance_id.clone(),
+            event,
+        };
+        let Ok(payload) = serde_json::to_string(&msg) else {
+            return;
+        };
+        let _ = self.outbox.send(payload);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ClusterMessage> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs a redis server: `docker run -p 6379:6379 redis`, then `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn events_arrive_in_publishing_order() {
+        let bus = RedisBus::connect("redis://127.0.0.1:6379").await.unwrap();
+        let mut rx = bus.subscribe();
+        for user_id in 0..200 {
+            bus.publish(ClusterEvent::Kick { user_id });
+        }
+        let mut received = Vec::new();
+        while received.len() < 200 {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            // Other instances may share the server
+            if msg.origin != bus.instance_id() {
+                continue;
+            }
+            if let ClusterEvent::Kick { user_id } = msg.event {
+                received.push(user_id);
+            }
+        }
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+    }
+}
@@ -1,39 +1,122 @@
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify, RwLock};
 use warp::filters::ws::Message;
 
-/// Each user_id maps to a list of senders (one per open tab/connection).
-pub type ConnectedUsers = Arc<RwLock<HashMap<i32, Vec<mpsc::UnboundedSender<Message>>>>>;
+/// Identifies one open socket. Never reused while the process runs, so closing
+/// one tab can't shift another tab's id the way Vec indices did.
+pub type ConnId = u64;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Frames a socket may have waiting to be written before its client counts as too slow.
+pub const OUTBOX_CAPACITY: usize = 256;
+
+/// Where everything a socket sends goes, for its writer task. Bounded, so a client that stops
+/// reading gets disconnected instead of piling up frames.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    tx: mpsc::Sender<Message>,
+    /// Stops the socket's receive loop, so a closed connection can't keep sending
+    closed: Arc<Notify>,
+}
+
+impl Outbox {
+    /// An outbox, and the receiving end for the socket's writer task.
+    pub fn new() -> (Outbox, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(OUTBOX_CAPACITY);
+        (Outbox { tx, closed: Arc::new(Notify::new()) }, rx)
+    }
+
+    /// Queue a frame. Returns false if the socket is gone, or too far behind, which closes it.
+    pub fn send(&self, msg: Message) -> bool {
+        match self.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.closed.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// The writer task sends the close frame and stops, the receive loop stops reading.
+    pub fn close(&self) {
+        let _ = self.tx.try_send(Message::close());
+        // Stored as a permit when the loop is busy with a frame, it stops right after that one
+        self.closed.notify_one();
+    }
+
+    /// Completes once the socket was closed from outside or fell too far behind.
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+}
+
+/// Where a connection comes from, as reported by the proxy.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// One open socket (tab/connection) of a user.
+#[derive(Debug, Clone)]
+pub struct ConnHandle {
+    pub sender: Outbox,
+    pub opened_at: DateTime<Utc>,
+    pub client: ClientInfo,
+    /// Token the socket authenticated with, so revoking the session can close it.
+    /// For an API key `gck_<prefix>`, see `api_keys::connection_label`
+    pub session_token: String,
+}
+
+/// What `list_connections` reports about a socket.
+#[derive(Debug, serde::Serialize)]
+pub struct ConnInfo {
+    pub id: ConnId,
+    pub opened_at: DateTime<Utc>,
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Each user_id maps to their open connections, keyed by connection id.
+pub type ConnectedUsers = Arc<RwLock<HashMap<i32, HashMap<ConnId, ConnHandle>>>>;
 
 /// Create an empty connected-users registry.
 pub fn new_registry() -> ConnectedUsers {
     Arc::new(RwLock::new(HashMap::new()))
 }
 
-/// Register a new sender for a user. Returns the index so we can remove it later.
-pub async fn register(
-    connected: &ConnectedUsers,
-    user_id: i32,
-    sender: mpsc::UnboundedSender<Message>,
-) -> usize {
+/// Register a connection for a user. Returns its id so we can remove it later,
+/// and whether it is the user's first connection (they just came online).
+pub async fn register(connected: &ConnectedUsers, user_id: i32, handle: ConnHandle) -> (ConnId, bool) {
+    let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
     let mut map = connected.write().await;
-    let senders = map.entry(user_id).or_default();
-    senders.push(sender);
-    senders.len() - 1
+    let conns = map.entry(user_id).or_default();
+    conns.insert(conn_id, handle);
+    (conn_id, conns.len() == 1)
 }
 
-/// Remove a sender for a user by index. Cleans up the entry if empty.
-pub async fn deregister(connected: &ConnectedUsers, user_id: i32, index: usize) {
+/// Remove a connection of a user. Cleans up the entry if it was the last one.
+/// Returns whether this removed the user's last connection (they just went offline). False when
+/// one of the `close_*` functions already removed it, whoever did that announces it.
+pub async fn deregister(connected: &ConnectedUsers, user_id: i32, conn_id: ConnId) -> bool {
     let mut map = connected.write().await;
-    if let Some(senders) = map.get_mut(&user_id) {
-        if index < senders.len() {
-            senders.remove(index);
-        }
-        if senders.is_empty() {
-            map.remove(&user_id);
-        }
+    let Some(conns) = map.get_mut(&user_id) else {
+        return false;
+    };
+    if conns.remove(&conn_id).is_none() {
+        return false;
     }
+    if !conns.is_empty() {
+        return false;
+    }
+    map.remove(&user_id);
+    true
 }
 
 /// Send a text message to all connections of a specific user.
@@ -45,9 +128,9 @@ pub async fn send_to_user(
 ) -> usize {
     let map = connected.read().await;
     let mut delivered = 0;
-    if let Some(senders) = map.get(&user_id) {
-        for sender in senders {
-            if sender.send(Message::text(text)).is_ok() {
+    if let Some(conns) = map.get(&user_id) {
+        for conn in conns.values() {
+            if conn.sender.send(Message::text(text)) {
                 delivered += 1;
             }
         }
@@ -55,8 +138,168 @@ pub async fn send_to_user(
     delivered
 }
 
+/// Send a text message to every connection on this instance.
+pub async fn send_to_all(connected: &ConnectedUsers, text: &str) {
+    let map = connected.read().await;
+    for conn in map.values().flat_map(|conns| conns.values()) {
+        conn.sender.send(Message::text(text));
+    }
+}
+
+/// List the open connections of a user, oldest first.
+pub async fn list_connections(connected: &ConnectedUsers, user_id: i32) -> Vec<ConnInfo> {
+    let map = connected.read().await;
+    let mut conns: Vec<ConnInfo> = map
+        .get(&user_id)
+        .map(|conns| {
+            conns
+                .iter()
+                .map(|(id, conn)| ConnInfo {
+                    id: *id,
+                    opened_at: conn.opened_at,
+                    remote_addr: conn.client.remote_addr.clone(),
+                    user_agent: conn.client.user_agent.clone(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    conns.sort_by_key(|conn| conn.id);
+    conns
+}
+
+/// Close one connection of a user and forget it. Returns `None` if the user has no connection
+/// with that id, otherwise whether it was their last one here (they just went offline).
+pub async fn close_connection(connected: &ConnectedUsers, user_id: i32, conn_id: ConnId) -> Option<bool> {
+    let mut map = connected.write().await;
+    let conns = map.get_mut(&user_id)?;
+    let conn = conns.remove(&conn_id)?;
+    conn.sender.close();
+    if !conns.is_empty() {
+        return Some(false);
+    }
+    map.remove(&user_id);
+    Some(true)
+}
+
+/// Close the connections of a user that authenticated with one of `tokens`.
+/// Returns whether that left them without connections here (they just went offline).
+pub async fn close_session_connections(connected: &ConnectedUsers, user_id: i32, tokens: &[String]) -> bool {
+    let mut map = connected.write().await;
+    let Some(conns) = map.get_mut(&user_id) else {
+        return false;
+    };
+    conns.retain(|_, conn| {
+        if tokens.contains(&conn.session_token) {
+            conn.sender.close();
+            false
+        } else {
+            true
+        }
+    });
+    if !conns.is_empty() {
+        return false;
+    }
+    map.remove(&user_id);
+    true
+}
+
+/// Close every connection of a user. Returns how many there were, any means they just went offline.
+pub async fn close_all_connections(connected: &ConnectedUsers, user_id: i32) -> usize {
+    let mut map = connected.write().await;
+    let Some(conns) = map.remove(&user_id) else {
+        return 0;
+    };
+    for conn in conns.values() {
+        conn.sender.close();
+    }
+    conns.len()
+}
+
+/// Whether the user has at least one open connection on this instance.
+pub async fn is_online(connected: &ConnectedUsers, user_id: i32) -> bool {
+    let map = connected.read().await;
+    map.contains_key(&user_id)
+}
+
 /// Get the list of all currently connected user IDs.
 pub async fn get_online_user_ids(connected: &ConnectedUsers) -> Vec<i32> {
     let map = connected.read().await;
     map.keys().cloned().collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_full_outbox_closes_the_socket() {
+        let (outbox, mut rx) = Outbox::new();
+        for _ in 0..OUTBOX_CAPACITY {
+            assert!(outbox.send(Message::text("frame")));
+        }
+        assert!(!outbox.send(Message::text("one too many")));
+        // The receive loop would stop now
+        tokio::time::timeout(std::time::Duration::from_secs(1), outbox.closed())
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap().to_str(), Ok("frame"));
+    }
+
+    #[tokio::test]
+    async fn closing_queues_a_close_frame() {
+        let (outbox, mut rx) = Outbox::new();
+        outbox.close();
+        assert!(rx.recv().await.unwrap().is_close());
+        outbox.closed().await;
+    }
+
+    #[tokio::test]
+    async fn sending_to_a_gone_socket_fails_without_closing() {
+        let (outbox, rx) = Outbox::new();
+        drop(rx);
+        assert!(!outbox.send(Message::text("frame")));
+    }
+
+    #[tokio::test]
+    async fn closing_a_user_reaches_every_connection() {
+        let connected = new_registry();
+        let (first, mut first_rx) = Outbox::new();
+        let (second, mut second_rx) = Outbox::new();
+        for sender in [first, second] {
+            let handle = ConnHandle {
+                sender,
+                opened_at: Utc::now(),
+                client: ClientInfo::default(),
+                session_token: "token".to_string(),
+            };
+            register(&connected, 1, handle).await;
+        }
+        assert!(is_online(&connected, 1).await);
+        assert_eq!(close_all_connections(&connected, 1).await, 2);
+        assert!(!is_online(&connected, 1).await);
+        assert!(first_rx.recv().await.unwrap().is_close());
+        assert!(second_rx.recv().await.unwrap().is_close());
+    }
+
+    #[tokio::test]
+    async fn only_removing_the_last_connection_goes_offline() {
+        let connected = new_registry();
+        let mut ids = Vec::new();
+        for token in ["first", "second", "third"] {
+            let handle = ConnHandle {
+                sender: Outbox::new().0,
+                opened_at: Utc::now(),
+                client: ClientInfo::default(),
+                session_token: token.to_string(),
+            };
+            ids.push(register(&connected, 1, handle).await.0);
+        }
+        assert!(!deregister(&connected, 1, ids[0]).await);
+        assert_eq!(close_connection(&connected, 1, ids[0]).await, None);
+        assert!(!close_session_connections(&connected, 1, &["second".to_string()]).await);
+        assert_eq!(close_connection(&connected, 1, ids[2]).await, Some(true));
+        // The sockets closed from outside find themselves gone
+        assert!(!deregister(&connected, 1, ids[1]).await);
+        assert!(!deregister(&connected, 1, ids[2]).await);
+    }
+}
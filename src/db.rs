@@ -4,7 +4,7 @@ use std::env;
 
 use crate::db::secrets::get_secret;
 
-mod secrets;
+pub mod secrets;
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct ChatMessage {
@@ -13,6 +13,9 @@ pub struct ChatMessage {
     pub username: String,
     pub content: String,
     pub created_at: std::option::Option<DateTime<Utc>>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Deleted messages are kept as tombstones with empty content
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 impl serde::Serialize for ChatMessage {
@@ -22,17 +25,49 @@ impl serde::Serialize for ChatMessage {
     {
         #[derive(serde::Serialize)]
         struct SerializedChatMessage {
+            message_id: i64,
             username: String,
             content: String,
+            created_at: Option<String>,
+            edited_at: Option<String>,
+            deleted: bool,
         }
         let message = SerializedChatMessage {
+            message_id: self.message_id,
             username: self.username.clone(),
             content: self.content.clone(),
+            // Same format as the `timestamp` of live frames
+            created_at: self.created_at.map(crate::ws_types::format_timestamp),
+            edited_at: self.edited_at.map(crate::ws_types::format_timestamp),
+            deleted: self.deleted_at.is_some(),
         };
         message.serialize(serializer)
     }
 }
 
+/// A chat message plus its aggregated reactions, as the history endpoints return it.
+#[derive(serde::Serialize, Debug)]
+pub struct ReactedChatMessage {
+    #[serde(flatten)]
+    pub message: ChatMessage,
+    pub reactions: Vec<crate::tables::reaction_db::ReactionCount>,
+}
+
+/// Attach reaction counts to a page of messages, keeping their order.
+pub async fn with_reactions(
+    pool: &MySqlPool,
+    messages: Vec<ChatMessage>,
+) -> Result<Vec<ReactedChatMessage>, sqlx::Error> {
+    let ids: Vec<i64> = messages.iter().map(|m| m.message_id).collect();
+    let mut counts = crate::tables::reaction_db::get_reaction_counts_for(pool, &ids).await?;
+    Ok(messages
+        .into_iter()
+        .map(|message| ReactedChatMessage {
+            reactions: counts.remove(&message.message_id).unwrap_or_default(),
+            message,
+        })
+        .collect())
+}
 
 pub async fn create_pool() -> Result<MySqlPool, sqlx::Error> {
     let database_url = get_secret(env::var("DATABASE_URL_NAME")
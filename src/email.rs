@@ -0,0 +1,244 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::sync::Arc;
+
+use crate::mailer::{Mail, Mailer};
+use crate::sessions;
+use crate::tables::email_db::{self, TokenPurpose};
+use crate::tables::user_db::{self, User};
+use crate::validation::{self, FieldError, Policy};
+use crate::ws_handler::ChatState;
+
+const VERIFY_TTL_HOURS: i64 = 48;
+const RESET_TTL_MINS: i64 = 60;
+/// A user is mailed at most one link of each kind this often.
+const RESEND_INTERVAL_MINS: i64 = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeEmailRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    /// Username or verified email address
+    pub login: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    /// From the link in the reset mail
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmailStatus {
+    pub email: Option<String>,
+    pub verified: bool,
+}
+
+#[derive(Debug)]
+pub enum EmailError {
+    WrongPassword,
+    /// The address or new password breaks the rules
+    Invalid(FieldError),
+    InvalidLink,
+    TooSoon,
+    Database,
+}
+
+impl EmailError {
+    pub fn message(&self) -> &str {
+        match self {
+            EmailError::WrongPassword => "Wrong password",
+            EmailError::Invalid(e) => &e.message,
+            EmailError::InvalidLink => "Invalid or expired link",
+            EmailError::TooSoon => "A mail was sent a moment ago, please wait a few minutes",
+            EmailError::Database => "Database is not online, please try again later",
+        }
+    }
+}
+
+/// Sends the account mails, with links back to this server.
+#[derive(Clone)]
+pub struct Emails {
+    mailer: Arc<dyn Mailer>,
+    /// Without a trailing slash
+    public_url: Arc<String>,
+}
+
+impl Emails {
+    /// `PUBLIC_URL` is where users reach the server, for the links in mails, `http://localhost:8000` by default.
+    pub fn from_env(mailer: Arc<dyn Mailer>) -> Self {
+        let public_url = env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+        Emails {
+            mailer,
+            public_url: Arc::new(public_url.trim_end_matches('/').to_string()),
+        }
+    }
+
+    /// Give a user a new, unverified address and mail it a verification link.
+    pub async fn set_email(
+        &self,
+        pool: &sqlx::MySqlPool,
+        user_id: i32,
+        username: &str,
+        email: &str,
+    ) -> Result<(), EmailError> {
+        validation::check_email(email).map_err(EmailError::Invalid)?;
+        email_db::set_email(pool, user_id, email)
+            .await
+            .map_err(|_| EmailError::Database)?;
+        let token = issue(pool, user_id, TokenPurpose::Verify, email, chrono::Duration::hours(VERIFY_TTL_HOURS)).await?;
+        self.mailer.send(Mail {
+            to: email.to_string(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nConfirm this address for your chat account by opening\n{}/api/email/verify?token={}\n\n\
+                 The link works for {} hours. If you didn't ask for this, ignore this mail.\n",
+                username, self.public_url, token, VERIFY_TTL_HOURS
+            ),
+        });
+        Ok(())
+    }
+
+    /// Change the address once the password checks out. The same address again sends a new link.
+    pub async fn change_email(
+        &self,
+        pool: &sqlx::MySqlPool,
+        user: &User,
+        request: &ChangeEmailRequest,
+    ) -> Result<(), EmailError> {
+        if user_db::verify_password(&request.password, &user.password_hash).is_err() {
+            return Err(EmailError::WrongPassword);
+        }
+        check_resend(pool, user.id, TokenPurpose::Verify).await?;
+        self.set_email(pool, user.id, &user.username, request.email.trim()).await
+    }
+
+    /// Mail a reset link to the users `login` names, if they have a verified address.
+    /// Says nothing about whether anyone was found, so it can't be used to look up accounts.
+    pub async fn request_reset(&self, pool: &sqlx::MySqlPool, login: &str) -> Result<(), EmailError> {
+        let targets = email_db::find_reset_targets(pool, login.trim())
+            .await
+            .map_err(|_| EmailError::Database)?;
+        for target in targets {
+            match check_resend(pool, target.id, TokenPurpose::Reset).await {
+                Ok(()) => {}
+                Err(EmailError::TooSoon) => continue,
+                Err(e) => return Err(e),
+            }
+            let ttl = chrono::Duration::minutes(RESET_TTL_MINS);
+            let token = issue(pool, target.id, TokenPurpose::Reset, &target.email, ttl).await?;
+            self.mailer.send(Mail {
+                to: target.email,
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Hi {},\n\nSet a new password for your chat account by opening\n{}/?reset_token={}\n\n\
+                     The link works for {} minutes and logs you out everywhere. If you didn't ask for this, ignore this mail.\n",
+                    target.username, self.public_url, token, RESET_TTL_MINS
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub async fn status(pool: &sqlx::MySqlPool, user_id: i32) -> Result<EmailStatus, EmailError> {
+    let address = email_db::get_email(pool, user_id)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    Ok(EmailStatus {
+        verified: address.email.is_some() && address.email_verified_at.is_some(),
+        email: address.email,
+    })
+}
+
+/// Confirm an address from the link in its verification mail. Returns the address.
+pub async fn verify(pool: &sqlx::MySqlPool, token: &str) -> Result<String, EmailError> {
+    let token = email_db::take_email_token(pool, &hash(token), TokenPurpose::Verify)
+        .await
+        .map_err(|_| EmailError::Database)?
+        .ok_or(EmailError::InvalidLink)?;
+    let verified = email_db::mark_email_verified(pool, token.user_id, &token.email)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    if !verified {
+        return Err(EmailError::InvalidLink);
+    }
+    Ok(token.email)
+}
+
+/// Set a new password from a reset link. Every session of the user is ended.
+pub async fn reset_password(
+    state: &ChatState,
+    policy: &Policy,
+    request: &ResetPasswordRequest,
+) -> Result<(), EmailError> {
+    let token_hash = hash(&request.token);
+    // Checked before the link is used up, so a rejected password doesn't cost it
+    let token = email_db::find_email_token(&state.pool, &token_hash, TokenPurpose::Reset)
+        .await
+        .map_err(|_| EmailError::Database)?
+        .ok_or(EmailError::InvalidLink)?;
+    let user = user_db::get_user_by_id(&state.pool, token.user_id)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    policy
+        .check_password(&request.new_password, &user.username)
+        .map_err(|e| EmailError::Invalid(FieldError { field: "new_password", ..e }))?;
+
+    let taken = email_db::take_email_token(&state.pool, &token_hash, TokenPurpose::Reset)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    if taken.is_none() {
+        return Err(EmailError::InvalidLink);
+    }
+    user_db::update_password(&state.pool, user.id, &request.new_password)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    let tokens = user_db::delete_user_sessions(&state.pool, user.id)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    sessions::revoke(state, user.id, &tokens).await;
+    println!("User {} reset their password", user.username);
+    Ok(())
+}
+
+/// A new random token, stored hashed until it's used or expires.
+async fn issue(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    purpose: TokenPurpose,
+    email: &str,
+    ttl: chrono::Duration,
+) -> Result<String, EmailError> {
+    let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    email_db::create_email_token(pool, &hash(&token), user_id, purpose, email, Utc::now() + ttl)
+        .await
+        .map_err(|_| EmailError::Database)?;
+    Ok(token)
+}
+
+async fn check_resend(pool: &sqlx::MySqlPool, user_id: i32, purpose: TokenPurpose) -> Result<(), EmailError> {
+    match email_db::last_email_token_at(pool, user_id, purpose).await {
+        Ok(Some(sent_at)) if Utc::now() - sent_at < chrono::Duration::minutes(RESEND_INTERVAL_MINS) => {
+            Err(EmailError::TooSoon)
+        }
+        Ok(_) => Ok(()),
+        Err(_) => Err(EmailError::Database),
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
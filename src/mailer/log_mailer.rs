@@ -0,0 +1,68 @@
+use tokio::io::AsyncWriteExt;
+
+use super::{Mail, Mailer};
+
+/// Writes mails to a file, or prints them. For development and servers without SMTP,
+/// an admin can then pass links on by hand.
+pub struct LogMailer {
+    path: Option<String>,
+}
+
+impl LogMailer {
+    pub fn new(path: Option<String>) -> Self {
+        LogMailer { path }
+    }
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, mail: Mail) {
+        let entry = format!(
+            "--- {} ---\nTo: {}\nSubject: {}\n\n{}\n\n",
+            chrono::Utc::now().to_rfc3339(),
+            mail.to,
+            mail.subject,
+            mail.body
+        );
+        let Some(path) = self.path.clone() else {
+            print!("{}", entry);
+            return;
+        };
+        tokio::spawn(async move {
+            let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await;
+            let written = match file {
+                Ok(mut file) => file.write_all(entry.as_bytes()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                println!("Failed to write a mail to {}: {}", path, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mails_are_appended_to_the_log() {
+        let path = std::env::temp_dir().join(format!("mails-{}.log", uuid::Uuid::new_v4().simple()));
+        let mailer = LogMailer::new(Some(path.to_string_lossy().into_owned()));
+        mailer.send(Mail {
+            to: "someone@example.com".to_string(),
+            subject: "Confirm your email address".to_string(),
+            body: "Open the link".to_string(),
+        });
+
+        let mut written = String::new();
+        for _ in 0..50 {
+            written = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            if !written.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let _ = tokio::fs::remove_file(&path).await;
+        assert!(written.contains("To: someone@example.com\nSubject: Confirm your email address\n\nOpen the link"));
+    }
+}
@@ -0,0 +1,77 @@
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use super::{Mail, Mailer};
+
+/// Sends through an SMTP server.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// `url` as understood by lettre, see `mailer::create_mailer`. Nothing connects until the first mail.
+    pub fn new(url: &str, from: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(SmtpMailer {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: Mail) {
+        let message = mail.to.parse::<Mailbox>().map_err(|e| e.to_string()).and_then(|to| {
+            Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(mail.subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(mail.body)
+                .map_err(|e| e.to_string())
+        });
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Not mailing {}: {}", mail.to, e);
+                return;
+            }
+        };
+        let transport = self.transport.clone();
+        tokio::spawn(async move {
+            if let Err(e) = transport.send(message).await {
+                println!("Failed to send a mail: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs MailHog: `docker run -p 1025:1025 -p 8025:8025 mailhog/mailhog`, then `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn mails_reach_the_smtp_server() {
+        let to = format!("{}@example.com", uuid::Uuid::new_v4().simple());
+        let mailer = SmtpMailer::new("smtp://127.0.0.1:1025", "ws-global-chat <noreply@localhost>").unwrap();
+        mailer.send(Mail {
+            to: to.clone(),
+            subject: "Reset your password".to_string(),
+            body: "Open the link".to_string(),
+        });
+
+        let search = format!("http://127.0.0.1:8025/api/v2/search?kind=to&query={}", to);
+        for _ in 0..50 {
+            let found: serde_json::Value = reqwest::get(&search).await.unwrap().json().await.unwrap();
+            if found["total"].as_u64().unwrap_or(0) > 0 {
+                assert_eq!(found["items"][0]["Content"]["Headers"]["Subject"][0], "Reset your password");
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        panic!("{} never got the mail", to);
+    }
+}
@@ -1,62 +1,159 @@
-use tokio::sync::{broadcast, RwLock};
 use warp::Filter;
-use std::collections::HashSet;
-use std::sync::Arc;
 
-use crate::{api::{login_route, register_route, get_chat_history, get_me_route, logout_route}, routes::ws_route};
+use crate::{api::{login_route, register_route, register_requirements_route, get_chat_history, chat_history_page_route, dm_history_route, get_me_route, logout_route, list_connections_route, close_connection_route, online_route, set_role_route, moderate_route, lift_sanction_route, list_sessions_route, revoke_session_route, revoke_all_sessions_route, create_api_key_route, list_api_keys_route, revoke_api_key_route, change_password_route, rename_route, delete_account_route, login_totp_route, totp_status_route, enroll_totp_route, totp_code_route, disable_totp_route, refresh_route, email_status_route, change_email_route, verify_email_page_route, verify_email_route, forgot_password_route, reset_password_route, oauth_providers_route, oauth_start_route, oauth_callback_route}, routes::ws_route};
 //mod ~= namespace import
+mod cluster;
 mod db;
+mod account;
 mod api;
+mod api_keys;
+mod auth;
 mod routes;
 mod tables;
+mod validation;
 mod ws_handler;
 mod ws_types;
 mod connected_users;
+mod moderation;
+mod permissions;
+mod presence;
+mod auth_throttle;
+mod register_gate;
+mod rate_limit;
+mod sessions;
+mod session_tokens;
+mod totp;
+mod rooms;
+mod mailer;
+mod email;
+mod oauth;
 //declare main thread runs this
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (tx, _rx) = broadcast::channel::<String>(100);
     let pool = db::create_pool().await?;
 
-    // Session cache (in-memory HashSet of valid tokens)
-    let session_cache = Arc::new(RwLock::new(HashSet::new()));
+    // Room registry, the default room is loaded up front so every socket can subscribe to it
+    let rooms = rooms::new_registry();
+    rooms::get_or_create(&pool, &rooms, rooms::DEFAULT_ROOM, None).await?;
 
-    // Initial cache population
-    if let Ok(sessions) = crate::tables::user_db::get_all_valid_sessions(&pool).await {
-        let mut cache = session_cache.write().await;
-        for token in sessions {
-            cache.insert(token);
-        }
+    // Lookalike detection needs every user's skeleton, older accounts don't have one yet
+    match crate::tables::user_db::backfill_username_skeletons(&pool).await {
+        Ok(0) => {}
+        Ok(filled) => println!("Filled in the username skeleton of {} users", filled),
+        Err(e) => println!("Failed to fill in username skeletons: {}", e),
+    }
+
+    // Who each session belongs to, filled as tokens get used, plus the revoked signed tokens
+    let session_store = sessions::SessionStore::from_env();
+    if let Err(e) = session_store.sync(&pool).await {
+        println!("Failed to load revoked sessions: {}", e);
     }
 
     //ROUTES
-    let login_route = login_route(pool.clone(), session_cache.clone());
-    let register_route = register_route(pool.clone(), session_cache.clone());
+    // Login/register brute-force and signup bot protection
+    let auth_throttle = auth_throttle::new_registry();
+    let register_gate = register_gate::RegisterGate::from_env();
+    let policy = validation::Policy::from_env();
+    let totp = totp::Totp::from_env();
+    // Verification and password reset mails, over SMTP or into a log
+    let emails = email::Emails::from_env(mailer::create_mailer()?);
+    // Single sign-on through the configured OpenID Connect / OAuth2 providers
+    let oauth = oauth::OAuth::from_env(policy.clone(), totp.clone()).await?;
+    let login_route = login_route(pool.clone(), session_store.clone(), auth_throttle.clone(), totp.clone());
+    let login_totp_route = login_totp_route(pool.clone(), session_store.clone(), auth_throttle.clone(), totp.clone());
+    let totp_status_route = totp_status_route(pool.clone(), session_store.clone());
+    let enroll_totp_route = enroll_totp_route(pool.clone(), session_store.clone(), totp);
+    let totp_code_route = totp_code_route(pool.clone(), session_store.clone(), auth_throttle.clone());
+    let disable_totp_route = disable_totp_route(pool.clone(), session_store.clone(), auth_throttle.clone());
+    let register_requirements_route = register_requirements_route(register_gate.clone());
     let chat_history_route = get_chat_history(pool.clone());
-    let me_route = get_me_route(session_cache.clone());
-    let logout_route = logout_route(pool.clone(), session_cache.clone());
+    let chat_history_page_route = chat_history_page_route(pool.clone());
+    let me_route = get_me_route(pool.clone(), session_store.clone());
     let connected_users = connected_users::new_registry();
-    let ws_route = ws_route(pool.clone(), tx.clone(), session_cache.clone(), connected_users.clone());
 
-    let total_route = ws_route.or(login_route).or(register_route).or(chat_history_route).or(me_route).or(logout_route);
+    // Backbone shared with the other instances (in-process when running alone)
+    let cluster = cluster::Cluster::new(cluster::create_bus().await?);
+    let api_keys = api_keys::ApiKeys::default();
+    let presence = presence::new_registry();
+    cluster::spawn_listener(
+        cluster.clone(),
+        pool.clone(),
+        rooms.clone(),
+        connected_users.clone(),
+        session_store.clone(),
+        api_keys.clone(),
+        presence.clone(),
+    );
 
-    // Background task for session cleanup AND cache sync
+    let chat_state = ws_handler::ChatState {
+        pool: pool.clone(),
+        rooms: rooms.clone(),
+        session_store: session_store.clone(),
+        connected: connected_users.clone(),
+        cluster: cluster.clone(),
+        presence,
+        rate_limiter: rate_limit::RateLimiter::from_env(),
+        api_keys,
+    };
+    let ws_route = ws_route(chat_state.clone());
+    let register_route = register_route(chat_state.clone(), emails.clone(), auth_throttle.clone(), register_gate, policy.clone());
+    let dm_history_route = dm_history_route(chat_state.clone());
+    let list_connections_route = list_connections_route(pool.clone(), session_store.clone(), connected_users.clone());
+    let close_connection_route = close_connection_route(chat_state.clone());
+    let online_route = online_route(chat_state.clone());
+    let set_role_route = set_role_route(chat_state.clone());
+    let moderate_route = moderate_route(chat_state.clone());
+    let lift_sanction_route = lift_sanction_route(chat_state.clone());
+    let logout_route = logout_route(chat_state.clone());
+    let refresh_route = refresh_route(chat_state.clone());
+    let list_sessions_route = list_sessions_route(pool.clone(), session_store.clone());
+    let revoke_session_route = revoke_session_route(chat_state.clone());
+    let revoke_all_sessions_route = revoke_all_sessions_route(chat_state.clone());
+    let create_api_key_route = create_api_key_route(pool.clone(), session_store.clone());
+    let list_api_keys_route = list_api_keys_route(pool.clone(), session_store.clone());
+    let revoke_api_key_route = revoke_api_key_route(chat_state.clone());
+    let change_password_route = change_password_route(chat_state.clone(), auth_throttle.clone(), policy.clone());
+    let rename_route = rename_route(chat_state.clone(), policy.clone());
+    let email_status_route = email_status_route(pool.clone(), session_store.clone());
+    let change_email_route = change_email_route(pool.clone(), session_store.clone(), emails.clone(), auth_throttle.clone());
+    let verify_email_page_route = verify_email_page_route();
+    let verify_email_route = verify_email_route(pool.clone());
+    let forgot_password_route = forgot_password_route(pool.clone(), emails, auth_throttle.clone());
+    let reset_password_route = reset_password_route(chat_state.clone(), policy);
+    let oauth_providers_route = oauth_providers_route(oauth.clone());
+    let oauth_start_route = oauth_start_route(oauth.clone());
+    let oauth_callback_route = oauth_callback_route(chat_state.clone(), oauth);
+    let delete_account_route = delete_account_route(chat_state.clone(), auth_throttle.clone());
+
+    let total_route = ws_route.or(login_route).or(register_route).or(register_requirements_route).or(chat_history_route).or(chat_history_page_route).or(dm_history_route).or(me_route).or(logout_route).or(refresh_route)
+        .or(list_connections_route).or(close_connection_route).or(online_route).or(set_role_route)
+        .or(moderate_route).or(lift_sanction_route)
+        .or(list_sessions_route).or(revoke_session_route).or(revoke_all_sessions_route)
+        .or(create_api_key_route).or(list_api_keys_route).or(revoke_api_key_route)
+        .or(change_password_route).or(rename_route).or(delete_account_route)
+        .or(login_totp_route).or(totp_status_route).or(enroll_totp_route).or(totp_code_route).or(disable_totp_route)
+        .or(email_status_route).or(change_email_route).or(verify_email_page_route).or(verify_email_route).or(forgot_password_route).or(reset_password_route)
+        .or(oauth_providers_route).or(oauth_start_route).or(oauth_callback_route);
+
+    // Background task for session cleanup and pruning the in-memory state
     let pool_cleanup = pool.clone();
-    let session_cache_cleanup = session_cache.clone();
+    let session_store_cleanup = session_store.clone();
+    let rate_limiter_cleanup = chat_state.rate_limiter.clone();
+    let auth_throttle_cleanup = auth_throttle.clone();
+    let api_keys_cleanup = chat_state.api_keys.clone();
     tokio::spawn(async move {
         loop {
             // Cleanup expired in DB
             let _ = crate::tables::user_db::cleanup_expired_sessions(&pool_cleanup).await;
+            let _ = crate::tables::email_db::cleanup_expired_email_tokens(&pool_cleanup).await;
             
-            // Re-sync cache from DB
-            if let Ok(sessions) = crate::tables::user_db::get_all_valid_sessions(&pool_cleanup).await {
-                let mut cache = session_cache_cleanup.write().await;
-                cache.clear();
-                for token in sessions {
-                    cache.insert(token);
-                }
-            }
+            // Drop what expired or was deleted elsewhere, sessions still in use stay valid meanwhile
+            let _ = session_store_cleanup.sync(&pool_cleanup).await;
             
+            rate_limiter_cleanup.prune_idle().await;
+            auth_throttle::prune(&auth_throttle_cleanup).await;
+            api_keys_cleanup.prune().await;
+
             tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
         }
     });
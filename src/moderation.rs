@@ -0,0 +1,157 @@
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::cluster::ClusterEvent;
+use crate::connected_users;
+use crate::permissions::{Permission, Role};
+use crate::sessions;
+use crate::tables::sanction_db::{self, SanctionKind};
+use crate::tables::user_db::{self, User};
+use crate::ws_handler::{self, ChatState};
+
+/// Longest timed sanction, anything longer should just be permanent.
+const MAX_SANCTION_SECS: i64 = 10 * 365 * 24 * 60 * 60;
+/// Fits `sanctions.reason`
+const MAX_REASON_CHARS: usize = 255;
+
+/// How long and why, for a mute or ban.
+#[derive(Debug, Default, Deserialize)]
+pub struct SanctionRequest {
+    /// Omitted for a permanent sanction
+    pub duration_secs: Option<i64>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    NotAllowed,
+    /// The target's role is the same as or above the moderator's
+    Outranked,
+    UserNotFound,
+    InvalidDuration,
+    ReasonTooLong,
+    Database,
+}
+
+impl ModerationError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ModerationError::NotAllowed => "You are not allowed to moderate users",
+            ModerationError::Outranked => "You can only moderate users with a lower role than yours",
+            ModerationError::UserNotFound => "User not found",
+            ModerationError::InvalidDuration => "'duration_secs' must be between 1 second and 10 years",
+            ModerationError::ReasonTooLong => "'reason' must be at most 255 characters",
+            ModerationError::Database => "Database is not online, please try again later",
+        }
+    }
+}
+
+/// Look up who a moderator wants to act on, if they may.
+async fn target_user(
+    state: &ChatState,
+    moderator_role: Role,
+    username: &str,
+) -> Result<User, ModerationError> {
+    if !moderator_role.can(Permission::Moderate) {
+        return Err(ModerationError::NotAllowed);
+    }
+    let target = match user_db::find_user_by_username(&state.pool, username).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => return Err(ModerationError::UserNotFound),
+        Err(_) => return Err(ModerationError::Database),
+    };
+    // Also stops moderators from sanctioning themselves
+    if target.role >= moderator_role {
+        return Err(ModerationError::Outranked);
+    }
+    Ok(target)
+}
+
+/// Close every socket of a user, on every instance.
+pub async fn disconnect(state: &ChatState, user_id: i32) {
+    state.cluster.publish(ClusterEvent::Kick { user_id });
+    if connected_users::close_all_connections(&state.connected, user_id).await > 0 {
+        ws_handler::went_offline(&state.connected, &state.cluster, &state.presence, &state.pool, user_id, None).await;
+    }
+}
+
+/// Disconnect `username` everywhere. They can come straight back.
+pub async fn kick(state: &ChatState, moderator_role: Role, username: &str) -> Result<(), ModerationError> {
+    let target = target_user(state, moderator_role, username).await?;
+    disconnect(state, target.id).await;
+    println!("User {} was kicked", target.username);
+    Ok(())
+}
+
+/// Mute or ban `username`. A ban also ends every session they have.
+pub async fn sanction(
+    state: &ChatState,
+    moderator_id: i32,
+    moderator_role: Role,
+    username: &str,
+    kind: SanctionKind,
+    request: &SanctionRequest,
+) -> Result<(), ModerationError> {
+    let expires_at = match request.duration_secs {
+        Some(secs) if (1..=MAX_SANCTION_SECS).contains(&secs) => Some(Utc::now() + chrono::Duration::seconds(secs)),
+        Some(_) => return Err(ModerationError::InvalidDuration),
+        None => None,
+    };
+    let reason = request.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    if reason.is_some_and(|r| r.chars().count() > MAX_REASON_CHARS) {
+        return Err(ModerationError::ReasonTooLong);
+    }
+    let target = target_user(state, moderator_role, username).await?;
+
+    if sanction_db::add_sanction(&state.pool, target.id, kind, reason, Some(moderator_id), expires_at)
+        .await
+        .is_err()
+    {
+        return Err(ModerationError::Database);
+    }
+
+    sessions::standing_changed(state, target.id).await;
+    if kind == SanctionKind::Ban {
+        let tokens = match user_db::delete_user_sessions(&state.pool, target.id).await {
+            Ok(tokens) => tokens,
+            Err(_) => return Err(ModerationError::Database),
+        };
+        sessions::revoke(state, target.id, &tokens).await;
+        // Sockets of sessions that are already gone too
+        disconnect(state, target.id).await;
+    }
+    println!("User {} got a {:?} from user {}", target.username, kind, moderator_id);
+    Ok(())
+}
+
+/// Mute a user who keeps hitting rate limits, for `secs`.
+pub async fn auto_mute(state: &ChatState, user_id: i32, secs: i64) {
+    let expires_at = Utc::now() + chrono::Duration::seconds(secs);
+    let added = sanction_db::add_sanction(&state.pool, user_id, SanctionKind::Mute, Some("Flooding"), None, Some(expires_at)).await;
+    match added {
+        Ok(_) => {
+            sessions::standing_changed(state, user_id).await;
+            println!("User {} was auto-muted for {}s", user_id, secs);
+        }
+        Err(_) => println!("Failed to auto-mute user {}", user_id),
+    }
+}
+
+/// Lift the mutes or bans on `username`. Returns false if there were none.
+pub async fn lift(
+    state: &ChatState,
+    moderator_role: Role,
+    username: &str,
+    kind: SanctionKind,
+) -> Result<bool, ModerationError> {
+    let target = target_user(state, moderator_role, username).await?;
+    match sanction_db::lift_sanctions(&state.pool, target.id, kind).await {
+        Ok(lifted) => {
+            if kind == SanctionKind::Mute {
+                sessions::standing_changed(state, target.id).await;
+            }
+            Ok(lifted > 0)
+        }
+        Err(_) => Err(ModerationError::Database),
+    }
+}
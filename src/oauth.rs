@@ -0,0 +1,526 @@
+use data_encoding::BASE64URL_NOPAD;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::secrets::get_secret;
+use crate::tables::oauth_db;
+use crate::tables::user_db::{self, User};
+use crate::totp::{self, Totp};
+use crate::validation::Policy;
+
+/// Cookie holding the provider, state and PKCE verifier of a sign-in in progress.
+pub const FLOW_COOKIE: &str = "oauth_flow";
+/// How long the user has to sign in at the provider.
+const FLOW_TTL_SECS: i64 = 600;
+/// Random suffixes tried for the username of a new account before giving up.
+const USERNAME_ATTEMPTS: usize = 5;
+
+/// What the provider sends the browser back to `/callback` with.
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Instead of `code` when the user said no or the provider failed
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OAuthError {
+    UnknownProvider,
+    /// Not the sign-in this browser started, or it took too long
+    InvalidState,
+    /// The provider refused, with its reason
+    Denied(String),
+    /// The provider gave no usable answer
+    Provider,
+    AlreadyLinked,
+    /// No free username for a new account
+    NoUsername,
+    /// Not linked to anyone, and the provider may not create accounts
+    SignupDisabled,
+    Database,
+}
+
+impl OAuthError {
+    pub fn message(&self) -> &str {
+        match self {
+            OAuthError::UnknownProvider => "Unknown sign-in provider",
+            OAuthError::InvalidState => "The sign-in expired or was started elsewhere, please try again",
+            OAuthError::Denied(reason) => reason,
+            OAuthError::Provider => "The sign-in provider didn't answer properly, please try again later",
+            OAuthError::AlreadyLinked => "That account, or another one at the same provider, is already linked to a different user",
+            OAuthError::NoUsername => "No free username for your account, please register instead",
+            OAuthError::SignupDisabled => "No account is linked to that sign-in, please log in and link it first",
+            OAuthError::Database => "Database is not online, please try again later",
+        }
+    }
+}
+
+/// Who the provider says signed in.
+#[derive(Debug)]
+pub struct ProviderIdentity {
+    /// The provider's stable id for the account
+    pub subject: String,
+    /// What new accounts are named after
+    pub username: Option<String>,
+    /// Only an address the provider says is verified
+    pub email: Option<String>,
+}
+
+/// Endpoints and client registration at one provider.
+struct Provider {
+    client_id: String,
+    /// `None` for public clients, PKCE alone protects the code then
+    client_secret: Option<String>,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    scopes: String,
+    username_claim: String,
+    /// Sign in to the one user with the same verified address rather than creating an account
+    link_by_email: bool,
+    /// Create an account for an identity nobody has linked, which skips the invite code or proof of work
+    allow_signup: bool,
+}
+
+/// The part of an OpenID Connect discovery document used here.
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Sign-in through outside OpenID Connect / OAuth2 providers, with the authorization code flow and PKCE.
+#[derive(Clone)]
+pub struct OAuth {
+    providers: Arc<HashMap<String, Provider>>,
+    http: reqwest::Client,
+    /// Without a trailing slash
+    public_url: Arc<String>,
+    /// For the usernames of new accounts
+    policy: Policy,
+    /// Users with 2FA still enter a code after signing in at the provider
+    totp: Totp,
+}
+
+impl OAuth {
+    /// `OAUTH_PROVIDERS` lists the provider names, comma separated, none by default. For each `<NAME>`, upper cased:
+    /// - `OAUTH_<NAME>_ISSUER` → an OpenID Connect issuer, its endpoints come from the discovery document. Without
+    ///   it, `OAUTH_<NAME>_AUTHORIZATION_URL`, `OAUTH_<NAME>_TOKEN_URL` and `OAUTH_<NAME>_USERINFO_URL` are required
+    /// - `OAUTH_<NAME>_CLIENT_ID`, and `OAUTH_<NAME>_CLIENT_SECRET_NAME` naming the secret of a confidential client
+    /// - `OAUTH_<NAME>_SCOPES` → default `openid profile email`
+    /// - `OAUTH_<NAME>_USERNAME_CLAIM` → what new accounts are named after, default `preferred_username`
+    /// - `OAUTH_<NAME>_LINK_BY_EMAIL` → `true` to sign in to the user with the same verified address
+    /// - `OAUTH_<NAME>_ALLOW_SIGNUP` → `true` to create accounts for identities nobody has linked
+    ///
+    /// The redirect URI to register at the provider is `<PUBLIC_URL>/api/oauth/<name>/callback`.
+    /// A provider that can't be set up, say its issuer is down, is left out rather than keeping the server from starting.
+    pub async fn from_env(policy: Policy, totp: Totp) -> Result<Self, Box<dyn std::error::Error>> {
+        let http = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+        let mut providers = HashMap::new();
+        let names = env::var("OAUTH_PROVIDERS").unwrap_or_default();
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let name = name.to_lowercase();
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                println!("Ignoring invalid sign-in provider name '{}'", name);
+                continue;
+            }
+            match load_provider(&http, &name).await {
+                Ok(provider) => {
+                    println!("Sign-in with {} enabled", name);
+                    providers.insert(name, provider);
+                }
+                Err(e) => println!("Sign-in with {} disabled: {}", name, e),
+            }
+        }
+        let public_url = env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+        Ok(OAuth {
+            providers: Arc::new(providers),
+            http,
+            public_url: Arc::new(public_url.trim_end_matches('/').to_string()),
+            policy,
+            totp,
+        })
+    }
+
+    /// Configured provider names, sorted.
+    pub fn providers(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Where to send the browser to sign in with `name`, and the cookie keeping the flow's secrets meanwhile.
+    pub fn start(&self, name: &str) -> Result<(String, String), OAuthError> {
+        let provider = self.providers.get(name).ok_or(OAuthError::UnknownProvider)?;
+        let state = random_token();
+        let verifier = random_token();
+        let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()));
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri(name).as_str()),
+                ("scope", provider.scopes.as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|_| OAuthError::Provider)?;
+        let cookie = format!(
+            "{}={}.{}.{}; Path=/api/oauth; HttpOnly; SameSite=Lax; Max-Age={}",
+            FLOW_COOKIE, name, state, verifier, FLOW_TTL_SECS
+        );
+        Ok((url.to_string(), cookie))
+    }
+
+    /// Who signed in, once the provider's answer matches the flow cookie `start` set.
+    pub async fn finish(
+        &self,
+        name: &str,
+        query: &CallbackQuery,
+        flow: Option<&str>,
+    ) -> Result<ProviderIdentity, OAuthError> {
+        let provider = self.providers.get(name).ok_or(OAuthError::UnknownProvider)?;
+        if let Some(error) = &query.error {
+            let reason = query.error_description.clone().unwrap_or_else(|| format!("Sign-in refused: {}", error));
+            return Err(OAuthError::Denied(reason));
+        }
+        // Only this browser has the cookie, so a matching state means it started this sign-in
+        let mut parts = flow.ok_or(OAuthError::InvalidState)?.splitn(3, '.');
+        let (Some(flow_name), Some(state), Some(verifier)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(OAuthError::InvalidState);
+        };
+        let (Some(code), Some(returned_state)) = (&query.code, &query.state) else {
+            return Err(OAuthError::InvalidState);
+        };
+        if flow_name != name || state != returned_state {
+            return Err(OAuthError::InvalidState);
+        }
+
+        let redirect_uri = self.redirect_uri(name);
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", verifier),
+        ];
+        if let Some(secret) = &provider.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let token: TokenResponse = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|_| OAuthError::Provider)?
+            .json()
+            .await
+            .map_err(|_| OAuthError::Provider)?;
+        let claims: Value = self
+            .http
+            .get(&provider.userinfo_endpoint)
+            .bearer_auth(&token.access_token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|_| OAuthError::Provider)?
+            .json()
+            .await
+            .map_err(|_| OAuthError::Provider)?;
+        identity_from_claims(&claims, &provider.username_claim).ok_or(OAuthError::Provider)
+    }
+
+    /// The user `identity` signs in as: the one it's linked to, else `current` (whoever is signed in here
+    /// already), the one user with the same verified address if the provider allows it, or a new account
+    /// if the provider may create them.
+    /// The identity gets linked to whichever it is.
+    pub async fn sign_in(
+        &self,
+        pool: &sqlx::MySqlPool,
+        name: &str,
+        identity: &ProviderIdentity,
+        current: Option<User>,
+    ) -> Result<User, OAuthError> {
+        let provider = self.providers.get(name).ok_or(OAuthError::UnknownProvider)?;
+        let linked = oauth_db::find_linked_user(pool, name, &identity.subject)
+            .await
+            .map_err(|_| OAuthError::Database)?;
+        if let Some(user_id) = linked {
+            if current.as_ref().is_some_and(|user| user.id != user_id) {
+                return Err(OAuthError::AlreadyLinked);
+            }
+            let _ = oauth_db::touch_identity(pool, name, &identity.subject).await;
+            return user_db::get_user_by_id(pool, user_id)
+                .await
+                .map_err(|_| OAuthError::Database);
+        }
+
+        let user = match (current, &identity.email) {
+            (Some(user), _) => user,
+            (None, Some(email)) if provider.link_by_email => {
+                let users = oauth_db::find_users_by_verified_email(pool, email)
+                    .await
+                    .map_err(|_| OAuthError::Database)?;
+                match users.as_slice() {
+                    [user_id] => user_db::get_user_by_id(pool, *user_id)
+                        .await
+                        .map_err(|_| OAuthError::Database)?,
+                    _ => return self.create_user(pool, name, provider, identity).await,
+                }
+            }
+            _ => return self.create_user(pool, name, provider, identity).await,
+        };
+        match oauth_db::link_identity(pool, name, &identity.subject, user.id).await {
+            Ok(()) => {}
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(OAuthError::AlreadyLinked),
+            Err(_) => return Err(OAuthError::Database),
+        }
+        println!("Linked {} account {} to user {}", name, identity.subject, user.username);
+        Ok(user)
+    }
+
+    /// The challenge to exchange at `/api/login/totp` if the user has 2FA on.
+    pub async fn second_factor(&self, pool: &sqlx::MySqlPool, user_id: i32) -> Result<Option<String>, OAuthError> {
+        match totp::is_enabled(pool, user_id).await {
+            Ok(true) => Ok(Some(self.totp.issue_challenge(user_id).challenge_token)),
+            Ok(false) => Ok(None),
+            Err(_) => Err(OAuthError::Database),
+        }
+    }
+
+    /// The web page, with `params` for its script.
+    pub fn page_url(&self, params: &[(&str, &str)]) -> String {
+        let page = format!("{}/", self.public_url);
+        match reqwest::Url::parse_with_params(&page, params) {
+            Ok(url) => url.to_string(),
+            Err(_) => page,
+        }
+    }
+
+    fn redirect_uri(&self, name: &str) -> String {
+        format!("{}/api/oauth/{}/callback", self.public_url, name)
+    }
+
+    /// A new account named after `identity` with the identity linked, and a random password. Its owner
+    /// signs in through the provider, or resets the password once the account has a verified address.
+    async fn create_user(
+        &self,
+        pool: &sqlx::MySqlPool,
+        name: &str,
+        provider: &Provider,
+        identity: &ProviderIdentity,
+    ) -> Result<User, OAuthError> {
+        if !provider.allow_signup {
+            return Err(OAuthError::SignupDisabled);
+        }
+        let password = random_token();
+        let max_chars = self.policy.username_max_chars();
+        for username in username_candidates(identity, max_chars) {
+            if self.policy.check_username(&username).is_err() {
+                continue;
+            }
+            // The provider vouches for the address
+            let created =
+                oauth_db::create_linked_user(pool, name, &identity.subject, &username, &password, identity.email.as_deref());
+            let user_id = match created.await {
+                Ok(Some(user_id)) => user_id,
+                // Taken, or a lookalike of a taken name
+                Ok(None) => continue,
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(OAuthError::AlreadyLinked),
+                Err(_) => return Err(OAuthError::Database),
+            };
+            println!("Created user {} for {} account {}", username, name, identity.subject);
+            return user_db::get_user_by_id(pool, user_id)
+                .await
+                .map_err(|_| OAuthError::Database);
+        }
+        Err(OAuthError::NoUsername)
+    }
+}
+
+/// The name the provider has for the user cut down to the allowed characters, then with numbers
+/// and random suffixes added. `max_chars` is the longest username allowed.
+fn username_candidates(identity: &ProviderIdentity, max_chars: usize) -> Vec<String> {
+    let hint = identity
+        .username
+        .as_deref()
+        .or_else(|| identity.email.as_deref().and_then(|email| email.split('@').next()))
+        .unwrap_or("user");
+    let cleaned: String = hint
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    // Room for a suffix
+    let max_chars = max_chars.saturating_sub(5).max(1);
+    let mut base: String = cleaned
+        .trim_start_matches(|c: char| !c.is_ascii_alphanumeric())
+        .chars()
+        .take(max_chars)
+        .collect();
+    if base.is_empty() {
+        base = "user".to_string();
+    }
+
+    let mut candidates = vec![base.clone()];
+    candidates.extend((2..=5).map(|n| format!("{}-{}", base, n)));
+    candidates.extend(
+        (0..USERNAME_ATTEMPTS).map(|_| format!("{}-{}", base, &uuid::Uuid::new_v4().simple().to_string()[..4])),
+    );
+    candidates
+}
+
+/// The cookie value that ends a sign-in flow.
+pub fn clear_flow_cookie() -> String {
+    format!("{}=; Path=/api/oauth; HttpOnly; SameSite=Lax; Max-Age=0", FLOW_COOKIE)
+}
+
+async fn load_provider(http: &reqwest::Client, name: &str) -> Result<Provider, Box<dyn std::error::Error>> {
+    let prefix = format!("OAUTH_{}_", name.to_uppercase());
+    let var = |key: &str| env::var(format!("{}{}", prefix, key));
+    let required = |key: &str| var(key).map_err(|_| format!("{}{} is not set", prefix, key));
+
+    let endpoints = match var("ISSUER") {
+        Ok(issuer) => {
+            let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+            http.get(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Discovery>()
+                .await?
+        }
+        Err(_) => Discovery {
+            authorization_endpoint: required("AUTHORIZATION_URL")?,
+            token_endpoint: required("TOKEN_URL")?,
+            userinfo_endpoint: required("USERINFO_URL")?,
+        },
+    };
+    Ok(Provider {
+        client_id: required("CLIENT_ID")?,
+        client_secret: var("CLIENT_SECRET_NAME").ok().map(|secret| get_secret(&secret)),
+        authorization_endpoint: endpoints.authorization_endpoint,
+        token_endpoint: endpoints.token_endpoint,
+        userinfo_endpoint: endpoints.userinfo_endpoint,
+        scopes: var("SCOPES").unwrap_or_else(|_| "openid profile email".to_string()),
+        username_claim: var("USERNAME_CLAIM").unwrap_or_else(|_| "preferred_username".to_string()),
+        link_by_email: var("LINK_BY_EMAIL").is_ok_and(|value| value.eq_ignore_ascii_case("true")),
+        allow_signup: var("ALLOW_SIGNUP").is_ok_and(|value| value.eq_ignore_ascii_case("true")),
+    })
+}
+
+fn identity_from_claims(claims: &Value, username_claim: &str) -> Option<ProviderIdentity> {
+    // OpenID Connect has `sub`, plain OAuth2 APIs like GitHub's a numeric `id`
+    let subject = match claims.get("sub").or_else(|| claims.get("id"))? {
+        Value::String(subject) if !subject.is_empty() => subject.clone(),
+        Value::Number(subject) => subject.to_string(),
+        _ => return None,
+    };
+    let text = |key: &str| claims.get(key).and_then(Value::as_str).map(str::to_string);
+    let verified = claims.get("email_verified").and_then(Value::as_bool) == Some(true);
+    Some(ProviderIdentity {
+        subject,
+        username: text(username_claim).or_else(|| text("name")),
+        email: text("email").filter(|_| verified),
+    })
+}
+
+/// Two UUIDs worth of randomness, base64url.
+fn random_token() -> String {
+    let mut bytes = uuid::Uuid::new_v4().as_bytes().to_vec();
+    bytes.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn identity(username: Option<&str>, email: Option<&str>) -> ProviderIdentity {
+        ProviderIdentity {
+            subject: "1".to_string(),
+            username: username.map(str::to_string),
+            email: email.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn claims_of_an_openid_provider() {
+        let claims = json!({
+            "sub": "abc",
+            "preferred_username": "alice",
+            "name": "Alice A.",
+            "email": "alice@example.com",
+            "email_verified": true,
+        });
+        let identity = identity_from_claims(&claims, "preferred_username").unwrap();
+        assert_eq!(identity.subject, "abc");
+        assert_eq!(identity.username.as_deref(), Some("alice"));
+        assert_eq!(identity.email.as_deref(), Some("alice@example.com"));
+    }
+
+    #[test]
+    fn claims_of_a_github_like_api() {
+        let claims = json!({ "id": 583231, "login": "octocat", "email": "octo@example.com" });
+        let identity = identity_from_claims(&claims, "login").unwrap();
+        assert_eq!(identity.subject, "583231");
+        assert_eq!(identity.username.as_deref(), Some("octocat"));
+        // Not said to be verified
+        assert_eq!(identity.email, None);
+    }
+
+    #[test]
+    fn claims_fall_back_to_the_name() {
+        let claims = json!({ "sub": "abc", "name": "Alice" });
+        let identity = identity_from_claims(&claims, "preferred_username").unwrap();
+        assert_eq!(identity.username.as_deref(), Some("Alice"));
+    }
+
+    #[test]
+    fn claims_without_a_subject_are_refused() {
+        assert!(identity_from_claims(&json!({ "name": "Alice" }), "preferred_username").is_none());
+        assert!(identity_from_claims(&json!({ "sub": "" }), "preferred_username").is_none());
+        assert!(identity_from_claims(&json!({ "sub": true }), "preferred_username").is_none());
+    }
+
+    #[test]
+    fn candidates_start_with_the_cleaned_username() {
+        let candidates = username_candidates(&identity(Some("Alice Smith!"), None), 32);
+        assert_eq!(candidates[..3], ["alice_smith_", "alice_smith_-2", "alice_smith_-3"]);
+        assert_eq!(candidates.len(), 5 + USERNAME_ATTEMPTS);
+        assert!(candidates[5..].iter().all(|c| c.starts_with("alice_smith_-") && c.len() == 17));
+    }
+
+    #[test]
+    fn candidates_fall_back_to_the_email_then_user() {
+        assert_eq!(username_candidates(&identity(None, Some("bob@example.com")), 32)[0], "bob");
+        assert_eq!(username_candidates(&identity(None, None), 32)[0], "user");
+        // Nothing usable left once cleaned
+        assert_eq!(username_candidates(&identity(Some("__"), None), 32)[0], "user");
+    }
+
+    #[test]
+    fn candidates_leave_room_for_a_suffix() {
+        let candidates = username_candidates(&identity(Some(&"a".repeat(40)), None), 20);
+        assert_eq!(candidates[0], "a".repeat(15));
+        assert!(candidates.iter().all(|c| c.chars().count() <= 20));
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// What a user is, stored in `app_users.role`. Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Something beyond what every user can do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Delete someone else's broadcast messages
+    DeleteAnyMessage,
+    /// Mute, kick and ban users with a lower role
+    Moderate,
+    /// Change other users' roles
+    ManageRoles,
+}
+
+impl Permission {
+    /// The least role that has this permission.
+    fn min_role(self) -> Role {
+        match self {
+            Permission::DeleteAnyMessage | Permission::Moderate => Role::Moderator,
+            Permission::ManageRoles => Role::Admin,
+        }
+    }
+}
+
+impl Role {
+    pub fn can(self, permission: Permission) -> bool {
+        self >= permission.min_role()
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::cluster::Cluster;
+use crate::connected_users::{self, ConnectedUsers};
+
+/// What other users see about someone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Away,
+    Dnd,
+    Offline,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::Dnd => "dnd",
+            Status::Offline => "offline",
+        }
+    }
+
+    /// Statuses a client may pick, offline is only ever derived from having no connections.
+    pub fn parse_settable(status: &str) -> Option<Status> {
+        match status {
+            "online" => Some(Status::Online),
+            "away" => Some(Status::Away),
+            "dnd" => Some(Status::Dnd),
+            _ => None,
+        }
+    }
+}
+
+/// Statuses users set explicitly. A connected user without an entry is `Online`,
+/// one without connections is `Offline` whatever they set.
+pub type Presence = Arc<RwLock<HashMap<i32, Status>>>;
+
+/// Create an empty presence registry.
+pub fn new_registry() -> Presence {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+/// Status of a user who is connected.
+pub async fn status_of(presence: &Presence, user_id: i32) -> Status {
+    let map = presence.read().await;
+    map.get(&user_id).copied().unwrap_or(Status::Online)
+}
+
+pub async fn set_status(presence: &Presence, user_id: i32, status: Status) {
+    let mut map = presence.write().await;
+    if status == Status::Online {
+        map.remove(&user_id);
+    } else {
+        map.insert(user_id, status);
+    }
+}
+
+/// Forget an explicit status, once the user's last connection is gone.
+pub async fn clear(presence: &Presence, user_id: i32) {
+    let mut map = presence.write().await;
+    map.remove(&user_id);
+}
+
+/// Usernames of everyone connected to any instance, sorted.
+pub async fn online_usernames(
+    pool: &sqlx::MySqlPool,
+    connected: &ConnectedUsers,
+    cluster: &Cluster,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut user_ids = connected_users::get_online_user_ids(connected).await;
+    user_ids.extend(cluster.online_elsewhere().await);
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    crate::tables::user_db::get_usernames_by_ids(pool, &user_ids).await
+}
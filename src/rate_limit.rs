@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::ws_types::MessageType;
+
+/// Longest `content` accepted by default, in characters. Overridden by `MAX_CONTENT_LENGTH`.
+const DEFAULT_MAX_CONTENT_LENGTH: usize = 2000;
+/// A user's buckets hold this many times what a single connection's do, for several tabs.
+const DEFAULT_USER_MULTIPLIER: u32 = 3;
+/// Rate limit hits within this window count towards an auto-mute.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// Default auto-mute length, overridden by `AUTO_MUTE_SECS`.
+const DEFAULT_AUTO_MUTE_SECS: i64 = 300;
+
+/// `count` frames every `per` on average, in bursts of up to `count`.
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    count: u32,
+    per: Duration,
+}
+
+impl Rule {
+    const fn new(count: u32, per_secs: u64) -> Self {
+        Rule { count, per: Duration::from_secs(per_secs) }
+    }
+
+    /// `"<count>/<seconds>"`, e.g. `5/10`.
+    pub fn parse(value: &str) -> Option<Rule> {
+        let (count, per_secs) = value.trim().split_once('/')?;
+        let count = count.trim().parse().ok().filter(|c| *c > 0)?;
+        let per_secs = per_secs.trim().parse().ok().filter(|s| *s > 0)?;
+        Some(Rule::new(count, per_secs))
+    }
+
+    fn times(self, multiplier: u32) -> Rule {
+        Rule { count: self.count.saturating_mul(multiplier), per: self.per }
+    }
+
+    fn bucket(self, now: Instant) -> TokenBucket {
+        TokenBucket { rule: self, tokens: self.count as f64, updated: now }
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.count, self.per.as_secs())
+    }
+}
+
+/// Per connection limit of each message type when nothing is configured.
+fn default_rule(msg_type: MessageType) -> Rule {
+    match msg_type {
+        MessageType::Broadcast | MessageType::Private => Rule::new(5, 5),
+        MessageType::Edit | MessageType::Delete => Rule::new(5, 10),
+        MessageType::Ephemeral => Rule::new(20, 5),
+        MessageType::Reaction => Rule::new(10, 5),
+        // Typing is also throttled to one frame per 2s, this only catches clients ignoring that
+        MessageType::Typing => Rule::new(10, 10),
+        MessageType::Presence | MessageType::Who => Rule::new(5, 10),
+        MessageType::Join | MessageType::Leave => Rule::new(10, 30),
+        MessageType::Mute | MessageType::Kick | MessageType::Ban => Rule::new(10, 60),
+    }
+}
+
+/// Classic token bucket: refills continuously, each frame takes one token.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rule: Rule,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let rate = self.rule.count as f64 / self.rule.per.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(self.rule.count as f64);
+        self.updated = now;
+    }
+
+    /// How long until a token is available, `None` if one is right now.
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        let rate = self.rule.count as f64 / self.rule.per.as_secs_f64();
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rule.count as f64
+    }
+}
+
+/// A connection's own buckets, kept by its receive loop.
+pub type ConnBuckets = HashMap<MessageType, TokenBucket>;
+
+/// Limits read from the environment, see `RateLimiter::from_env`.
+struct RateLimitConfig {
+    conn: HashMap<MessageType, Rule>,
+    user: HashMap<MessageType, Rule>,
+    max_content_length: usize,
+    /// Rate limit hits within `STRIKE_WINDOW` that get a user muted, `None` when disabled
+    auto_mute_strikes: Option<u32>,
+    auto_mute_secs: i64,
+}
+
+/// Recent rate limit hits of a user.
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+/// Flood control shared by every socket: the config plus each user's buckets on this instance.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    users: Arc<Mutex<HashMap<(i32, MessageType), TokenBucket>>>,
+    /// One bucket per API key, for everything done with it
+    api_keys: Arc<Mutex<HashMap<i64, TokenBucket>>>,
+    strikes: Arc<Mutex<HashMap<i32, Strikes>>>,
+}
+
+impl RateLimiter {
+    /// Every setting is optional:
+    /// - `RATE_LIMIT_CONN_<TYPE>` / `RATE_LIMIT_USER_<TYPE>` as `<count>/<seconds>`, e.g. `RATE_LIMIT_CONN_BROADCAST=5/5`
+    /// - `MAX_CONTENT_LENGTH` in characters
+    /// - `AUTO_MUTE_STRIKES` rate limit hits within a minute that mute the user for `AUTO_MUTE_SECS`
+    pub fn from_env() -> Self {
+        let mut conn = HashMap::new();
+        let mut user = HashMap::new();
+        for msg_type in MessageType::ALL {
+            let name = msg_type.as_str().to_uppercase();
+            let conn_rule = rule_from_env(&format!("RATE_LIMIT_CONN_{}", name))
+                .unwrap_or_else(|| default_rule(msg_type));
+            let user_rule = rule_from_env(&format!("RATE_LIMIT_USER_{}", name))
+                .unwrap_or_else(|| conn_rule.times(DEFAULT_USER_MULTIPLIER));
+            conn.insert(msg_type, conn_rule);
+            user.insert(msg_type, user_rule);
+        }
+
+        let config = RateLimitConfig {
+            conn,
+            user,
+            max_content_length: number_from_env("MAX_CONTENT_LENGTH").unwrap_or(DEFAULT_MAX_CONTENT_LENGTH),
+            auto_mute_strikes: number_from_env("AUTO_MUTE_STRIKES").filter(|s| *s > 0),
+            auto_mute_secs: number_from_env("AUTO_MUTE_SECS").unwrap_or(DEFAULT_AUTO_MUTE_SECS),
+        };
+        RateLimiter {
+            config: Arc::new(config),
+            users: Arc::new(Mutex::new(HashMap::new())),
+            api_keys: Arc::new(Mutex::new(HashMap::new())),
+            strikes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn max_content_length(&self) -> usize {
+        self.config.max_content_length
+    }
+
+    /// Take a token for `msg_type` from the connection's bucket. Checked before the frame is
+    /// authenticated, so a flood never reaches the session lookup.
+    /// Returns how long to wait instead if it's empty.
+    pub fn check_conn(&self, conn: &mut ConnBuckets, msg_type: MessageType) -> Result<(), Duration> {
+        let now = Instant::now();
+        let bucket = conn
+            .entry(msg_type)
+            .or_insert_with(|| self.config.conn[&msg_type].bucket(now));
+        match bucket.wait_time(now) {
+            None => {
+                bucket.take();
+                Ok(())
+            }
+            Some(wait) => Err(wait),
+        }
+    }
+
+    /// Take a token for `msg_type` from the user's bucket, shared by all their connections.
+    /// Returns how long to wait instead if it's empty.
+    pub async fn check_user(&self, user_id: i32, msg_type: MessageType) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut users = self.users.lock().await;
+        let bucket = users
+            .entry((user_id, msg_type))
+            .or_insert_with(|| self.config.user[&msg_type].bucket(now));
+        match bucket.wait_time(now) {
+            None => {
+                bucket.take();
+                Ok(())
+            }
+            Some(wait) => Err(wait),
+        }
+    }
+
+    /// Take a token from API key `key_id`'s own bucket, which holds `rule`.
+    /// Returns how long to wait instead if it's empty.
+    pub async fn check_api_key(&self, key_id: i64, rule: Rule) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut api_keys = self.api_keys.lock().await;
+        let bucket = api_keys.entry(key_id).or_insert_with(|| rule.bucket(now));
+        match bucket.wait_time(now) {
+            None => {
+                bucket.take();
+                Ok(())
+            }
+            Some(wait) => Err(wait),
+        }
+    }
+
+    /// Count a rate limit hit. Returns how long to auto-mute the user for when
+    /// that was one too many, auto-mute being on.
+    pub async fn strike(&self, user_id: i32) -> Option<i64> {
+        let threshold = self.config.auto_mute_strikes?;
+        let now = Instant::now();
+        let mut strikes = self.strikes.lock().await;
+        let entry = strikes.entry(user_id).or_insert(Strikes { count: 0, since: now });
+        if now.duration_since(entry.since) > STRIKE_WINDOW {
+            *entry = Strikes { count: 0, since: now };
+        }
+        entry.count += 1;
+        if entry.count < threshold {
+            return None;
+        }
+        strikes.remove(&user_id);
+        Some(self.config.auto_mute_secs)
+    }
+
+    /// Forget users' buckets that are full again, they'd start out the same.
+    pub async fn prune_idle(&self) {
+        let now = Instant::now();
+        self.users.lock().await.retain(|_, bucket| !bucket.is_full(now));
+        self.api_keys.lock().await.retain(|_, bucket| !bucket.is_full(now));
+        self.strikes
+            .lock()
+            .await
+            .retain(|_, strikes| now.duration_since(strikes.since) <= STRIKE_WINDOW);
+    }
+}
+
+fn rule_from_env(key: &str) -> Option<Rule> {
+    let value = env::var(key).ok()?;
+    let rule = Rule::parse(&value);
+    if rule.is_none() {
+        println!("Ignoring {}={:?}, expected <count>/<seconds>", key, value);
+    }
+    rule
+}
+
+fn number_from_env<T: std::str::FromStr>(key: &str) -> Option<T> {
+    let value = env::var(key).ok()?;
+    let number = value.trim().parse().ok();
+    if number.is_none() {
+        println!("Ignoring {}={:?}, expected a number", key, value);
+    }
+    number
+}
@@ -0,0 +1,253 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::db::secrets::get_secret;
+
+/// How long a proof-of-work challenge can be solved and redeemed for.
+const CHALLENGE_TTL_SECS: i64 = 10 * 60;
+/// More than that and nobody registers before the challenge expires.
+const MAX_POW_DIFFICULTY: u32 = 32;
+
+/// What `/api/register` asks for on top of a username and password.
+#[derive(Debug, Serialize)]
+pub struct RegisterRequirements {
+    pub invite_code_required: bool,
+    /// Find a `nonce` so that SHA-256 of `"<pow_challenge>:<nonce>"` starts with
+    /// `pow_difficulty` zero bits. Absent when no proof of work is required
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pow_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pow_difficulty: Option<u32>,
+}
+
+/// Optional hurdles for scripted signups, configured from the environment.
+#[derive(Clone)]
+pub struct RegisterGate {
+    invite_codes: Arc<Vec<String>>,
+    pow_difficulty: Option<u32>,
+    /// Signs challenges so any instance can check them without remembering them
+    pow_key: Arc<Vec<u8>>,
+    /// Challenges already redeemed here, with their expiry (unix seconds)
+    redeemed: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl RegisterGate {
+    /// - `REGISTER_INVITE_CODES_NAME` names a secret holding comma separated invite codes, one of which is then required.
+    ///   Codes are shared and can be used any number of times, remove one from the secret to retire it
+    /// - `REGISTER_POW_DIFFICULTY` turns on proof of work, in leading zero bits (around 20 takes a browser a few seconds)
+    /// - `REGISTER_POW_KEY_NAME` names a secret to sign challenges with. Needed with several instances, otherwise
+    ///   each instance makes up its own key and only accepts its own challenges
+    pub fn from_env() -> Self {
+        let invite_codes = match env::var("REGISTER_INVITE_CODES_NAME") {
+            Ok(name) => get_secret(&name)
+                .split(',')
+                .map(|code| code.trim().to_string())
+                .filter(|code| !code.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let pow_difficulty = env::var("REGISTER_POW_DIFFICULTY")
+            .ok()
+            .and_then(|d| d.trim().parse::<u32>().ok())
+            .filter(|d| *d > 0)
+            .map(|d| d.min(MAX_POW_DIFFICULTY));
+        let pow_key = match env::var("REGISTER_POW_KEY_NAME") {
+            Ok(name) => get_secret(&name).into_bytes(),
+            Err(_) => uuid::Uuid::new_v4().as_bytes().to_vec(),
+        };
+
+        RegisterGate {
+            invite_codes: Arc::new(invite_codes),
+            pow_difficulty,
+            pow_key: Arc::new(pow_key),
+            redeemed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// What a client has to send, with a fresh challenge if proof of work is on.
+    pub fn requirements(&self) -> RegisterRequirements {
+        RegisterRequirements {
+            invite_code_required: !self.invite_codes.is_empty(),
+            pow_challenge: self.pow_difficulty.map(|_| self.new_challenge()),
+            pow_difficulty: self.pow_difficulty,
+        }
+    }
+
+    /// Check what the client sent against the configured hurdles.
+    pub async fn verify(
+        &self,
+        invite_code: Option<&str>,
+        pow_challenge: Option<&str>,
+        pow_nonce: Option<&str>,
+    ) -> Result<(), &'static str> {
+        if !self.invite_codes.is_empty() {
+            match invite_code {
+                Some(code) if self.is_invite_code(code) => {}
+                Some(_) => return Err("Invalid invite code"),
+                None => return Err("An invite code is required to register"),
+            }
+        }
+
+        let Some(difficulty) = self.pow_difficulty else {
+            return Ok(());
+        };
+        let (Some(challenge), Some(nonce)) = (pow_challenge, pow_nonce) else {
+            return Err("Registering requires solving a proof of work, see /api/register/requirements");
+        };
+        let expires_at = self.check_signature(challenge).ok_or("Invalid proof of work challenge")?;
+        let now = chrono::Utc::now().timestamp();
+        if expires_at < now {
+            return Err("Proof of work challenge expired, get a new one");
+        }
+        let hash = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+        if leading_zero_bits(&hash) < difficulty {
+            return Err("Proof of work is not solved");
+        }
+
+        let mut redeemed = self.redeemed.lock().await;
+        redeemed.retain(|_, expiry| *expiry >= now);
+        if redeemed.insert(challenge.to_string(), expires_at).is_some() {
+            return Err("Proof of work challenge already used");
+        }
+        Ok(())
+    }
+
+    /// Compares digests of every code in constant time, so the time taken doesn't hint at a valid code.
+    fn is_invite_code(&self, code: &str) -> bool {
+        let digest = Sha256::digest(code.as_bytes());
+        self.invite_codes.iter().fold(false, |found, c| {
+            let differs = Sha256::digest(c.as_bytes())
+                .iter()
+                .zip(digest.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+            found | (differs == 0)
+        })
+    }
+
+    /// `<expires_at>.<random>.<signature>`
+    fn new_challenge(&self) -> String {
+        let expires_at = chrono::Utc::now().timestamp() + CHALLENGE_TTL_SECS;
+        let body = format!("{}.{}", expires_at, uuid::Uuid::new_v4().simple());
+        format!("{}.{}", body, hex::encode(self.sign(&body)))
+    }
+
+    /// Expiry of a challenge this gate signed.
+    fn check_signature(&self, challenge: &str) -> Option<i64> {
+        let (body, signature) = challenge.rsplit_once('.')?;
+        let signature = hex::decode(signature).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.pow_key).ok()?;
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        body.split_once('.')?.0.parse().ok()
+    }
+
+    fn sign(&self, body: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.pow_key).expect("HMAC takes keys of any length");
+        mac.update(body.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate(invite_codes: &[&str], pow_difficulty: Option<u32>) -> RegisterGate {
+        RegisterGate {
+            invite_codes: Arc::new(invite_codes.iter().map(|c| c.to_string()).collect()),
+            pow_difficulty,
+            pow_key: Arc::new(b"test key".to_vec()),
+            redeemed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn solve(challenge: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes())) >= difficulty)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn open_gate_lets_everyone_in() {
+        assert_eq!(gate(&[], None).verify(None, None, None).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn invite_codes_must_match_one_exactly() {
+        let gate = gate(&["first", "second"], None);
+        assert_eq!(gate.verify(Some("second"), None, None).await, Ok(()));
+        assert_eq!(gate.verify(Some("first"), None, None).await, Ok(()));
+        // Shared codes stay valid
+        assert_eq!(gate.verify(Some("first"), None, None).await, Ok(()));
+        assert_eq!(gate.verify(Some("firs"), None, None).await, Err("Invalid invite code"));
+        assert_eq!(gate.verify(Some("Second"), None, None).await, Err("Invalid invite code"));
+        assert_eq!(gate.verify(None, None, None).await, Err("An invite code is required to register"));
+    }
+
+    #[tokio::test]
+    async fn solved_challenges_are_redeemed_once() {
+        let gate = gate(&[], Some(8));
+        let challenge = gate.requirements().pow_challenge.unwrap();
+        let nonce = solve(&challenge, 8);
+        assert_eq!(gate.verify(None, Some(&challenge), Some(&nonce)).await, Ok(()));
+        assert_eq!(
+            gate.verify(None, Some(&challenge), Some(&nonce)).await,
+            Err("Proof of work challenge already used")
+        );
+        assert!(gate.verify(None, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsolved_forged_and_expired_challenges_are_refused() {
+        let gate = gate(&[], Some(8));
+        let challenge = gate.requirements().pow_challenge.unwrap();
+        let unsolved = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes())) < 8)
+            .unwrap();
+        assert_eq!(
+            gate.verify(None, Some(&challenge), Some(&unsolved)).await,
+            Err("Proof of work is not solved")
+        );
+
+        let (body, _) = challenge.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", body, hex::encode([0u8; 32]));
+        assert_eq!(
+            gate.verify(None, Some(&forged), Some(&solve(&forged, 8))).await,
+            Err("Invalid proof of work challenge")
+        );
+
+        let body = format!("{}.{}", chrono::Utc::now().timestamp() - 1, uuid::Uuid::new_v4().simple());
+        let expired = format!("{}.{}", body, hex::encode(gate.sign(&body)));
+        assert_eq!(
+            gate.verify(None, Some(&expired), Some(&solve(&expired, 8))).await,
+            Err("Proof of work challenge expired, get a new one")
+        );
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+}
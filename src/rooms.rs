@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+/// Room every connection is subscribed to when it opens.
+pub const DEFAULT_ROOM: &str = "global";
+
+/// How many frames a room buffers for slow subscribers before they start lagging.
+const ROOM_CHANNEL_CAPACITY: usize = 100;
+
+/// A live room: its database id plus the broadcast channel its members listen on.
+#[derive(Clone)]
+pub struct Room {
+    pub id: i32,
+    pub name: String,
+    pub tx: broadcast::Sender<String>,
+}
+
+/// Each room name maps to its live broadcast channel.
+pub type Rooms = Arc<RwLock<HashMap<String, Room>>>;
+
+/// Create an empty room registry.
+pub fn new_registry() -> Rooms {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+/// Room names are short slugs so they can be typed in a `/join` command.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 50
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Get a room that is already loaded, without touching the database.
+pub async fn get(rooms: &Rooms, name: &str) -> Option<Room> {
+    let map = rooms.read().await;
+    map.get(name).cloned()
+}
+
+/// Get a room, loading it from (or creating it in) the database the first time it's used.
+pub async fn get_or_create(
+    pool: &sqlx::MySqlPool,
+    rooms: &Rooms,
+    name: &str,
+    created_by: Option<i32>,
+) -> Result<Room, sqlx::Error> {
+    if let Some(room) = get(rooms, name).await {
+        return Ok(room);
+    }
+
+    let id = crate::tables::room_db::get_or_create_room(pool, name, created_by).await?;
+
+    // Someone else may have loaded it while we were in the DB, keep theirs
+    let mut map = rooms.write().await;
+    let room = map.entry(name.to_string()).or_insert_with(|| Room {
+        id,
+        name: name.to_string(),
+        tx: broadcast::channel::<String>(ROOM_CHANNEL_CAPACITY).0,
+    });
+    Ok(room.clone())
+}
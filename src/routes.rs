@@ -1,51 +1,43 @@
-use tokio::sync::{broadcast, RwLock};
-use warp::Filter;
-use std::sync::Arc;
-use std::collections::HashSet;
+use warp::{Filter, Reply};
 
-use crate::connected_users::ConnectedUsers;
+use crate::connected_users::ClientInfo;
+use crate::ws_handler::ChatState;
 
 pub fn ws_route(
-    pool: sqlx::MySqlPool,
-    tx: broadcast::Sender<String>,
-    session_cache: Arc<RwLock<HashSet<String>>>,
-    connected: ConnectedUsers,
+    state: ChatState,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("ws")
         .and(warp::ws())
-        .and(with_db(pool))
-        .and(with_broadcast(tx.clone()))
-        .and(with_session_cache(session_cache))
-        .and(with_connected_users(connected))
-        .map(| ws: warp::ws::Ws, pool: sqlx::MySqlPool, tx: broadcast::Sender<String>, cache: Arc<RwLock<HashSet<String>>>, connected: ConnectedUsers| {
-            let pool_for_task = pool.clone(); 
-            ws.on_upgrade(move |websocket| {
-                crate::ws_handler::handle_connection(pool_for_task, websocket, tx, cache, connected)
-            })
+        .and(with_state(state))
+        .and(crate::auth::ws_session_token())
+        // Set by nginx, the TCP peer is always the proxy
+        .and(warp::header::optional::<String>("x-real-ip"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and_then(| ws: warp::ws::Ws, state: ChatState, token: Option<String>, remote_addr: Option<String>, user_agent: Option<String>| async move {
+            // Authenticated once here when the upgrade carries a session, otherwise every
+            // message has to bring its own `metadata.session_id`
+            let session = match token {
+                Some(token) => match crate::auth::authenticate(&state, &token).await {
+                    Some(principal) => Some((token, principal)),
+                    None => {
+                        return Ok::<_, warp::Rejection>(warp::reply::with_status(
+                            warp::reply::json(&"Invalid or expired session"),
+                            warp::http::StatusCode::UNAUTHORIZED,
+                        ).into_response());
+                    }
+                },
+                None => None,
+            };
+            let client = ClientInfo { remote_addr, user_agent };
+            Ok(ws.on_upgrade(move |websocket| {
+                crate::ws_handler::handle_connection(websocket, state, client, session)
+            }).into_response())
         })
 }
 
-// Create a filter that yields a clone of the pool
-fn with_db(
-    pool: sqlx::MySqlPool,
-) -> impl Filter<Extract = (sqlx::MySqlPool,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || pool.clone())
+// Create a filter that yields a clone of the shared state
+fn with_state(
+    state: ChatState,
+) -> impl Filter<Extract = (ChatState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
 }
-fn with_broadcast(
-    tx: broadcast::Sender<String>,
-) -> impl Filter<Extract = (broadcast::Sender<String>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || tx.clone())
-}
-
-fn with_session_cache(
-    cache: Arc<RwLock<HashSet<String>>>,
-) -> impl Filter<Extract = (Arc<RwLock<HashSet<String>>>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || cache.clone())
-}
-
-fn with_connected_users(
-    connected: ConnectedUsers,
-) -> impl Filter<Extract = (ConnectedUsers,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || connected.clone())
-}
-
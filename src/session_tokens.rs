@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::db::secrets::get_secret;
+
+/// Signed session tokens start with this, opaque ones are bare UUIDs.
+pub const SIGNED_PREFIX: &str = "gcs_";
+
+/// What a signed session token vouches for.
+#[derive(Debug, Clone)]
+pub struct SessionClaims {
+    pub user_id: i32,
+    pub username: String,
+    /// End of the session's absolute lifetime, unix seconds. Its idle expiry is in the database
+    pub expires_at: i64,
+}
+
+/// Signs session tokens and remembers the signed ones that were ended early.
+#[derive(Clone)]
+pub struct SessionTokens {
+    /// `None` keeps sessions as opaque tokens, looked up in the database on every use
+    key: Option<Arc<Vec<u8>>>,
+    /// Revoked signed tokens with their expiry (unix seconds), mirrors `revoked_sessions`
+    revoked: Arc<RwLock<HashMap<String, i64>>>,
+}
+
+impl SessionTokens {
+    /// `SESSION_SIGNING_KEY_NAME` names a secret to sign session tokens with, the same on every instance.
+    /// Without it sessions stay opaque tokens, checked against the database on every message.
+    pub fn from_env() -> Self {
+        let key = env::var("SESSION_SIGNING_KEY_NAME")
+            .ok()
+            .map(|name| Arc::new(get_secret(&name).into_bytes()));
+        SessionTokens {
+            key,
+            revoked: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// A token for a new session of `user_id` that can't outlive `expires_at`.
+    /// Signed ones look like `gcs_<base64url of user_id.expires_at.nonce.username>.<signature>`.
+    pub fn issue(&self, user_id: i32, username: &str, expires_at: DateTime<Utc>) -> String {
+        let Some(key) = &self.key else {
+            return uuid::Uuid::new_v4().to_string();
+        };
+        let payload = format!(
+            "{}.{}.{}.{}",
+            user_id,
+            expires_at.timestamp(),
+            &uuid::Uuid::new_v4().simple().to_string()[..16],
+            username
+        );
+        let body = format!("{}{}", SIGNED_PREFIX, BASE64URL_NOPAD.encode(payload.as_bytes()));
+        format!("{}.{}", body, BASE64URL_NOPAD.encode(&sign(key, &body)))
+    }
+
+    /// Whether `token` is checked by its signature here rather than in the database.
+    pub fn verifies(&self, token: &str) -> bool {
+        self.key.is_some() && token.starts_with(SIGNED_PREFIX)
+    }
+
+    /// Who a signed token is for, if it's genuine, unexpired and not revoked. Never touches the database.
+    pub async fn verify(&self, token: &str) -> Option<SessionClaims> {
+        let key = self.key.as_ref()?;
+        let (body, signature) = token.rsplit_once('.')?;
+        let signature = BASE64URL_NOPAD.decode(signature.as_bytes()).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).ok()?;
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        let claims = decode(token)?;
+        if claims.expires_at <= Utc::now().timestamp() || self.revoked.read().await.contains_key(token) {
+            return None;
+        }
+        Some(claims)
+    }
+
+    /// Refuse `tokens` from now on, their sessions were just deleted.
+    /// Anything that isn't a signed token is left to the database.
+    pub async fn revoke(&self, tokens: &[String]) {
+        let mut revoked = self.revoked.write().await;
+        for token in tokens {
+            if let Some(claims) = decode(token) {
+                revoked.insert(token.clone(), claims.expires_at);
+            }
+        }
+    }
+
+    /// Add what's in the `revoked_sessions` table, for revocations this instance missed.
+    pub async fn load_revoked(&self, pool: &sqlx::MySqlPool) -> Result<(), sqlx::Error> {
+        let rows = crate::tables::user_db::get_revoked_sessions(pool).await?;
+        let mut revoked = self.revoked.write().await;
+        for (token, expires_at) in rows {
+            if token.starts_with(SIGNED_PREFIX) {
+                revoked.insert(token, expires_at.timestamp());
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget revocations of tokens that expired anyway.
+    pub async fn prune(&self) {
+        let now = Utc::now().timestamp();
+        self.revoked.write().await.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+/// When a signed token stops being accepted anyway, from its claims. `None` for opaque tokens.
+pub fn expires_at(token: &str) -> Option<DateTime<Utc>> {
+    decode(token).and_then(|claims| DateTime::from_timestamp(claims.expires_at, 0))
+}
+
+fn sign(key: &[u8], body: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The claims of a signed token, without checking the signature.
+fn decode(token: &str) -> Option<SessionClaims> {
+    let (body, _) = token.strip_prefix(SIGNED_PREFIX)?.rsplit_once('.')?;
+    let payload = String::from_utf8(BASE64URL_NOPAD.decode(body.as_bytes()).ok()?).ok()?;
+    let mut parts = payload.splitn(4, '.');
+    let user_id = parts.next()?.parse().ok()?;
+    let expires_at = parts.next()?.parse().ok()?;
+    let _nonce = parts.next()?;
+    let username = parts.next()?.to_string();
+    Some(SessionClaims { user_id, username, expires_at })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing(key: &str) -> SessionTokens {
+        SessionTokens {
+            key: Some(Arc::new(key.as_bytes().to_vec())),
+            revoked: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn in_an_hour() -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::hours(1)
+    }
+
+    #[tokio::test]
+    async fn signed_tokens_carry_their_claims() {
+        let tokens = signing("key");
+        let end = in_an_hour();
+        // Dots in the name don't confuse the payload
+        let token = tokens.issue(7, "al.ice", end);
+        assert!(token.starts_with(SIGNED_PREFIX));
+        assert!(tokens.verifies(&token));
+        let claims = tokens.verify(&token).await.unwrap();
+        assert_eq!((claims.user_id, claims.username.as_str()), (7, "al.ice"));
+        assert_eq!(claims.expires_at, end.timestamp());
+        assert_eq!(expires_at(&token).map(|at| at.timestamp()), Some(end.timestamp()));
+    }
+
+    #[tokio::test]
+    async fn forged_tokens_are_refused() {
+        let token = signing("key").issue(7, "alice", in_an_hour());
+        assert!(signing("other key").verify(&token).await.is_none());
+
+        // Someone else's id under the original signature
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let payload = format!("8.{}.0123456789abcdef.alice", in_an_hour().timestamp());
+        let forged = format!("{}{}.{}", SIGNED_PREFIX, BASE64URL_NOPAD.encode(payload.as_bytes()), signature);
+        assert!(signing("key").verify(&forged).await.is_none());
+        assert!(signing("key").verify("gcs_garbage").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens_are_refused() {
+        let tokens = signing("key");
+        let expired = tokens.issue(7, "alice", Utc::now() - chrono::Duration::seconds(1));
+        assert!(tokens.verify(&expired).await.is_none());
+
+        let token = tokens.issue(7, "alice", in_an_hour());
+        tokens.revoke(std::slice::from_ref(&token)).await;
+        assert!(tokens.verify(&token).await.is_none());
+        // Kept until the token would have expired anyway
+        tokens.prune().await;
+        assert!(tokens.verify(&token).await.is_none());
+    }
+
+    #[test]
+    fn unsigned_tokens_are_opaque() {
+        let tokens = SessionTokens { key: None, revoked: Arc::new(RwLock::new(HashMap::new())) };
+        let token = tokens.issue(7, "alice", in_an_hour());
+        assert!(uuid::Uuid::parse_str(&token).is_ok());
+        assert!(!tokens.verifies(&token));
+        assert_eq!(expires_at(&token), None);
+    }
+}
@@ -0,0 +1,365 @@
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::cluster::ClusterEvent;
+use crate::connected_users::{self, ClientInfo};
+use crate::permissions::Role;
+use crate::session_tokens::{SessionClaims, SessionTokens, SIGNED_PREFIX};
+use crate::tables::sanction_db::{self, Sanction, SanctionKind};
+use crate::tables::user_db::{self, StoredSession};
+use crate::ws_handler::{self, ChatState};
+
+/// `last_seen` and the sliding expiry are kept to this precision, see `SessionStore::touch`.
+pub const LAST_SEEN_RESOLUTION_MINS: i64 = 5;
+/// A token the database didn't know is refused without asking again for this long.
+const MISS_TTL: Duration = Duration::from_secs(60);
+/// Tokens remembered as unknown at most, so a flood of made up ones can't grow the cache for ever.
+const MAX_MISSES: usize = 10_000;
+/// A user's role and mute are looked up again after this long, in case a `StandingChanged` got lost.
+const STANDING_TTL: Duration = Duration::from_secs(60);
+
+/// How long sessions last.
+#[derive(Clone, Copy)]
+struct Lifetimes {
+    /// Unused for this long, a session ends
+    idle: chrono::Duration,
+    /// However much it's used, a session ends this long after logging in
+    absolute: chrono::Duration,
+}
+
+/// What the messages a user sends depend on, cached so they needn't cost a query each.
+#[derive(Debug, Clone)]
+pub struct Standing {
+    pub role: Role,
+    /// In force when this was looked up
+    mute: Option<Sanction>,
+}
+
+impl Standing {
+    /// The mute on the user, unless it ran out since.
+    pub fn mute(&self) -> Option<&Sanction> {
+        self.mute
+            .as_ref()
+            .filter(|mute| mute.expires_at.is_none_or(|expires_at| expires_at > Utc::now()))
+    }
+}
+
+/// Who every session in use here belongs to. Filled as tokens are used and kept up to date
+/// as sessions start, end and get renamed, expired ones are dropped when next looked at.
+#[derive(Clone)]
+pub struct SessionStore {
+    sessions: Arc<RwLock<HashMap<String, StoredSession>>>,
+    /// Well-formed tokens the database had no session for, and when it said so
+    misses: Arc<RwLock<HashMap<String, Instant>>>,
+    /// Role and mute of the users sending messages here, and when they were looked up
+    standings: Arc<RwLock<HashMap<i32, (Standing, Instant)>>>,
+    /// Current names of the users with a session, for signed tokens still carrying an old one
+    usernames: Arc<RwLock<HashMap<i32, String>>>,
+    tokens: SessionTokens,
+    lifetimes: Lifetimes,
+}
+
+impl SessionStore {
+    /// - `SESSION_IDLE_HOURS` how long a session lasts unused, 168 (a week) by default
+    /// - `SESSION_MAX_DAYS` how long it lasts at most however much it's used, 30 by default
+    /// - `SESSION_SIGNING_KEY_NAME`, see `SessionTokens::from_env`
+    pub fn from_env() -> Self {
+        let absolute_days = env::var("SESSION_MAX_DAYS")
+            .ok()
+            .and_then(|d| d.trim().parse::<i64>().ok())
+            .filter(|d| *d > 0)
+            .unwrap_or(30);
+        let idle_hours = env::var("SESSION_IDLE_HOURS")
+            .ok()
+            .and_then(|h| h.trim().parse::<i64>().ok())
+            .filter(|h| *h > 0)
+            .unwrap_or(7 * 24)
+            .min(absolute_days * 24);
+        SessionStore {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            misses: Arc::new(RwLock::new(HashMap::new())),
+            standings: Arc::new(RwLock::new(HashMap::new())),
+            usernames: Arc::new(RwLock::new(HashMap::new())),
+            tokens: SessionTokens::from_env(),
+            lifetimes: Lifetimes {
+                idle: chrono::Duration::hours(idle_hours),
+                absolute: chrono::Duration::days(absolute_days),
+            },
+        }
+    }
+
+    /// Start a session for a user who just proved who they are. Returns its token.
+    pub async fn create(
+        &self,
+        pool: &sqlx::MySqlPool,
+        user_id: i32,
+        username: &str,
+        client: &ClientInfo,
+    ) -> Result<String, sqlx::Error> {
+        let now = Utc::now();
+        let session = StoredSession {
+            user_id,
+            username: username.to_string(),
+            created_at: now,
+            expires_at: now + self.lifetimes.idle,
+            last_seen: now,
+        };
+        let token = self.tokens.issue(user_id, username, now + self.lifetimes.absolute);
+        user_db::create_session(pool, &token, &session, client.user_agent.as_deref(), client.remote_addr.as_deref())
+            .await?;
+        self.sessions.write().await.insert(token.clone(), session);
+        Ok(token)
+    }
+
+    /// Swap `token` for a new one, keeping the session's absolute lifetime. Returns `None`
+    /// if `token` isn't valid. The old token is deleted, `revoke` it to finish the job.
+    pub async fn rotate(
+        &self,
+        pool: &sqlx::MySqlPool,
+        token: &str,
+        client: &ClientInfo,
+    ) -> Result<Option<(String, StoredSession)>, sqlx::Error> {
+        let Some(old) = self.get(pool, token).await else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let session = StoredSession {
+            expires_at: self.extended_expiry(&old, now),
+            last_seen: now,
+            ..old
+        };
+        let new_token = self.tokens.issue(
+            session.user_id,
+            &session.username,
+            session.created_at + self.lifetimes.absolute,
+        );
+        let replaced = user_db::replace_session(
+            pool,
+            token,
+            &new_token,
+            &session,
+            client.user_agent.as_deref(),
+            client.remote_addr.as_deref(),
+        )
+        .await?;
+        if !replaced {
+            return Ok(None);
+        }
+        self.sessions.write().await.insert(new_token.clone(), session.clone());
+        Ok(Some((new_token, session)))
+    }
+
+    /// The session behind `token`, if it's valid. Signed tokens are taken at their word once the
+    /// signature checks out and they aren't revoked, whether the database is up or not; `touch`
+    /// finds out when their session ended elsewhere. Opaque tokens are looked up in the database
+    /// the first time they're seen here, e.g. when another instance started the session, and again
+    /// when they seem to have expired, as they may have been used elsewhere since. Nothing that
+    /// isn't a token at all, or was just looked up in vain, gets that far.
+    pub async fn get(&self, pool: &sqlx::MySqlPool, token: &str) -> Option<StoredSession> {
+        if self.tokens.verifies(token) {
+            let claims = self.tokens.verify(token).await?;
+            let stored = self.sessions.read().await.get(token).cloned();
+            if let Some(session) = stored.filter(|s| s.expires_at > Utc::now()) {
+                return Some(session);
+            }
+            let session = self.from_claims(claims).await?;
+            self.sessions.write().await.insert(token.to_string(), session.clone());
+            return Some(session);
+        }
+        // Opaque tokens are UUIDs, signed ones need the key to be checked
+        if token.starts_with(SIGNED_PREFIX) || uuid::Uuid::parse_str(token).is_err() {
+            return None;
+        }
+
+        let stored = self.sessions.read().await.get(token).cloned();
+        if let Some(session) = stored.filter(|s| s.expires_at > Utc::now()) {
+            return Some(session);
+        }
+        if self.misses.read().await.get(token).is_some_and(|at| at.elapsed() < MISS_TTL) {
+            return None;
+        }
+        let session = match user_db::get_session(pool, token).await {
+            Ok(session) => session,
+            Err(sqlx::Error::RowNotFound) => {
+                self.sessions.write().await.remove(token);
+                self.remember_miss(token).await;
+                return None;
+            }
+            // Whether it was revoked elsewhere can't be told, so it's refused until the database is back
+            Err(_) => return None,
+        };
+        self.sessions.write().await.insert(token.to_string(), session.clone());
+        Some(session)
+    }
+
+    /// A session as a signed token describes it, under the user's current name. When it was last
+    /// used isn't known here, so it's set far enough back for the next `touch` to write through.
+    async fn from_claims(&self, claims: SessionClaims) -> Option<StoredSession> {
+        let created_at = DateTime::from_timestamp(claims.expires_at, 0)? - self.lifetimes.absolute;
+        let username = self
+            .usernames
+            .read()
+            .await
+            .get(&claims.user_id)
+            .cloned()
+            .unwrap_or(claims.username);
+        let now = Utc::now();
+        let session = StoredSession {
+            user_id: claims.user_id,
+            username,
+            created_at,
+            expires_at: now,
+            last_seen: now - chrono::Duration::minutes(LAST_SEEN_RESOLUTION_MINS),
+        };
+        Some(StoredSession {
+            expires_at: self.extended_expiry(&session, now),
+            ..session
+        })
+    }
+
+    /// Record use of a session, extending it to the idle lifetime from now, up to its absolute
+    /// lifetime. Written at most every `LAST_SEEN_RESOLUTION_MINS`, without failing the request over it.
+    pub async fn touch(&self, pool: &sqlx::MySqlPool, token: &str) {
+        let now = Utc::now();
+        let session = {
+            let mut sessions = self.sessions.write().await;
+            let Some(session) = sessions.get_mut(token) else {
+                return;
+            };
+            if now - session.last_seen < chrono::Duration::minutes(LAST_SEEN_RESOLUTION_MINS) {
+                return;
+            }
+            session.expires_at = self.extended_expiry(session, now);
+            session.last_seen = now;
+            session.clone()
+        };
+        match user_db::touch_session(pool, token, session.last_seen, session.expires_at).await {
+            Ok(true) => {}
+            // Expired or deleted without this instance hearing of it, signed tokens would
+            // otherwise keep working on their claims
+            Ok(false) => self.forget(&[token.to_string()]).await,
+            Err(_) => println!("Failed to extend a session"),
+        }
+    }
+
+    /// The role and mute of `user_id`, from the database at most every `STANDING_TTL` unless
+    /// `forget_standing` says they changed.
+    pub async fn standing(&self, pool: &sqlx::MySqlPool, user_id: i32) -> Result<Standing, sqlx::Error> {
+        let cached = self.standings.read().await.get(&user_id).cloned();
+        if let Some((standing, _)) = cached.filter(|(_, at)| at.elapsed() < STANDING_TTL) {
+            return Ok(standing);
+        }
+        let role = user_db::get_user_by_id(pool, user_id).await?.role;
+        let mute = sanction_db::get_active(pool, user_id, SanctionKind::Mute).await?;
+        let standing = Standing { role, mute };
+        self.standings.write().await.insert(user_id, (standing.clone(), Instant::now()));
+        Ok(standing)
+    }
+
+    /// `user_id` got a new role, or was muted or unmuted.
+    pub async fn forget_standing(&self, user_id: i32) {
+        self.standings.write().await.remove(&user_id);
+    }
+
+    async fn remember_miss(&self, token: &str) {
+        let mut misses = self.misses.write().await;
+        if misses.len() >= MAX_MISSES {
+            misses.retain(|_, at| at.elapsed() < MISS_TTL);
+            if misses.len() >= MAX_MISSES {
+                misses.clear();
+            }
+        }
+        misses.insert(token.to_string(), Instant::now());
+    }
+
+    fn extended_expiry(&self, session: &StoredSession, now: DateTime<Utc>) -> DateTime<Utc> {
+        (now + self.lifetimes.idle).min(session.created_at + self.lifetimes.absolute)
+    }
+
+    /// `Set-Cookie` value for `token`, kept by the browser as long as the session can last.
+    pub fn cookie(&self, token: &str) -> String {
+        format!(
+            "session_token={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+            token,
+            self.lifetimes.absolute.num_seconds()
+        )
+    }
+
+    /// Drop sessions that were just deleted from the database.
+    pub async fn forget(&self, tokens: &[String]) {
+        {
+            let mut sessions = self.sessions.write().await;
+            for token in tokens {
+                sessions.remove(token);
+            }
+        }
+        self.tokens.revoke(tokens).await;
+    }
+
+    /// Give the sessions of `user_id` their new name.
+    pub async fn rename(&self, user_id: i32, username: &str) {
+        self.usernames.write().await.insert(user_id, username.to_string());
+        for session in self.sessions.write().await.values_mut() {
+            if session.user_id == user_id {
+                session.username = username.to_string();
+            }
+        }
+    }
+
+    /// Drop sessions that expired or were deleted without this instance hearing of it, catch
+    /// up on revocations and on renames. A session dropped by mistake is only looked up again on its next use.
+    pub async fn sync(&self, pool: &sqlx::MySqlPool) -> Result<(), sqlx::Error> {
+        let rows = user_db::get_all_valid_sessions(pool).await?;
+        let usernames: HashMap<i32, String> = rows.iter().map(|(_, user_id, name)| (*user_id, name.clone())).collect();
+        let valid: HashSet<String> = rows.into_iter().map(|(token, _, _)| token).collect();
+        let now = Utc::now();
+        self.sessions.write().await.retain(|token, session| {
+            if let Some(name) = usernames.get(&session.user_id) {
+                session.username = name.clone();
+            }
+            session.expires_at > now && valid.contains(token)
+        });
+        *self.usernames.write().await = usernames;
+        self.misses.write().await.retain(|_, at| at.elapsed() < MISS_TTL);
+        self.standings.write().await.retain(|_, (_, at)| at.elapsed() < STANDING_TTL);
+        self.tokens.prune().await;
+        self.tokens.load_revoked(pool).await
+    }
+}
+
+/// Forget sessions of `user_id` that were just deleted from the database: drop them
+/// from the cache and close every socket that authenticated with them, on every instance.
+pub async fn revoke(state: &ChatState, user_id: i32, tokens: &[String]) {
+    if tokens.is_empty() {
+        return;
+    }
+    state.session_store.forget(tokens).await;
+    state.cluster.publish(ClusterEvent::SessionsRevoked {
+        user_id,
+        tokens: tokens.to_vec(),
+    });
+    if connected_users::close_session_connections(&state.connected, user_id, tokens).await {
+        ws_handler::went_offline(&state.connected, &state.cluster, &state.presence, &state.pool, user_id, None).await;
+    }
+}
+
+/// Rename `user_id` in every instance's session store.
+pub async fn rename(state: &ChatState, user_id: i32, username: &str) {
+    state.session_store.rename(user_id, username).await;
+    state.api_keys.forget_user(user_id).await;
+    state.cluster.publish(ClusterEvent::Renamed {
+        user_id,
+        username: username.to_string(),
+    });
+}
+
+/// Make every instance look up the role, mute and ban of `user_id` again, for sessions and API keys.
+pub async fn standing_changed(state: &ChatState, user_id: i32) {
+    state.session_store.forget_standing(user_id).await;
+    state.api_keys.forget_user(user_id).await;
+    state.cluster.publish(ClusterEvent::StandingChanged { user_id });
+}
@@ -1 +1,10 @@
-pub mod user_db;
\ No newline at end of file
+pub mod user_db;
+pub mod room_db;
+pub mod private_message_db;
+pub mod message_db;
+pub mod reaction_db;
+pub mod sanction_db;
+pub mod api_key_db;
+pub mod totp_db;
+pub mod email_db;
+pub mod oauth_db;
\ No newline at end of file
@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+
+use crate::permissions::Role;
+use crate::tables::user_db::User;
+
+#[derive(Debug, FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: String,
+    pub rate_limit: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+pub async fn create_api_key(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    scopes: &str,
+    rate_limit: &str,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query!(
+        "INSERT INTO api_keys (user_id, name, prefix, key_hash, scopes, rate_limit) VALUES (?, ?, ?, ?, ?, ?)",
+        user_id,
+        name,
+        prefix,
+        key_hash,
+        scopes,
+        rate_limit
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_id() as i64)
+}
+
+pub async fn get_api_key(pool: &sqlx::MySqlPool, id: i64) -> Result<ApiKey, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT id, user_id, name, prefix, key_hash, scopes, rate_limit,
+            created_at AS `created_at!`, last_used_at
+        FROM api_keys
+        WHERE id = ?
+        "#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_api_key_by_prefix(pool: &sqlx::MySqlPool, prefix: &str) -> Result<ApiKey, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT id, user_id, name, prefix, key_hash, scopes, rate_limit,
+            created_at AS `created_at!`, last_used_at
+        FROM api_keys
+        WHERE prefix = ?
+        "#,
+        prefix
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// A user's keys, newest first.
+pub async fn list_api_keys(pool: &sqlx::MySqlPool, user_id: i32) -> Result<Vec<ApiKey>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT id, user_id, name, prefix, key_hash, scopes, rate_limit,
+            created_at AS `created_at!`, last_used_at
+        FROM api_keys
+        WHERE user_id = ?
+        ORDER BY id DESC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_api_keys(pool: &sqlx::MySqlPool, user_id: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!("SELECT COUNT(*) FROM api_keys WHERE user_id = ?", user_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// The owner of key `id`, failing with `RowNotFound` once the key is revoked.
+pub async fn get_api_key_owner(pool: &sqlx::MySqlPool, id: i64) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT u.id, u.username, u.password_hash, u.created_at, u.role AS `role: Role`
+        FROM api_keys k
+        JOIN app_users u ON k.user_id = u.id
+        WHERE k.id = ?
+        "#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn touch_api_key(pool: &sqlx::MySqlPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE api_keys SET last_used_at = ? WHERE id = ?", Utc::now(), id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Delete key `id` if `user_id` owns it. Returns its prefix, `None` if there was no such key.
+pub async fn delete_api_key(pool: &sqlx::MySqlPool, user_id: i32, id: i64) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let prefix = sqlx::query_scalar!(
+        "SELECT prefix FROM api_keys WHERE id = ? AND user_id = ? FOR UPDATE",
+        id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if prefix.is_some() {
+        sqlx::query!("DELETE FROM api_keys WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(prefix)
+}
@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TokenPurpose {
+    /// Confirms the address it was sent to
+    Verify,
+    /// Sets a new password
+    Reset,
+}
+
+#[derive(Debug, FromRow)]
+pub struct EmailAddress {
+    pub email: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+}
+
+/// Whose a mailed token is, and the address it went to.
+#[derive(Debug, FromRow)]
+pub struct EmailToken {
+    pub user_id: i32,
+    pub email: String,
+}
+
+/// A user a password reset can be mailed to.
+#[derive(Debug, FromRow)]
+pub struct ResetTarget {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+}
+
+pub async fn get_email(pool: &sqlx::MySqlPool, user_id: i32) -> Result<EmailAddress, sqlx::Error> {
+    sqlx::query_as!(
+        EmailAddress,
+        "SELECT email, email_verified_at FROM app_users WHERE id = ?",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Set a new, unverified address, dropping the links sent for the old one.
+pub async fn set_email(pool: &sqlx::MySqlPool, user_id: i32, email: &str) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE app_users SET email = ?, email_verified_at = NULL WHERE id = ?",
+        email,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM email_tokens WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Mark `email` verified, if it's still the user's address. Returns whether it was.
+pub async fn mark_email_verified(pool: &sqlx::MySqlPool, user_id: i32, email: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE app_users SET email_verified_at = ? WHERE id = ? AND email = ?",
+        Utc::now(),
+        user_id,
+        email
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Users a password reset for `login` goes to: the user called that, or everyone who verified
+/// it as their address. Only ever verified addresses.
+pub async fn find_reset_targets(pool: &sqlx::MySqlPool, login: &str) -> Result<Vec<ResetTarget>, sqlx::Error> {
+    sqlx::query_as!(
+        ResetTarget,
+        r#"
+        SELECT id, username, email AS `email!`
+        FROM app_users
+        WHERE (username = ? OR email = ?) AND email IS NOT NULL AND email_verified_at IS NOT NULL
+        "#,
+        login,
+        login
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn create_email_token(
+    pool: &sqlx::MySqlPool,
+    token_hash: &str,
+    user_id: i32,
+    purpose: TokenPurpose,
+    email: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO email_tokens (token_hash, user_id, purpose, email, expires_at) VALUES (?, ?, ?, ?, ?)",
+        token_hash,
+        user_id,
+        purpose,
+        email,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// When the user was last sent a token for `purpose`, to not flood their inbox.
+pub async fn last_email_token_at(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    purpose: TokenPurpose,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT MAX(created_at) FROM email_tokens WHERE user_id = ? AND purpose = ?",
+        user_id,
+        purpose
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Use up a token. Returns whose it was, `None` if it's unknown, expired or for something else.
+pub async fn take_email_token(
+    pool: &sqlx::MySqlPool,
+    token_hash: &str,
+    purpose: TokenPurpose,
+) -> Result<Option<EmailToken>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let token = sqlx::query_as!(
+        EmailToken,
+        r#"
+        SELECT user_id, email FROM email_tokens
+        WHERE token_hash = ? AND purpose = ? AND expires_at > ?
+        FOR UPDATE
+        "#,
+        token_hash,
+        purpose,
+        Utc::now()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(token) = token else {
+        return Ok(None);
+    };
+    // Every link of the same kind goes, one reset is enough
+    sqlx::query!(
+        "DELETE FROM email_tokens WHERE user_id = ? AND purpose = ?",
+        token.user_id,
+        purpose
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(token))
+}
+
+pub async fn cleanup_expired_email_tokens(pool: &sqlx::MySqlPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM email_tokens WHERE expires_at < ?", Utc::now())
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Who a token is for, without using it up.
+pub async fn find_email_token(
+    pool: &sqlx::MySqlPool,
+    token_hash: &str,
+    purpose: TokenPurpose,
+) -> Result<Option<EmailToken>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailToken,
+        "SELECT user_id, email FROM email_tokens WHERE token_hash = ? AND purpose = ? AND expires_at > ?",
+        token_hash,
+        purpose,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+
+/// The parts of a stored broadcast message needed to authorize and announce a change.
+#[derive(Debug, FromRow)]
+pub struct StoredMessage {
+    pub user_id: i32,
+    pub room: String,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+pub async fn get_message(pool: &sqlx::MySqlPool, id: i64) -> Result<StoredMessage, sqlx::Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        r#"
+        SELECT m.user_id, r.name AS room, m.deleted_at
+        FROM messages m
+        JOIN rooms r ON m.room_id = r.id
+        WHERE m.id = ?
+        "#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Replace a message's content, keeping the previous version in `message_edits`.
+pub async fn edit_message(
+    pool: &sqlx::MySqlPool,
+    id: i64,
+    editor_id: i32,
+    new_content: &str,
+    edited_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO message_edits (message_id, editor_id, action, old_content, new_content, created_at)
+        SELECT id, ?, 'edit', content, ?, ? FROM messages WHERE id = ?
+        "#,
+        editor_id,
+        new_content,
+        edited_at,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE messages SET content = ?, edited_at = ? WHERE id = ?",
+        new_content,
+        edited_at,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Turn a message into a tombstone, keeping its last content in `message_edits`.
+pub async fn delete_message(
+    pool: &sqlx::MySqlPool,
+    id: i64,
+    editor_id: i32,
+    deleted_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO message_edits (message_id, editor_id, action, old_content, created_at)
+        SELECT id, ?, 'delete', content, ? FROM messages WHERE id = ?
+        "#,
+        editor_id,
+        deleted_at,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE messages SET content = '', deleted_at = ? WHERE id = ?",
+        deleted_at,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
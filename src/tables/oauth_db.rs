@@ -0,0 +1,108 @@
+use chrono::Utc;
+
+/// The user an outside account is linked to, if any.
+pub async fn find_linked_user(
+    pool: &sqlx::MySqlPool,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM oauth_identities WHERE provider = ? AND subject = ?",
+        provider,
+        subject
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Fails with a unique violation when the outside account, or another one of the same
+/// provider, is already linked.
+pub async fn link_identity(
+    pool: &sqlx::MySqlPool,
+    provider: &str,
+    subject: &str,
+    user_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO oauth_identities (provider, subject, user_id, last_login_at) VALUES (?, ?, ?, ?)",
+        provider,
+        subject,
+        user_id,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// A new user with the outside account linked, and the address the provider verified if any:
+/// all of it or nothing. `None` when the username, or a lookalike of it, is taken. Fails with a
+/// unique violation when the outside account got linked meanwhile.
+pub async fn create_linked_user(
+    pool: &sqlx::MySqlPool,
+    provider: &str,
+    subject: &str,
+    username: &str,
+    raw_password: &str,
+    email: Option<&str>,
+) -> Result<Option<i32>, sqlx::Error> {
+    let hashed_password = crate::tables::user_db::hash_password(raw_password);
+    let skeleton = crate::validation::skeleton(username);
+    let mut tx = pool.begin().await?;
+    let inserted = sqlx::query!(
+        "INSERT INTO app_users (username, username_skeleton, password_hash) VALUES (?, ?, ?)",
+        username,
+        skeleton,
+        hashed_password
+    )
+    .execute(&mut *tx)
+    .await;
+    let user_id = match inserted {
+        Ok(result) => result.last_insert_id() as i32,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    sqlx::query!(
+        "INSERT INTO oauth_identities (provider, subject, user_id, last_login_at) VALUES (?, ?, ?, ?)",
+        provider,
+        subject,
+        user_id,
+        Utc::now()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if let Some(email) = email {
+        sqlx::query!(
+            "UPDATE app_users SET email = ?, email_verified_at = ? WHERE id = ?",
+            email,
+            Utc::now(),
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+pub async fn touch_identity(pool: &sqlx::MySqlPool, provider: &str, subject: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE oauth_identities SET last_login_at = ? WHERE provider = ? AND subject = ?",
+        Utc::now(),
+        provider,
+        subject
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Users with this verified address, at most two: linking by address needs exactly one.
+pub async fn find_users_by_verified_email(pool: &sqlx::MySqlPool, email: &str) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT id FROM app_users WHERE email = ? AND email_verified_at IS NOT NULL LIMIT 2",
+        email
+    )
+    .fetch_all(pool)
+    .await
+}
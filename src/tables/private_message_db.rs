@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct PrivateMessage {
+    pub id: i64,
+    pub from_username: String,
+    pub to_username: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    /// None while the recipient hasn't been online since it was sent
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Store a private message as undelivered. Returns its id.
+pub async fn save_private_message(
+    pool: &sqlx::MySqlPool,
+    sender_id: i32,
+    recipient_id: i32,
+    content: &str,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query!(
+        "INSERT INTO private_messages (sender_id, recipient_id, content) VALUES (?, ?, ?)",
+        sender_id,
+        recipient_id,
+        content
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_id() as i64)
+}
+
+/// Mark a message as delivered. Returns false if it already was, so only one
+/// caller ends up sending it when a user connects from several places at once.
+pub async fn mark_delivered(pool: &sqlx::MySqlPool, id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE private_messages SET delivered_at = ? WHERE id = ? AND delivered_at IS NULL",
+        Utc::now(),
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Queue a claimed message again, when its recipient was gone by the time it was sent.
+pub async fn mark_undelivered(pool: &sqlx::MySqlPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE private_messages SET delivered_at = NULL WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Everything still waiting for `recipient_id`, oldest first.
+pub async fn get_undelivered(
+    pool: &sqlx::MySqlPool,
+    recipient_id: i32,
+) -> Result<Vec<PrivateMessage>, sqlx::Error> {
+    sqlx::query_as!(
+        PrivateMessage,
+        r#"
+        SELECT
+            pm.id,
+            s.username AS from_username,
+            r.username AS to_username,
+            pm.content,
+            pm.created_at,
+            pm.delivered_at
+        FROM private_messages pm
+        JOIN app_users s ON pm.sender_id = s.id
+        JOIN app_users r ON pm.recipient_id = r.id
+        WHERE pm.recipient_id = ? AND pm.delivered_at IS NULL
+        ORDER BY pm.id ASC
+        "#,
+        recipient_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// The last `limit` messages exchanged between two users, oldest first.
+pub async fn get_dm_history(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    other_id: i32,
+    limit: i32,
+) -> Result<Vec<PrivateMessage>, sqlx::Error> {
+    // Derived tables lose NOT NULL flags, hence the `!` overrides on the outer select
+    sqlx::query_as!(
+        PrivateMessage,
+        r#"
+        SELECT
+            id AS `id!`,
+            from_username AS `from_username!`,
+            to_username AS `to_username!`,
+            content AS `content!`,
+            created_at AS `created_at!`,
+            delivered_at
+        FROM (
+            SELECT
+                pm.id,
+                s.username AS from_username,
+                r.username AS to_username,
+                pm.content,
+                pm.created_at,
+                pm.delivered_at
+            FROM private_messages pm
+            JOIN app_users s ON pm.sender_id = s.id
+            JOIN app_users r ON pm.recipient_id = r.id
+            WHERE (pm.sender_id = ? AND pm.recipient_id = ?)
+               OR (pm.sender_id = ? AND pm.recipient_id = ?)
+            ORDER BY pm.id DESC
+            LIMIT ?
+        ) latest
+        ORDER BY id ASC
+        "#,
+        user_id,
+        other_id,
+        other_id,
+        user_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,102 @@
+use serde::Serialize;
+use sqlx::FromRow;
+use std::collections::HashMap;
+
+/// How many users reacted to a message with one emoji.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+}
+
+#[derive(FromRow)]
+struct MessageReactionCount {
+    message_id: i64,
+    emoji: String,
+    count: i64,
+}
+
+/// Add a reaction. Reacting twice with the same emoji is a no-op.
+pub async fn add_reaction(
+    pool: &sqlx::MySqlPool,
+    message_id: i64,
+    user_id: i32,
+    emoji: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT IGNORE INTO message_reactions (message_id, user_id, emoji) VALUES (?, ?, ?)",
+        message_id,
+        user_id,
+        emoji
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_reaction(
+    pool: &sqlx::MySqlPool,
+    message_id: i64,
+    user_id: i32,
+    emoji: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM message_reactions WHERE message_id = ? AND user_id = ? AND emoji = ?",
+        message_id,
+        user_id,
+        emoji
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Reaction counts of one message, in the order the emoji were first used.
+pub async fn get_reaction_counts(
+    pool: &sqlx::MySqlPool,
+    message_id: i64,
+) -> Result<Vec<ReactionCount>, sqlx::Error> {
+    sqlx::query_as!(
+        ReactionCount,
+        r#"
+        SELECT emoji, COUNT(*) AS `count!: i64`
+        FROM message_reactions
+        WHERE message_id = ?
+        GROUP BY emoji
+        ORDER BY MIN(created_at)
+        "#,
+        message_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Reaction counts of many messages in one query. Messages nobody reacted to are absent.
+pub async fn get_reaction_counts_for(
+    pool: &sqlx::MySqlPool,
+    message_ids: &[i64],
+) -> Result<HashMap<i64, Vec<ReactionCount>>, sqlx::Error> {
+    let mut counts: HashMap<i64, Vec<ReactionCount>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(counts);
+    }
+
+    // The id list has a runtime length, so this one can't be a checked query!
+    let mut query = sqlx::QueryBuilder::<sqlx::MySql>::new(
+        "SELECT message_id, emoji, COUNT(*) AS count FROM message_reactions WHERE message_id IN (",
+    );
+    let mut ids = query.separated(", ");
+    for id in message_ids {
+        ids.push_bind(*id);
+    }
+    ids.push_unseparated(") GROUP BY message_id, emoji ORDER BY MIN(created_at)");
+
+    let rows: Vec<MessageReactionCount> = query.build_query_as().fetch_all(pool).await?;
+    for row in rows {
+        counts.entry(row.message_id).or_default().push(ReactionCount {
+            emoji: row.emoji,
+            count: row.count,
+        });
+    }
+    Ok(counts)
+}
@@ -0,0 +1,20 @@
+/// Return the id of the room called `name`, creating it first if it doesn't exist yet.
+pub async fn get_or_create_room(
+    pool: &sqlx::MySqlPool,
+    name: &str,
+    created_by: Option<i32>,
+) -> Result<i32, sqlx::Error> {
+    // IGNORE makes concurrent joins of a brand new room safe, the UNIQUE name wins
+    sqlx::query!(
+        "INSERT IGNORE INTO rooms (name, created_by) VALUES (?, ?)",
+        name,
+        created_by
+    )
+    .execute(pool)
+    .await?;
+
+    let row = sqlx::query!("SELECT id FROM rooms WHERE name = ?", name)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.id)
+}
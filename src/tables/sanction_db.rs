@@ -0,0 +1,103 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum SanctionKind {
+    /// Can't send anything others get to see
+    Mute,
+    /// Can't log in, and every session is gone
+    Ban,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Sanction {
+    pub reason: Option<String>,
+    /// `None` for a permanent one
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Sanction {
+    /// "until <time>" or "permanently", plus the reason, for messages to the sanctioned user.
+    pub fn describe(&self) -> String {
+        let until = match self.expires_at {
+            Some(expires_at) => format!("until {}", crate::ws_types::format_timestamp(expires_at)),
+            None => "permanently".to_string(),
+        };
+        match &self.reason {
+            Some(reason) => format!("{}: {}", until, reason),
+            None => until,
+        }
+    }
+}
+
+pub async fn add_sanction(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    kind: SanctionKind,
+    reason: Option<&str>,
+    // `None` for automatic ones
+    issued_by: Option<i32>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query!(
+        "INSERT INTO sanctions (user_id, kind, reason, issued_by, expires_at) VALUES (?, ?, ?, ?, ?)",
+        user_id,
+        kind,
+        reason,
+        issued_by,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_id() as i64)
+}
+
+/// The `kind` sanction currently on a user, the one lasting longest if several overlap.
+pub async fn get_active(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    kind: SanctionKind,
+) -> Result<Option<Sanction>, sqlx::Error> {
+    sqlx::query_as!(
+        Sanction,
+        r#"
+        SELECT reason, expires_at
+        FROM sanctions
+        WHERE user_id = ? AND kind = ? AND lifted_at IS NULL
+          AND (expires_at IS NULL OR expires_at > ?)
+        ORDER BY expires_at IS NULL DESC, expires_at DESC
+        LIMIT 1
+        "#,
+        user_id,
+        kind,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Lift every active `kind` sanction on a user. Returns how many there were.
+pub async fn lift_sanctions(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    kind: SanctionKind,
+) -> Result<u64, sqlx::Error> {
+    let now = Utc::now();
+    let result = sqlx::query!(
+        r#"
+        UPDATE sanctions SET lifted_at = ?
+        WHERE user_id = ? AND kind = ? AND lifted_at IS NULL
+          AND (expires_at IS NULL OR expires_at > ?)
+        "#,
+        now,
+        user_id,
+        kind,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+
+/// A user's second factor, all `None` when they never enrolled.
+#[derive(Debug, FromRow)]
+pub struct TotpState {
+    pub totp_secret: Option<String>,
+    /// `None` while enrollment isn't confirmed
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    pub totp_last_step: Option<i64>,
+}
+
+impl TotpState {
+    /// The secret codes are checked against, once enrollment is confirmed.
+    pub fn enabled_secret(&self) -> Option<&str> {
+        self.totp_enabled_at.and(self.totp_secret.as_deref())
+    }
+}
+
+pub async fn get_totp(pool: &sqlx::MySqlPool, user_id: i32) -> Result<TotpState, sqlx::Error> {
+    sqlx::query_as!(
+        TotpState,
+        "SELECT totp_secret, totp_enabled_at, totp_last_step FROM app_users WHERE id = ?",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Start enrolling with a new secret, not enabled until confirmed.
+pub async fn set_pending_secret(pool: &sqlx::MySqlPool, user_id: i32, secret: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE app_users SET totp_secret = ?, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = ?",
+        secret,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Record that a code for time step `step` was used. Returns `false` if that step, or a
+/// later one, already was: the code is being replayed.
+pub async fn claim_step(pool: &sqlx::MySqlPool, user_id: i32, step: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE app_users SET totp_last_step = ?
+        WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)
+        "#,
+        step,
+        user_id,
+        step
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Confirm enrollment, with fresh recovery codes replacing any old ones.
+pub async fn enable_totp(pool: &sqlx::MySqlPool, user_id: i32, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("UPDATE app_users SET totp_enabled_at = ? WHERE id = ?", Utc::now(), user_id)
+        .execute(&mut *tx)
+        .await?;
+    insert_recovery_codes(&mut tx, user_id, code_hashes).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn replace_recovery_codes(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    code_hashes: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    insert_recovery_codes(&mut tx, user_id, code_hashes).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn insert_recovery_codes(
+    tx: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    user_id: i32,
+    code_hashes: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM totp_recovery_codes WHERE user_id = ?", user_id)
+        .execute(&mut **tx)
+        .await?;
+    for code_hash in code_hashes {
+        sqlx::query!(
+            "INSERT INTO totp_recovery_codes (user_id, code_hash) VALUES (?, ?)",
+            user_id,
+            code_hash
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Use up a recovery code. Returns whether it was one of the user's unused ones.
+pub async fn use_recovery_code(pool: &sqlx::MySqlPool, user_id: i32, code_hash: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE totp_recovery_codes SET used_at = ?
+        WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+        "#,
+        Utc::now(),
+        user_id,
+        code_hash
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn count_unused_recovery_codes(pool: &sqlx::MySqlPool, user_id: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM totp_recovery_codes WHERE user_id = ? AND used_at IS NULL",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Turn the second factor off and forget its secret and recovery codes.
+pub async fn disable_totp(pool: &sqlx::MySqlPool, user_id: i32) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE app_users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = ?",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM totp_recovery_codes WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
@@ -7,18 +7,81 @@ use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 use crate::db::ChatMessage;
+use crate::permissions::Role;
 
-#[derive(Debug, FromRow, Serialize, Deserialize)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct User {
     pub id: i32,          // maps to INT
     pub username: String, // maps to VARCHAR
     #[serde(skip_serializing)]
     pub password_hash: String, // maps to VARCHAR
     pub created_at: DateTime<Utc>, // maps to TIMESTAMP
+    pub role: Role, // maps to ENUM
 }
 
-pub async fn get_chat_history(
+/// A login as listed to its user. The token itself is never sent back.
+#[derive(Debug, FromRow, Serialize)]
+pub struct SessionInfo {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Who a session belongs to, as `sessions::SessionStore` keeps it.
+#[derive(Debug, Clone, FromRow)]
+pub struct StoredSession {
+    pub user_id: i32,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    /// Slides forward as the session is used, see `SessionStore::touch`
+    pub expires_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Messages of a room older than `before` (or the latest ones when `None`), newest first.
+pub async fn get_chat_history_before(
+    pool: &sqlx::MySqlPool,
+    room: &str,
+    before: Option<i64>,
+    limit: i32,
+) -> Result<Vec<ChatMessage>, sqlx::Error> {
+    sqlx::query_as!(
+        ChatMessage,
+        r#"
+        SELECT 
+            m.id as message_id,
+            m.user_id,
+            u.username, 
+            m.content, 
+            m.created_at,
+            m.edited_at,
+            m.deleted_at
+        FROM messages m
+        JOIN app_users u ON m.user_id = u.id
+        JOIN rooms r ON m.room_id = r.id
+        WHERE r.name = ? AND (? IS NULL OR m.id < ?)
+        ORDER BY m.id DESC
+        LIMIT ?
+        "#,
+        room,
+        before,
+        before,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// The oldest messages of a room newer than `after`, oldest first so nothing is skipped.
+pub async fn get_chat_history_after(
     pool: &sqlx::MySqlPool,
+    room: &str,
+    after: i64,
     limit: i32,
 ) -> Result<Vec<ChatMessage>, sqlx::Error> {
     sqlx::query_as!(
@@ -29,12 +92,18 @@ pub async fn get_chat_history(
             m.user_id,
             u.username, 
             m.content, 
-            m.created_at
+            m.created_at,
+            m.edited_at,
+            m.deleted_at
         FROM messages m
         JOIN app_users u ON m.user_id = u.id
-        ORDER BY m.created_at ASC
+        JOIN rooms r ON m.room_id = r.id
+        WHERE r.name = ? AND m.id > ?
+        ORDER BY m.id ASC
         LIMIT ?
         "#,
+        room,
+        after,
         limit
     )
     .fetch_all(pool)
@@ -47,13 +116,68 @@ pub async fn find_user_by_username(
 ) -> Result<User, sqlx::Error> {
     sqlx::query_as!(
         User,
-        "SELECT id, username, password_hash, created_at FROM app_users WHERE username = ?",
+        "SELECT id, username, password_hash, created_at, role AS `role: Role` FROM app_users WHERE username = ?",
         name
     )
     .fetch_one(pool)
     .await
 }
 
+pub async fn get_user_by_id(
+    pool: &sqlx::MySqlPool,
+    id: i32,
+) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        "SELECT id, username, password_hash, created_at, role AS `role: Role` FROM app_users WHERE id = ?",
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// The user whose name looks like `username`, see `validation::skeleton`.
+pub async fn find_user_by_skeleton(
+    pool: &sqlx::MySqlPool,
+    username: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    let skeleton = crate::validation::skeleton(username);
+    sqlx::query_as!(
+        User,
+        "SELECT id, username, password_hash, created_at, role AS `role: Role` FROM app_users WHERE username_skeleton = ?",
+        skeleton
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Fill in `username_skeleton` for users from before it existed. Users whose skeleton
+/// another one already has are left out. Returns how many were filled in.
+pub async fn backfill_username_skeletons(pool: &sqlx::MySqlPool) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query!("SELECT id, username FROM app_users WHERE username_skeleton IS NULL")
+        .fetch_all(pool)
+        .await?;
+    let mut filled = 0;
+    for row in rows {
+        let skeleton = crate::validation::skeleton(&row.username);
+        match sqlx::query!(
+            "UPDATE app_users SET username_skeleton = ? WHERE id = ?",
+            skeleton,
+            row.id
+        )
+        .execute(pool)
+        .await
+        {
+            Ok(_) => filled += 1,
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                println!("Username '{}' looks like another user's, not reserving its skeleton", row.username);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 pub fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(OsRng);
     let argon2 = Argon2::default();
@@ -82,14 +206,16 @@ pub async fn create_user(
 ) -> Result<u64, sqlx::Error> {
     // 1. Hash the password using the function we talked about earlier
     let hashed_password = hash_password(raw_password);
+    let skeleton = crate::validation::skeleton(username);
 
     // 2. Insert into the database
     let result = sqlx::query!(
         r#"
-        INSERT INTO app_users (username, password_hash)
-        VALUES (?, ?)
+        INSERT INTO app_users (username, username_skeleton, password_hash)
+        VALUES (?, ?, ?)
         "#,
         username,
+        skeleton,
         hashed_password
     )
     .execute(pool)
@@ -99,49 +225,308 @@ pub async fn create_user(
     Ok(result.rows_affected())
 }
 
-pub async fn save_message(
+pub async fn update_password(
     pool: &sqlx::MySqlPool,
     user_id: i32,
-    content: &str,
+    raw_password: &str,
 ) -> Result<(), sqlx::Error> {
+    let hashed_password = hash_password(raw_password);
     sqlx::query!(
-        "INSERT INTO messages (user_id, content) VALUES (?, ?)",
-        user_id,
-        content
+        "UPDATE app_users SET password_hash = ? WHERE id = ?",
+        hashed_password,
+        user_id
     )
     .execute(pool)
     .await?;
     Ok(())
 }
 
-pub async fn create_session(
+/// Rename a user. Fails with a unique violation if the name, or a lookalike, is taken.
+pub async fn rename_user(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    new_username: &str,
+) -> Result<(), sqlx::Error> {
+    let skeleton = crate::validation::skeleton(new_username);
+    sqlx::query!(
+        "UPDATE app_users SET username = ?, username_skeleton = ? WHERE id = ?",
+        new_username,
+        skeleton,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Delete a user along with everything that references them, see the foreign keys.
+pub async fn delete_user(
     pool: &sqlx::MySqlPool,
     user_id: i32,
-) -> Result<String, sqlx::Error> {
-    let token = uuid::Uuid::new_v4().to_string();
-    let expires_at = Utc::now() + chrono::Duration::days(7);
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM app_users WHERE id = ?",
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Store a broadcast message sent at `created_at`. Returns its id.
+pub async fn save_message(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    room_id: i32,
+    content: &str,
+    created_at: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query!(
+        "INSERT INTO messages (user_id, room_id, content, created_at) VALUES (?, ?, ?, ?)",
+        user_id,
+        room_id,
+        content,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_id() as i64)
+}
 
+/// Give `username` a new role.
+/// Returns the user's id, `None` if there's no such user.
+pub async fn set_role(
+    pool: &sqlx::MySqlPool,
+    username: &str,
+    role: Role,
+) -> Result<Option<i32>, sqlx::Error> {
+    let user = match find_user_by_username(pool, username).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
     sqlx::query!(
-        "INSERT INTO sessions (token, user_id, expires_at) VALUES (?, ?, ?)",
+        "UPDATE app_users SET role = ? WHERE id = ?",
+        role,
+        user.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(Some(user.id))
+}
+
+/// Store a session, its token comes from `SessionTokens::issue`.
+pub async fn create_session(
+    pool: &sqlx::MySqlPool,
+    token: &str,
+    session: &StoredSession,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    // Fits the column, user agents can be arbitrarily long
+    let user_agent = user_agent.map(|ua| ua.chars().take(255).collect::<String>());
+
+    sqlx::query!(
+        r#"
+        INSERT INTO sessions (token, user_id, created_at, expires_at, last_seen, user_agent, ip)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
         token,
+        session.user_id,
+        session.created_at,
+        session.expires_at,
+        session.last_seen,
+        user_agent,
+        ip
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Replace the session of `old_token` with `session` under `new_token`, like `delete_session`
+/// for the old one. Returns `false` if `old_token` was already gone.
+pub async fn replace_session(
+    pool: &sqlx::MySqlPool,
+    old_token: &str,
+    new_token: &str,
+    session: &StoredSession,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let user_agent = user_agent.map(|ua| ua.chars().take(255).collect::<String>());
+    let mut tx = pool.begin().await?;
+    let old = sqlx::query!(
+        "SELECT expires_at FROM sessions WHERE token = ? FOR UPDATE",
+        old_token
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(old) = old else {
+        return Ok(false);
+    };
+    remember_revoked(&mut tx, old_token, old.expires_at).await?;
+    sqlx::query!(
+        "DELETE FROM sessions WHERE token = ?",
+        old_token
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO sessions (token, user_id, created_at, expires_at, last_seen, user_agent, ip)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        new_token,
+        session.user_id,
+        session.created_at,
+        session.expires_at,
+        session.last_seen,
+        user_agent,
+        ip
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Delete every session of a user. Returns their tokens, for dropping them from caches.
+pub async fn delete_user_sessions(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query!(
+        "SELECT token, expires_at FROM sessions WHERE user_id = ? FOR UPDATE",
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for row in &rows {
+        remember_revoked(&mut tx, &row.token, row.expires_at).await?;
+    }
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ?",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(rows.into_iter().map(|r| r.token).collect())
+}
+
+/// Sessions of a user that haven't expired, most recently used first.
+pub async fn list_sessions(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        SessionInfo,
+        r#"
+        SELECT id, token, created_at AS `created_at!`, expires_at, last_seen, user_agent, ip
+        FROM sessions
+        WHERE user_id = ? AND expires_at > ?
+        ORDER BY COALESCE(last_seen, created_at) DESC
+        "#,
         user_id,
-        expires_at
+        Utc::now()
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Record that a session was just used, extending it to `expires_at`. Expired sessions stay expired.
+/// Returns false if the session is gone or expired.
+pub async fn touch_session(
+    pool: &sqlx::MySqlPool,
+    token: &str,
+    last_seen: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE sessions SET last_seen = ?, expires_at = ? WHERE token = ? AND expires_at > ?",
+        last_seen,
+        expires_at,
+        token,
+        last_seen
     )
     .execute(pool)
     .await?;
+    Ok(result.rows_affected() == 1)
+}
 
-    Ok(token)
+/// Delete one session of a user by id. Returns its token, `None` if they have no such session.
+pub async fn delete_session_by_id(
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    id: i64,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "SELECT token, expires_at FROM sessions WHERE id = ? AND user_id = ? FOR UPDATE",
+        id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    remember_revoked(&mut tx, &row.token, row.expires_at).await?;
+    sqlx::query!(
+        "DELETE FROM sessions WHERE id = ?",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(row.token))
 }
 
+/// Delete a session, remembering its token in `revoked_sessions` like every other way of ending one.
 pub async fn delete_session(
     pool: &sqlx::MySqlPool,
     token: &str,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "SELECT expires_at FROM sessions WHERE token = ? FOR UPDATE",
+        token
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(row) = row {
+        remember_revoked(&mut tx, token, row.expires_at).await?;
+    }
     sqlx::query!(
         "DELETE FROM sessions WHERE token = ?",
         token
     )
-    .execute(pool)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Keep a deleted session's token in `revoked_sessions` for as long as it could still be accepted:
+/// a signed token's absolute expiry, which outlasts its session's sliding `expires_at`.
+async fn remember_revoked(
+    conn: &mut sqlx::MySqlConnection,
+    token: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let until = crate::session_tokens::expires_at(token).map_or(expires_at, |end| end.max(expires_at));
+    sqlx::query!(
+        "INSERT IGNORE INTO revoked_sessions (token, expires_at) VALUES (?, ?)",
+        token,
+        until
+    )
+    .execute(conn)
     .await?;
     Ok(())
 }
@@ -164,26 +549,70 @@ pub async fn _confirm_user_id(
 pub async fn cleanup_expired_sessions(
     pool: &sqlx::MySqlPool,
 ) -> Result<u64, sqlx::Error> {
+    let now = Utc::now();
     let result = sqlx::query!(
         "DELETE FROM sessions WHERE expires_at < ?",
-        Utc::now()
+        now
+    )
+    .execute(pool)
+    .await?;
+    // Expired tokens are refused anyway
+    sqlx::query!(
+        "DELETE FROM revoked_sessions WHERE expires_at < ?",
+        now
     )
     .execute(pool)
     .await?;
     Ok(result.rows_affected())
 }
 
+/// Tokens of sessions ended before they expired, with their expiry.
+pub async fn get_revoked_sessions(
+    pool: &sqlx::MySqlPool,
+) -> Result<Vec<(String, DateTime<Utc>)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT token, expires_at FROM revoked_sessions WHERE expires_at > ?",
+        Utc::now()
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|r| (r.token, r.expires_at)).collect())
+}
+
+/// Token, user id and current username of every unexpired session.
 pub async fn get_all_valid_sessions(
     pool: &sqlx::MySqlPool,
-) -> Result<Vec<String>, sqlx::Error> {
+) -> Result<Vec<(String, i32, String)>, sqlx::Error> {
     let rows = sqlx::query!(
-        "SELECT token FROM sessions WHERE expires_at > ?",
+        "SELECT s.token, s.user_id, u.username FROM sessions s JOIN app_users u ON u.id = s.user_id WHERE s.expires_at > ?",
         Utc::now()
     )
     .fetch_all(pool)
     .await?;
 
-    Ok(rows.into_iter().map(|r| r.token).collect())
+    Ok(rows.into_iter().map(|r| (r.token, r.user_id, r.username)).collect())
+}
+
+/// Who a session belongs to, if it hasn't expired.
+pub async fn get_session(
+    pool: &sqlx::MySqlPool,
+    token: &str,
+) -> Result<StoredSession, sqlx::Error> {
+    sqlx::query_as!(
+        StoredSession,
+        r#"
+        SELECT s.user_id, u.username, s.created_at AS `created_at!`, s.expires_at,
+            COALESCE(s.last_seen, s.created_at) AS `last_seen!: DateTime<Utc>`
+        FROM sessions s
+        JOIN app_users u ON u.id = s.user_id
+        WHERE s.token = ? AND s.expires_at > ?
+        "#,
+        token,
+        Utc::now()
+    )
+    .fetch_one(pool)
+    .await
 }
 
 pub async fn get_user_by_token(
@@ -193,7 +622,7 @@ pub async fn get_user_by_token(
     sqlx::query_as!(
         User,
         r#"
-        SELECT u.id, u.username, u.password_hash, u.created_at
+        SELECT u.id, u.username, u.password_hash, u.created_at, u.role AS `role: Role`
         FROM app_users u
         JOIN sessions s ON u.id = s.user_id
         WHERE s.token = ? AND s.expires_at > ?
@@ -210,18 +639,17 @@ pub async fn get_usernames_by_ids(
     pool: &sqlx::MySqlPool,
     ids: &[i32],
 ) -> Result<Vec<String>, sqlx::Error> {
-    let mut usernames = Vec::new();
-    for &uid in ids {
-        if let Ok(row) = sqlx::query_as!(
-            User,
-            "SELECT id, username, password_hash, created_at FROM app_users WHERE id = ?",
-            uid
-        )
-        .fetch_one(pool)
-        .await
-        {
-            usernames.push(row.username);
-        }
+    if ids.is_empty() {
+        return Ok(Vec::new());
     }
-    Ok(usernames)
+
+    // The id list has a runtime length, so this one can't be a checked query!
+    let mut query = sqlx::QueryBuilder::<sqlx::MySql>::new("SELECT username FROM app_users WHERE id IN (");
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    separated.push_unseparated(") ORDER BY username");
+
+    query.build_query_scalar().fetch_all(pool).await
 }
\ No newline at end of file
@@ -0,0 +1,366 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::db::secrets::get_secret;
+use crate::tables::totp_db;
+use crate::tables::user_db::{self, User};
+
+/// RFC 6238 defaults, what every authenticator app expects.
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after now still count, for clocks that drift.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// How long after the password a login can send its code.
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    /// 6 digit code from the authenticator, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpLoginRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+/// What `/api/login` answers instead of a session when the account has a second factor.
+#[derive(Debug, Serialize)]
+pub struct TotpChallenge {
+    pub message: String,
+    /// Send back to `/api/login/totp` with a code
+    pub challenge_token: String,
+    pub expires_in_secs: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32, for typing into the app by hand
+    pub secret: String,
+    /// For a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    /// Shown this once, each works a single time
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+    AlreadyEnabled,
+    NotEnrolled,
+    NotEnabled,
+    WrongCode,
+    WrongPassword,
+    Database,
+}
+
+impl TotpError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            TotpError::AlreadyEnabled => "Two-factor authentication is already on, disable it first",
+            TotpError::NotEnrolled => "Start enrolling with POST /api/me/totp first",
+            TotpError::NotEnabled => "Two-factor authentication is not on",
+            TotpError::WrongCode => "Wrong or already used code",
+            TotpError::WrongPassword => "Wrong password",
+            TotpError::Database => "Database is not online, please try again later",
+        }
+    }
+}
+
+/// Issuer shown in authenticator apps and the key login challenges are signed with.
+#[derive(Clone)]
+pub struct Totp {
+    issuer: Arc<String>,
+    /// Signs challenges so any instance can check them without remembering them
+    challenge_key: Arc<Vec<u8>>,
+    /// Challenges already exchanged for a session here, with their expiry (unix seconds)
+    redeemed: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl Totp {
+    /// - `TOTP_ISSUER` names the service in authenticator apps, `ws-global-chat` by default
+    /// - `TOTP_CHALLENGE_KEY_NAME` names a secret to sign login challenges with. Needed with several
+    ///   instances, otherwise a challenge only works on the instance that issued it
+    pub fn from_env() -> Self {
+        let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "ws-global-chat".to_string());
+        let challenge_key = match env::var("TOTP_CHALLENGE_KEY_NAME") {
+            Ok(name) => get_secret(&name).into_bytes(),
+            Err(_) => uuid::Uuid::new_v4().as_bytes().to_vec(),
+        };
+        Totp {
+            issuer: Arc::new(issuer),
+            challenge_key: Arc::new(challenge_key),
+            redeemed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Challenge for a user whose password checked out: `<user_id>.<expires_at>.<random>.<signature>`
+    pub fn issue_challenge(&self, user_id: i32) -> TotpChallenge {
+        let expires_at = chrono::Utc::now().timestamp() + CHALLENGE_TTL_SECS;
+        let body = format!("{}.{}.{}", user_id, expires_at, uuid::Uuid::new_v4().simple());
+        TotpChallenge {
+            message: "Enter the code from your authenticator app".to_string(),
+            challenge_token: format!("{}.{}", body, hex::encode(self.sign(&body))),
+            expires_in_secs: CHALLENGE_TTL_SECS,
+        }
+    }
+
+    /// The user a challenge was issued to, if it's genuine, unexpired and unused.
+    pub async fn check_challenge(&self, token: &str) -> Option<i32> {
+        let (body, signature) = token.rsplit_once('.')?;
+        let signature = hex::decode(signature).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.challenge_key).ok()?;
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        let mut parts = body.split('.');
+        let user_id = parts.next()?.parse().ok()?;
+        let expires_at: i64 = parts.next()?.parse().ok()?;
+        let now = chrono::Utc::now().timestamp();
+        if expires_at < now {
+            return None;
+        }
+        let mut redeemed = self.redeemed.lock().await;
+        redeemed.retain(|_, expiry| *expiry >= now);
+        if redeemed.contains_key(token) {
+            return None;
+        }
+        Some(user_id)
+    }
+
+    /// Use up a challenge once it got its user logged in.
+    pub async fn redeem_challenge(&self, token: &str) {
+        let expires_at = chrono::Utc::now().timestamp() + CHALLENGE_TTL_SECS;
+        self.redeemed.lock().await.insert(token.to_string(), expires_at);
+    }
+
+    fn sign(&self, body: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.challenge_key).expect("HMAC takes keys of any length");
+        mac.update(body.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Start enrolling: a new secret, replacing any unconfirmed one.
+    pub async fn enroll(&self, pool: &sqlx::MySqlPool, user: &User) -> Result<TotpEnrollment, TotpError> {
+        let state = totp_db::get_totp(pool, user.id).await.map_err(|_| TotpError::Database)?;
+        if state.enabled_secret().is_some() {
+            return Err(TotpError::AlreadyEnabled);
+        }
+        // 160 bits, the size RFC 4226 recommends
+        let random = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let secret = BASE32_NOPAD.encode(&Sha256::digest(random.as_bytes())[..20]);
+        totp_db::set_pending_secret(pool, user.id, &secret)
+            .await
+            .map_err(|_| TotpError::Database)?;
+
+        let otpauth_uri = format!(
+            "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+            issuer = encode_component(&self.issuer),
+            user = encode_component(&user.username),
+            secret = secret,
+        );
+        Ok(TotpEnrollment { secret, otpauth_uri })
+    }
+}
+
+/// Turn the second factor on with a first code from the app, handing out recovery codes.
+pub async fn confirm(pool: &sqlx::MySqlPool, user: &User, code: &str) -> Result<RecoveryCodes, TotpError> {
+    let state = totp_db::get_totp(pool, user.id).await.map_err(|_| TotpError::Database)?;
+    if state.totp_enabled_at.is_some() {
+        return Err(TotpError::AlreadyEnabled);
+    }
+    let Some(secret) = state.totp_secret.as_deref() else {
+        return Err(TotpError::NotEnrolled);
+    };
+    let step = matching_step(secret, code).ok_or(TotpError::WrongCode)?;
+    if !totp_db::claim_step(pool, user.id, step).await.map_err(|_| TotpError::Database)? {
+        return Err(TotpError::WrongCode);
+    }
+    let (codes, hashes) = new_recovery_codes();
+    totp_db::enable_totp(pool, user.id, &hashes)
+        .await
+        .map_err(|_| TotpError::Database)?;
+    println!("User {} turned on two-factor authentication", user.username);
+    Ok(RecoveryCodes { recovery_codes: codes })
+}
+
+/// New recovery codes replacing the old ones, for a code from the app.
+pub async fn regenerate_recovery_codes(
+    pool: &sqlx::MySqlPool,
+    user: &User,
+    code: &str,
+) -> Result<RecoveryCodes, TotpError> {
+    if !verify(pool, user.id, code).await? {
+        return Err(TotpError::WrongCode);
+    }
+    let (codes, hashes) = new_recovery_codes();
+    totp_db::replace_recovery_codes(pool, user.id, &hashes)
+        .await
+        .map_err(|_| TotpError::Database)?;
+    Ok(RecoveryCodes { recovery_codes: codes })
+}
+
+/// Turn the second factor off, which takes both the password and a code.
+pub async fn disable(pool: &sqlx::MySqlPool, user: &User, request: &DisableTotpRequest) -> Result<(), TotpError> {
+    if user_db::verify_password(&request.password, &user.password_hash).is_err() {
+        return Err(TotpError::WrongPassword);
+    }
+    if !verify(pool, user.id, &request.code).await? {
+        return Err(TotpError::WrongCode);
+    }
+    totp_db::disable_totp(pool, user.id)
+        .await
+        .map_err(|_| TotpError::Database)?;
+    println!("User {} turned off two-factor authentication", user.username);
+    Ok(())
+}
+
+pub async fn status(pool: &sqlx::MySqlPool, user_id: i32) -> Result<TotpStatus, TotpError> {
+    let state = totp_db::get_totp(pool, user_id).await.map_err(|_| TotpError::Database)?;
+    let recovery_codes_left = totp_db::count_unused_recovery_codes(pool, user_id)
+        .await
+        .map_err(|_| TotpError::Database)?;
+    Ok(TotpStatus {
+        enabled: state.enabled_secret().is_some(),
+        recovery_codes_left,
+    })
+}
+
+/// Whether the user has a second factor to ask for at login.
+pub async fn is_enabled(pool: &sqlx::MySqlPool, user_id: i32) -> Result<bool, sqlx::Error> {
+    Ok(totp_db::get_totp(pool, user_id).await?.enabled_secret().is_some())
+}
+
+/// Check a code from the app, or use up a recovery code. Each code only works once.
+pub async fn verify(pool: &sqlx::MySqlPool, user_id: i32, code: &str) -> Result<bool, TotpError> {
+    let state = totp_db::get_totp(pool, user_id).await.map_err(|_| TotpError::Database)?;
+    let Some(secret) = state.enabled_secret() else {
+        return Err(TotpError::NotEnabled);
+    };
+    let code = code.trim();
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        return match matching_step(secret, code) {
+            Some(step) => totp_db::claim_step(pool, user_id, step).await.map_err(|_| TotpError::Database),
+            None => Ok(false),
+        };
+    }
+    totp_db::use_recovery_code(pool, user_id, &hash_recovery_code(code))
+        .await
+        .map_err(|_| TotpError::Database)
+}
+
+/// The time step around now that `code` is right for.
+fn matching_step(secret: &str, code: &str) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.trim().parse().ok()?;
+    let now = chrono::Utc::now().timestamp() / STEP_SECS;
+    (now - SKEW_STEPS..=now + SKEW_STEPS).find(|step| code_at(&key, *step) == Some(code))
+}
+
+/// HOTP (RFC 4226) of a time step.
+fn code_at(key: &[u8], step: i64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes(hash[offset..offset + 4].try_into().ok()?) & 0x7fff_ffff;
+    Some(bin % 10u32.pow(DIGITS))
+}
+
+/// Codes to show once, and the hashes to store.
+fn new_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let random = uuid::Uuid::new_v4().simple().to_string();
+            format!("{}-{}-{}", &random[..4], &random[4..8], &random[8..12])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+/// Dashes, spaces and case don't matter when typing a recovery code.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Percent-encode everything but unreserved characters, for the otpauth label and issuer.
+fn encode_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 seed of RFC 6238's test vectors.
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        // The RFC lists 8 digits, these are their last 6
+        for (time, code) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+        ] {
+            assert_eq!(code_at(RFC_KEY, time / STEP_SECS), Some(code), "at {}", time);
+        }
+    }
+
+    #[test]
+    fn matching_step_accepts_the_current_code_only() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        let now = chrono::Utc::now().timestamp() / STEP_SECS;
+        let code = format!("{:06}", code_at(RFC_KEY, now).unwrap());
+        assert_eq!(matching_step(&secret, &code), Some(now));
+        let stale = format!("{:06}", code_at(RFC_KEY, now - 10).unwrap());
+        assert_ne!(matching_step(&secret, &stale), Some(now - 10));
+        assert_eq!(matching_step(&secret, "not a code"), None);
+    }
+
+    #[test]
+    fn recovery_codes_ignore_dashes_and_case() {
+        assert_eq!(hash_recovery_code("1a2b-3c4d-5e6f"), hash_recovery_code("1A2B 3C4D5E6F"));
+        let (codes, hashes) = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(hashes[0], hash_recovery_code(&codes[0]));
+    }
+}
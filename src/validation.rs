@@ -0,0 +1,265 @@
+use serde::Serialize;
+use std::env;
+use std::sync::Arc;
+
+/// Fits `app_users.username`
+const USERNAME_COLUMN_CHARS: usize = 50;
+/// Argon2 takes longer inputs, but nobody needs them and they make hashing slower.
+const PASSWORD_MAX_CHARS: usize = 128;
+/// Fits `app_users.email`, the longest address SMTP allows.
+const EMAIL_MAX_BYTES: usize = 254;
+/// Names nobody may register, on top of `RESERVED_USERNAMES`. `system` is the sender of server frames.
+const RESERVED: [&str; 10] = [
+    "system", "server", "admin", "administrator", "moderator", "mod", "root", "everyone", "null", "undefined",
+];
+
+/// What's wrong with one field of a request.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    /// Stable, for clients to match on
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: impl Into<String>) -> Self {
+        FieldError { field, code, message: message.into() }
+    }
+}
+
+/// Body of a `422 Unprocessable Entity`.
+#[derive(Debug, Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+struct PolicyConfig {
+    password_min_chars: usize,
+    /// Of lowercase, uppercase, digits and anything else
+    password_min_classes: usize,
+    username_min_chars: usize,
+    username_max_chars: usize,
+    /// Skeletons of the reserved names
+    reserved: Vec<String>,
+}
+
+/// Username and password rules, configured from the environment.
+#[derive(Clone)]
+pub struct Policy {
+    config: Arc<PolicyConfig>,
+}
+
+impl Policy {
+    /// Every setting is optional:
+    /// - `PASSWORD_MIN_LENGTH` (default 8) and `PASSWORD_MIN_CLASSES`, how many of lowercase,
+    ///   uppercase, digits and symbols a password needs (default 2)
+    /// - `USERNAME_MIN_LENGTH` (default 3) and `USERNAME_MAX_LENGTH` (default 32, at most 50)
+    /// - `RESERVED_USERNAMES` comma separated names nobody may take, besides `system`, `admin` and the like
+    pub fn from_env() -> Self {
+        let mut reserved: Vec<String> = RESERVED.iter().map(|name| skeleton(name)).collect();
+        if let Ok(names) = env::var("RESERVED_USERNAMES") {
+            reserved.extend(names.split(',').map(str::trim).filter(|n| !n.is_empty()).map(skeleton));
+        }
+        let username_max_chars = number_from_env("USERNAME_MAX_LENGTH")
+            .unwrap_or(32)
+            .clamp(1, USERNAME_COLUMN_CHARS);
+        let config = PolicyConfig {
+            password_min_chars: number_from_env("PASSWORD_MIN_LENGTH").unwrap_or(8).min(PASSWORD_MAX_CHARS),
+            password_min_classes: number_from_env("PASSWORD_MIN_CLASSES").unwrap_or(2).min(4),
+            username_min_chars: number_from_env("USERNAME_MIN_LENGTH")
+                .unwrap_or(3)
+                .clamp(1, username_max_chars),
+            username_max_chars,
+            reserved,
+        };
+        Policy { config: Arc::new(config) }
+    }
+
+    pub fn username_max_chars(&self) -> usize {
+        self.config.username_max_chars
+    }
+
+    /// Everything wrong with a signup, empty if nothing is.
+    pub fn check_registration(&self, username: &str, password: &str) -> Vec<FieldError> {
+        [self.check_username(username), self.check_password(password, username)]
+            .into_iter()
+            .filter_map(Result::err)
+            .collect()
+    }
+
+    /// Length, charset and reserved names. Lookalikes of existing users need the database,
+    /// see `user_db::find_user_by_skeleton`.
+    pub fn check_username(&self, username: &str) -> Result<(), FieldError> {
+        let length = username.chars().count();
+        let (min, max) = (self.config.username_min_chars, self.config.username_max_chars);
+        if length < min || length > max {
+            let e = format!("Usernames must be {} to {} characters", min, max);
+            return Err(FieldError::new("username", "length", e));
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+            let e = "Usernames may only contain letters a-z, digits, '_', '-' and '.'";
+            return Err(FieldError::new("username", "charset", e));
+        }
+        if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(FieldError::new("username", "charset", "Usernames must start with a letter or digit"));
+        }
+        if self.config.reserved.contains(&skeleton(username)) {
+            return Err(FieldError::new("username", "reserved", format!("'{}' is reserved", username)));
+        }
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str, username: &str) -> Result<(), FieldError> {
+        let length = password.chars().count();
+        if length < self.config.password_min_chars {
+            let e = format!("Passwords must be at least {} characters", self.config.password_min_chars);
+            return Err(FieldError::new("password", "too_short", e));
+        }
+        if length > PASSWORD_MAX_CHARS {
+            let e = format!("Passwords must be at most {} characters", PASSWORD_MAX_CHARS);
+            return Err(FieldError::new("password", "too_long", e));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|has| **has).count() < self.config.password_min_classes {
+            let e = format!(
+                "Passwords need at least {} of: lowercase letters, uppercase letters, digits, symbols",
+                self.config.password_min_classes
+            );
+            return Err(FieldError::new("password", "too_weak", e));
+        }
+        if password.to_lowercase().contains(&username.to_lowercase()) {
+            return Err(FieldError::new("password", "contains_username", "Passwords can't contain the username"));
+        }
+        Ok(())
+    }
+}
+
+/// Roughly an email address: whether it really is one is for the verification mail to find out.
+pub fn check_email(email: &str) -> Result<(), FieldError> {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return Err(FieldError::new("email", "invalid", "Not an email address"));
+    };
+    if email.len() > EMAIL_MAX_BYTES
+        || local.is_empty()
+        || !domain.contains('.')
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+        || email.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(FieldError::new("email", "invalid", "Not an email address"));
+    }
+    Ok(())
+}
+
+/// `username` is free, but too close to `existing`'s name.
+pub fn lookalike(username: &str, existing: &str) -> FieldError {
+    let e = format!("'{}' looks too much like the existing user '{}'", username, existing);
+    FieldError::new("username", "lookalike", e)
+}
+
+/// What a username looks like, stored in `app_users.username_skeleton`: two names with the
+/// same skeleton are too easily mistaken for one another. Lowercased, separators dropped and
+/// the usual lookalikes folded together (`0`/`o`, `1`/`i`/`l`, `rn`/`m`, `vv`/`w`, `5`/`s`).
+pub fn skeleton(username: &str) -> String {
+    let folded: String = username
+        .to_lowercase()
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | '.'))
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '5' => 's',
+            other => other,
+        })
+        .collect();
+    folded.replace("rn", "m").replace("vv", "w")
+}
+
+fn number_from_env(key: &str) -> Option<usize> {
+    let value = env::var(key).ok()?;
+    let number = value.trim().parse().ok();
+    if number.is_none() {
+        println!("Ignoring {}={:?}, expected a number", key, value);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The defaults, without reading the environment.
+    fn policy() -> Policy {
+        let config = PolicyConfig {
+            password_min_chars: 8,
+            password_min_classes: 2,
+            username_min_chars: 3,
+            username_max_chars: 32,
+            reserved: RESERVED.iter().map(|name| skeleton(name)).collect(),
+        };
+        Policy { config: Arc::new(config) }
+    }
+
+    fn code(result: Result<(), FieldError>) -> Option<&'static str> {
+        result.err().map(|e| e.code)
+    }
+
+    #[test]
+    fn usernames() {
+        let policy = policy();
+        assert_eq!(code(policy.check_username("alice_01")), None);
+        assert_eq!(code(policy.check_username("al")), Some("length"));
+        assert_eq!(code(policy.check_username(&"a".repeat(33))), Some("length"));
+        assert_eq!(code(policy.check_username("alice smith")), Some("charset"));
+        assert_eq!(code(policy.check_username("аlice")), Some("charset"));
+        assert_eq!(code(policy.check_username("_alice")), Some("charset"));
+        assert_eq!(code(policy.check_username("Admin")), Some("reserved"));
+        // Lookalikes of reserved names are reserved too
+        assert_eq!(code(policy.check_username("r00t")), Some("reserved"));
+        assert_eq!(code(policy.check_username("sys.tem")), Some("reserved"));
+    }
+
+    #[test]
+    fn passwords() {
+        let policy = policy();
+        assert_eq!(code(policy.check_password("correct horse", "alice")), None);
+        assert_eq!(code(policy.check_password("Short1", "alice")), Some("too_short"));
+        assert_eq!(code(policy.check_password(&"aB1".repeat(43), "alice")), Some("too_long"));
+        assert_eq!(code(policy.check_password("alllowercase", "alice")), Some("too_weak"));
+        assert_eq!(code(policy.check_password("my-ALICE-pass", "alice")), Some("contains_username"));
+    }
+
+    #[test]
+    fn registration_reports_every_field() {
+        let errors = policy().check_registration("x", "weak");
+        let fields: Vec<&str> = errors.iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["username", "password"]);
+        assert!(policy().check_registration("alice", "Password1").is_empty());
+    }
+
+    #[test]
+    fn emails() {
+        assert_eq!(code(check_email("alice@example.com")), None);
+        assert_eq!(code(check_email("alice@localhost")), Some("invalid"));
+        assert_eq!(code(check_email("@example.com")), Some("invalid"));
+        assert_eq!(code(check_email("alice@.example.com")), Some("invalid"));
+        assert_eq!(code(check_email("alice@example.com.")), Some("invalid"));
+        assert_eq!(code(check_email("ali ce@example.com")), Some("invalid"));
+        assert_eq!(code(check_email("alice")), Some("invalid"));
+        assert_eq!(code(check_email(&format!("{}@example.com", "a".repeat(250)))), Some("invalid"));
+    }
+
+    #[test]
+    fn skeletons_fold_lookalikes() {
+        assert_eq!(skeleton("Rn_B0b.1"), skeleton("mbobl"));
+        assert_eq!(skeleton("vvill"), skeleton("w1ll"));
+        assert_eq!(skeleton("S5"), "ss");
+        assert_ne!(skeleton("alice"), skeleton("alicia"));
+    }
+}
@@ -1,99 +1,287 @@
 use futures_util::{SinkExt, StreamExt as _};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 use warp::filters::ws::{Message, WebSocket};
-use std::sync::Arc;
-use std::collections::HashSet;
+use std::collections::HashMap;
 
-use crate::connected_users::{self, ConnectedUsers};
+use crate::cluster::{Cluster, ClusterEvent};
+use crate::connected_users::{self, ClientInfo, ConnHandle, ConnId, ConnectedUsers, Outbox};
+use crate::permissions::{Permission, Role};
+use crate::presence::{self, Presence, Status};
+use crate::api_keys::ApiKeys;
+use crate::auth::{self, Identity, Principal};
+use crate::rate_limit::{ConnBuckets, RateLimiter};
+use crate::sessions::{self, SessionStore};
+use crate::rooms::{self, Room, Rooms};
+use crate::moderation::{self, SanctionRequest};
+use crate::tables::sanction_db::SanctionKind;
 use crate::ws_types::*;
 
+/// A client may announce typing at most this often, extra frames are dropped.
+const TYPING_MIN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How often a busy socket refreshes its session's `last_seen`.
+const LAST_SEEN_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(sessions::LAST_SEEN_RESOLUTION_MINS as u64 * 60);
+/// How long a typing indicator lasts unless the typist sends another one.
+const TYPING_TTL_SECS: i64 = 6;
+/// A client that takes longer than this to accept a frame is too slow and gets dropped.
+const WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Shared server state every socket works with.
+#[derive(Clone)]
+pub struct ChatState {
+    pub pool: sqlx::MySqlPool,
+    pub rooms: Rooms,
+    pub session_store: SessionStore,
+    pub connected: ConnectedUsers,
+    pub cluster: Cluster,
+    pub presence: Presence,
+    pub rate_limiter: RateLimiter,
+    pub api_keys: ApiKeys,
+}
+
+/// `session` is the token and who it authenticated as, if the upgrade request carried one.
 pub async fn handle_connection(
-    pool: sqlx::MySqlPool,
     ws: WebSocket,
-    tx: broadcast::Sender<String>,
-    session_cache: Arc<RwLock<HashSet<String>>>,
-    connected: ConnectedUsers,
+    state: ChatState,
+    client: ClientInfo,
+    session: Option<(String, Principal)>,
 ) {
+    let ChatState {
+        pool,
+        rooms,
+        connected,
+        cluster,
+        presence,
+        rate_limiter,
+        ..
+    } = state.clone();
+    let opened_at = chrono::Utc::now();
     let (mut ws_sender, mut ws_receiver) = ws.split();
 
-    // Per-connection channel for direct messages (private, error, who probes, etc.)
-    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<Message>();
-
-    // Broadcast listener
-    let mut broadcast_rx = tx.subscribe();
+    // Per-connection channel for everything this socket receives: room traffic is
+    // forwarded into it by one task per joined room, direct messages (private, error,
+    // who probes, etc.) are sent into it straight away. A client that falls
+    // `OUTBOX_CAPACITY` frames behind is disconnected
+    let (direct_tx, mut direct_rx) = Outbox::new();
+    let mut conn = ConnState {
+        direct_tx: direct_tx.clone(),
+        joined_rooms: HashMap::new(),
+        last_typing: None,
+        buckets: ConnBuckets::new(),
+        last_touch: None,
+    };
 
-    // Forward both broadcast and direct messages to the WS sender
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                Ok(msg) = broadcast_rx.recv() => {
-                    if ws_sender.send(Message::text(msg)).await.is_err() {
-                        break;
-                    }
-                }
-                Some(msg) = direct_rx.recv() => {
-                    if ws_sender.send(msg).await.is_err() {
-                        break;
-                    }
-                }
+    let mut writer = tokio::spawn(async move {
+        while let Some(msg) = direct_rx.recv().await {
+            // A close frame comes from `connected_users::close_connection`
+            let closing = msg.is_close();
+            let sent = tokio::time::timeout(WRITE_TIMEOUT, ws_sender.send(msg)).await;
+            if !matches!(sent, Ok(Ok(()))) || closing {
+                break;
             }
         }
     });
 
+    // Every socket starts in the default room
+    if let Some(room) = rooms::get(&rooms, rooms::DEFAULT_ROOM).await {
+        conn.joined_rooms.insert(
+            rooms::DEFAULT_ROOM.to_string(),
+            forward_room(&room, direct_tx.clone()),
+        );
+    }
+
     // Track this connection's authenticated state
     let mut authenticated_user_id: Option<i32> = None;
     let mut authenticated_username: Option<String> = None;
-    let mut sender_index: Option<usize> = None;
+    let mut conn_id: Option<ConnId> = None;
+
+    // Authenticated at upgrade: online straight away, and every message uses that session
+    let upgrade_token = match session {
+        Some((token, principal)) => {
+            let label = principal.connection_label(&token);
+            conn_id = Some(
+                register_connection(&state, &conn, opened_at, &client, principal.user.id, &principal.user.username, &label).await,
+            );
+            authenticated_user_id = Some(principal.user.id);
+            authenticated_username = Some(principal.user.username);
+            Some(token)
+        }
+        None => None,
+    };
 
-    while let Some(result) = ws_receiver.next().await {
+    loop {
+        let result = tokio::select! {
+            next = ws_receiver.next() => match next {
+                Some(result) => result,
+                None => break,
+            },
+            // Kicked, banned, logged out or too slow to read, see `Outbox`
+            _ = conn.direct_tx.closed() => break,
+        };
         match result {
             Ok(message) => {
                 if let Ok(text) = message.to_str() {
                     if let Ok(ws_msg) = serde_json::from_str::<WsIncoming>(text) {
+                        // ── Flood control, before anything costs a lookup ──
+                        if let Err(wait) = rate_limiter.check_conn(&mut conn.buckets, ws_msg.msg_type) {
+                            send_rate_limited(&direct_tx, ws_msg.msg_type, wait, ws_msg.metadata.client_msg_id.clone());
+                            if let Some(user_id) = authenticated_user_id {
+                                let mute = rate_limiter.strike(user_id).await;
+                                if let Some(secs) = mute {
+                                    moderation::auto_mute(&state, user_id, secs).await;
+                                }
+                            }
+                            continue;
+                        }
+
                         // ── Authenticate ──
-                        let (user_id, username) =
-                            match resolve_session(&pool, &session_cache, &ws_msg.metadata.session_id).await {
-                                Some((uid, uname)) => {
+                        let Some(token) = upgrade_token.as_deref().or(ws_msg.metadata.session_id.as_deref()) else {
+                            send_error(
+                                &direct_tx,
+                                "Not authenticated, connect with a session or set 'session_id' in metadata",
+                                ws_msg.metadata.client_msg_id.clone(),
+                            );
+                            continue;
+                        };
+                        // Checked on every message so revocations apply straight away,
+                        // signed session tokens without a trip to the database
+                        let (user_id, username, key) =
+                            match auth::identify(&state, token).await {
+                                Some(identity) => {
                                     // Register in connected users on first auth
                                     if authenticated_user_id.is_none() {
-                                        let idx = connected_users::register(
-                                            &connected,
-                                            uid,
-                                            direct_tx.clone(),
-                                        )
-                                        .await;
-                                        authenticated_user_id = Some(uid);
-                                        authenticated_username = Some(uname.clone());
-                                        sender_index = Some(idx);
+                                        let label = identity.connection_label(token);
+                                        conn_id = Some(
+                                            register_connection(&state, &conn, opened_at, &client, identity.user_id, &identity.username, &label).await,
+                                        );
+                                        authenticated_user_id = Some(identity.user_id);
+                                        authenticated_username = Some(identity.username.clone());
+                                    }
+                                    let Identity { user_id, username, key } = identity;
+                                    // Keys keep their own `last_used_at`
+                                    if key.is_none() && conn.last_touch.is_none_or(|t| t.elapsed() >= LAST_SEEN_INTERVAL) {
+                                        state.session_store.touch(&pool, token).await;
+                                        conn.last_touch = Some(std::time::Instant::now());
                                     }
-                                    (uid, uname)
+                                    (user_id, username, key)
                                 }
                                 None => {
-                                    send_error(&direct_tx, "Invalid or expired session");
+                                    send_error(
+                                        &direct_tx,
+                                        "Invalid or expired session",
+                                        ws_msg.metadata.client_msg_id.clone(),
+                                    );
                                     continue;
                                 }
                             };
 
                         // ── Route by message type ──
-                        match ws_msg.msg_type {
-                            MessageType::Broadcast => {
-                                handle_broadcast(&pool, &tx, user_id, &username, &ws_msg.content).await;
+                        let client_msg_id = ws_msg.metadata.client_msg_id.clone();
+
+                        // ── Per user flood control ──
+                        let max_length = rate_limiter.max_content_length();
+                        if ws_msg.content.chars().count() > max_length {
+                            let e = format!("'content' is limited to {} characters", max_length);
+                            send_error(&direct_tx, &e, client_msg_id);
+                            continue;
+                        }
+                        if let Err(wait) = rate_limiter.check_user(user_id, ws_msg.msg_type).await {
+                            send_rate_limited(&direct_tx, ws_msg.msg_type, wait, client_msg_id);
+                            if let Some(secs) = rate_limiter.strike(user_id).await {
+                                moderation::auto_mute(&state, user_id, secs).await;
                             }
-                            MessageType::Private => {
-                                handle_private(
-                                    &pool,
-                                    &connected,
-                                    &direct_tx,
-                                    user_id,
-                                    &username,
-                                    &ws_msg,
-                                )
-                                .await;
+                            continue;
+                        }
+                        if let Some(key) = &key {
+                            if !key.allows_message(ws_msg.msg_type) {
+                                let e = format!("This API key can't send '{}' messages", ws_msg.msg_type.as_str());
+                                send_error(&direct_tx, &e, client_msg_id);
+                                continue;
                             }
-                            MessageType::Ephemeral => {
-                                handle_ephemeral(&tx, &username, &ws_msg.content, ws_msg.extra);
+                            if let Err(wait) = rate_limiter.check_api_key(key.key_id, key.rate_limit).await {
+                                send_rate_limited(&direct_tx, ws_msg.msg_type, wait, client_msg_id);
+                                continue;
                             }
                         }
+
+                        let checked = match validate_metadata(&ws_msg) {
+                            Ok(()) if ws_msg.msg_type.is_content() => {
+                                check_not_muted(&state, user_id).await
+                            }
+                            other => other,
+                        };
+                        let result = match checked {
+                            Err(e) => Err(e),
+                            Ok(()) => match ws_msg.msg_type {
+                                MessageType::Broadcast => match joined_room(&rooms, &conn, &ws_msg).await {
+                                    Ok(room) => handle_broadcast(&pool, &cluster, &room, user_id, &username, &ws_msg).await,
+                                    Err(e) => Err(e),
+                                },
+                                MessageType::Private => {
+                                    handle_private(
+                                        &pool,
+                                        &connected,
+                                        &cluster,
+                                        &direct_tx,
+                                        user_id,
+                                        &username,
+                                        &ws_msg,
+                                    )
+                                    .await
+                                }
+                                MessageType::Ephemeral => match joined_room(&rooms, &conn, &ws_msg).await {
+                                    Ok(room) => handle_ephemeral(&cluster, &room, &username, &ws_msg),
+                                    Err(e) => Err(e),
+                                },
+                                MessageType::Join => {
+                                    handle_join(
+                                        &pool,
+                                        &cluster,
+                                        &rooms,
+                                        &mut conn,
+                                        user_id,
+                                        &username,
+                                        &ws_msg,
+                                    )
+                                    .await
+                                }
+                                MessageType::Leave => {
+                                    handle_leave(&cluster, &rooms, &mut conn, &username, &ws_msg).await
+                                }
+                                MessageType::Edit | MessageType::Delete => match current_role(&state, user_id).await {
+                                    Ok(role) => handle_edit(&pool, &cluster, &rooms, user_id, &username, role, &ws_msg).await,
+                                    Err(e) => Err(e),
+                                },
+                                MessageType::Reaction => {
+                                    handle_reaction(&pool, &cluster, &rooms, user_id, &username, &ws_msg).await
+                                }
+                                MessageType::Typing => match joined_room(&rooms, &conn, &ws_msg).await {
+                                    Ok(room) => handle_typing(&cluster, &room, &mut conn, &username, &ws_msg),
+                                    Err(e) => Err(e),
+                                },
+                                MessageType::Presence => {
+                                    handle_presence(&connected, &cluster, &presence, user_id, &username, &ws_msg).await
+                                }
+                                MessageType::Who => handle_who(&pool, &connected, &cluster, &direct_tx, &ws_msg),
+                                MessageType::Mute | MessageType::Kick | MessageType::Ban => {
+                                    match current_role(&state, user_id).await {
+                                        Ok(role) => handle_moderation(&state, user_id, role, &ws_msg).await,
+                                        Err(e) => Err(e),
+                                    }
+                                }
+                            },
+                        };
+
+                        // ── Report back to the sender ──
+                        match result {
+                            Ok(message_id) => {
+                                if client_msg_id.is_some() {
+                                    send_ack(&direct_tx, client_msg_id, message_id);
+                                }
+                            }
+                            Err(e) => send_error(&direct_tx, &e, client_msg_id),
+                        }
                     } else {
                         println!("Failed to parse WS message: {}", text);
                     }
@@ -104,172 +292,808 @@ pub async fn handle_connection(
     }
 
     // ── Cleanup on disconnect ──
-    if let (Some(uid), Some(idx)) = (authenticated_user_id, sender_index) {
-        connected_users::deregister(&connected, uid, idx).await;
+    for (_, forwarder) in conn.joined_rooms.drain() {
+        forwarder.abort();
+    }
+    // Time to flush a close frame, then the socket is dropped even if the client stopped reading
+    tokio::spawn(async move {
+        if tokio::time::timeout(WRITE_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+    });
+    if let (Some(uid), Some(id), Some(uname)) = (authenticated_user_id, conn_id, authenticated_username) {
+        if connected_users::deregister(&connected, uid, id).await {
+            went_offline(&connected, &cluster, &presence, &pool, uid, Some(uname)).await;
+        }
     }
 }
 
+/// `user_id` has no socket left here: tell everyone they're offline, unless another instance
+/// still has one or they came back meanwhile. `username` is used if the database can't say.
+pub async fn went_offline(
+    connected: &ConnectedUsers,
+    cluster: &Cluster,
+    presence: &Presence,
+    pool: &sqlx::MySqlPool,
+    user_id: i32,
+    username: Option<String>,
+) {
+    presence::clear(presence, user_id).await;
+    let (connected, cluster, pool) = (connected.clone(), cluster.clone(), pool.clone());
+    // The probe can take a while, this shouldn't
+    tokio::spawn(async move {
+        if cluster.who(user_id).await.is_some() {
+            return;
+        }
+        // A reload may have reconnected them during the probe, that socket said they're online
+        if connected_users::is_online(&connected, user_id).await {
+            return;
+        }
+        // Under the name they have now, they may have renamed themselves since
+        let username = match crate::tables::user_db::get_usernames_by_ids(&pool, &[user_id]).await {
+            Ok(names) => names.into_iter().next().or(username),
+            Err(_) => username,
+        };
+        if let Some(username) = username {
+            send_presence(&connected, &cluster, &username, Status::Offline).await;
+        }
+    });
+}
+
 // ─── Helpers ────────────────────────────────────────────────────────────────
 
-/// Validate session token and return (user_id, username).
-async fn resolve_session(
-    pool: &sqlx::MySqlPool,
-    session_cache: &Arc<RwLock<HashSet<String>>>,
-    token: &str,
-) -> Option<(i32, String)> {
-    let cache = session_cache.read().await;
-    if !cache.contains(token) {
-        return None;
-    }
-    drop(cache);
+/// What the receive loop keeps about its own socket.
+struct ConnState {
+    /// Sender half of this socket's direct channel, closing it ends the receive loop
+    direct_tx: Outbox,
+    /// Forwarder task per joined room, aborting it unsubscribes the socket
+    joined_rooms: HashMap<String, JoinHandle<()>>,
+    /// When this socket last had a typing frame relayed
+    last_typing: Option<std::time::Instant>,
+    /// This connection's share of the rate limits
+    buckets: ConnBuckets,
+    /// When this socket last updated its session's `last_seen`
+    last_touch: Option<std::time::Instant>,
+}
 
-    match crate::tables::user_db::get_user_by_token(pool, token).await {
-        Ok(user) => Some((user.id, user.username)),
-        Err(_) => None,
+/// Add an authenticated socket to the connected users, going online if it's the user's first
+/// and delivering what was sent to them while they were away.
+async fn register_connection(
+    state: &ChatState,
+    conn: &ConnState,
+    opened_at: chrono::DateTime<chrono::Utc>,
+    client: &ClientInfo,
+    user_id: i32,
+    username: &str,
+    // Registered as its `session_token`, see `Principal::connection_label`
+    label: &str,
+) -> ConnId {
+    let (id, first) = connected_users::register(
+        &state.connected,
+        user_id,
+        ConnHandle {
+            sender: conn.direct_tx.clone(),
+            opened_at,
+            client: client.clone(),
+            session_token: label.to_string(),
+        },
+    )
+    .await;
+    if first {
+        send_presence(&state.connected, &state.cluster, username, Status::Online).await;
     }
+    flush_pending_private(&state.pool, &state.connected, user_id).await;
+    id
 }
 
-/// Send an error frame to a single connection.
-fn send_error(direct_tx: &mpsc::UnboundedSender<Message>, msg: &str) {
-    let out = WsOutgoing {
-        msg_type: OutgoingType::Error,
-        username: "system".to_string(),
-        content: msg.to_string(),
-        to_username: None,
-        users: None,
-        extra: None,
-    };
+/// The sender's role as it is now, for the few messages that depend on it.
+async fn current_role(state: &ChatState, user_id: i32) -> Result<Role, String> {
+    state
+        .session_store
+        .standing(&state.pool, user_id)
+        .await
+        .map(|standing| standing.role)
+        .map_err(|_| "Database is not online, please try again later".to_string())
+}
+
+/// Forward everything sent to `room` into this connection's direct channel.
+fn forward_room(room: &Room, direct_tx: Outbox) -> JoinHandle<()> {
+    let mut room_rx = room.tx.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match room_rx.recv().await {
+                Ok(msg) => {
+                    if !direct_tx.send(Message::text(msg)) {
+                        break;
+                    }
+                }
+                // A slow socket only misses the frames it fell behind on
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Send a frame to everyone in `room`, here and on every other instance.
+fn send_to_room(cluster: &Cluster, room: &Room, frame: String) {
+    cluster.publish(ClusterEvent::RoomBroadcast {
+        room: room.name.clone(),
+        frame: frame.clone(),
+    });
+    let _ = room.tx.send(frame);
+}
+
+/// Send a frame to every connected user, here and on every other instance.
+pub async fn send_to_everyone(connected: &ConnectedUsers, cluster: &Cluster, frame: String) {
+    cluster.publish(ClusterEvent::AllUsers { frame: frame.clone() });
+    connected_users::send_to_all(connected, &frame).await;
+}
+
+/// Tell everyone `username` is now `status`.
+async fn send_presence(connected: &ConnectedUsers, cluster: &Cluster, username: &str, status: Status) {
+    let out = WsOutgoing::new(OutgoingType::Presence, username, status.as_str());
     if let Ok(json) = serde_json::to_string(&out) {
+        send_to_everyone(connected, cluster, json).await;
+    }
+}
+
+/// Name of the room a message targets, `global` when the client didn't say.
+fn target_room_name(ws_msg: &WsIncoming) -> &str {
+    ws_msg.metadata.room.as_deref().unwrap_or(rooms::DEFAULT_ROOM)
+}
+
+/// Resolve the room a `Broadcast`/`Ephemeral` targets, only if this connection joined it.
+async fn joined_room(rooms: &Rooms, conn: &ConnState, ws_msg: &WsIncoming) -> Result<Room, String> {
+    let name = target_room_name(ws_msg);
+    if !conn.joined_rooms.contains_key(name) {
+        return Err(format!("You have not joined room '{}'", name));
+    }
+    rooms::get(rooms, name)
+        .await
+        .ok_or_else(|| format!("Room '{}' no longer exists", name))
+}
+
+/// Checks on metadata shared by every message type.
+fn validate_metadata(ws_msg: &WsIncoming) -> Result<(), String> {
+    if let Some(sent_when) = &ws_msg.metadata.sent_when_override {
+        if chrono::DateTime::parse_from_rfc3339(sent_when).is_err() {
+            return Err("'sent_when_override' must be an RFC3339 timestamp".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Muted users can't talk, in rooms or privately, nor edit or react.
+async fn check_not_muted(state: &ChatState, user_id: i32) -> Result<(), String> {
+    match state.session_store.standing(&state.pool, user_id).await {
+        Ok(standing) => match standing.mute() {
+            None => Ok(()),
+            Some(mute) => Err(format!("You are muted {}", mute.describe())),
+        },
+        Err(_) => Err("Database is not online, please try again later".to_string()),
+    }
+}
+
+/// Start a frame that answers `ws_msg`, carrying back its client id and timestamp.
+fn reply_frame(msg_type: OutgoingType, username: &str, content: &str, ws_msg: &WsIncoming) -> WsOutgoing {
+    WsOutgoing {
+        client_msg_id: ws_msg.metadata.client_msg_id.clone(),
+        sent_when: ws_msg.metadata.sent_when_override.clone(),
+        ..WsOutgoing::new(msg_type, username, content)
+    }
+}
+
+/// Send a frame to a single connection.
+fn send_direct(direct_tx: &Outbox, out: &WsOutgoing) {
+    if let Ok(json) = serde_json::to_string(out) {
         let _ = direct_tx.send(Message::text(json));
     }
 }
 
+/// Send an error frame to a single connection, tied to the message that caused it.
+fn send_error(direct_tx: &Outbox, msg: &str, client_msg_id: Option<String>) {
+    let out = WsOutgoing {
+        client_msg_id,
+        ..WsOutgoing::new(OutgoingType::Error, "system", msg)
+    };
+    send_direct(direct_tx, &out);
+}
+
+/// Tell the sender they're going too fast, and when they may send `msg_type` again.
+fn send_rate_limited(
+    direct_tx: &Outbox,
+    msg_type: MessageType,
+    wait: std::time::Duration,
+    client_msg_id: Option<String>,
+) {
+    let retry_after_ms = (wait.as_millis() as u64).max(1);
+    let msg = format!(
+        "Too many '{}' messages, retry in {:.1}s",
+        msg_type.as_str(),
+        retry_after_ms as f64 / 1000.0
+    );
+    let out = WsOutgoing {
+        client_msg_id,
+        retry_after_ms: Some(retry_after_ms),
+        ..WsOutgoing::new(OutgoingType::Error, "system", &msg)
+    };
+    send_direct(direct_tx, &out);
+}
+
+/// Confirm to the sender that the message with `client_msg_id` was accepted.
+fn send_ack(direct_tx: &Outbox, client_msg_id: Option<String>, message_id: Option<i64>) {
+    let out = WsOutgoing {
+        client_msg_id,
+        message_id,
+        ..WsOutgoing::new(OutgoingType::Ack, "system", "")
+    };
+    send_direct(direct_tx, &out);
+}
+
 // ─── Message type handlers ──────────────────────────────────────────────────
 
+/// Id of whatever the handler stored (for the ack), or the error to send back.
+type HandlerResult = Result<Option<i64>, String>;
+
 async fn handle_broadcast(
     pool: &sqlx::MySqlPool,
-    tx: &broadcast::Sender<String>,
+    cluster: &Cluster,
+    room: &Room,
     user_id: i32,
     username: &str,
-    content: &str,
-) {
-    if crate::tables::user_db::save_message(pool, user_id, content)
-        .await
-        .is_err()
-    {
-        println!("Failed to save broadcast message from user {}", user_id);
-        return;
-    }
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    // The same instant is stored and sent, so history and live frames order alike
+    let now = chrono::Utc::now();
+    let message_id = match crate::tables::user_db::save_message(pool, user_id, room.id, &ws_msg.content, now).await {
+        Ok(id) => id,
+        Err(_) => {
+            println!("Failed to save broadcast message from user {}", user_id);
+            return Err("Database is not online, please try again later".to_string());
+        }
+    };
 
     let out = WsOutgoing {
-        msg_type: OutgoingType::Broadcast,
-        username: username.to_string(),
-        content: content.to_string(),
-        to_username: None,
-        users: None,
-        extra: None,
+        timestamp: format_timestamp(now),
+        message_id: Some(message_id),
+        room: Some(room.name.clone()),
+        ..reply_frame(OutgoingType::Broadcast, username, &ws_msg.content, ws_msg)
     };
     if let Ok(json) = serde_json::to_string(&out) {
-        let _ = tx.send(json);
+        send_to_room(cluster, room, json);
     }
+    Ok(Some(message_id))
 }
 
 async fn handle_private(
     pool: &sqlx::MySqlPool,
     connected: &ConnectedUsers,
-    sender_direct_tx: &mpsc::UnboundedSender<Message>,
+    cluster: &Cluster,
+    sender_direct_tx: &Outbox,
     sender_id: i32,
     sender_username: &str,
     ws_msg: &WsIncoming,
-) {
+) -> HandlerResult {
     let to_username = match &ws_msg.metadata.to_username {
         Some(name) => name.clone(),
-        None => {
-            send_error(sender_direct_tx, "Private message requires 'to_username' in metadata");
-            return;
-        }
+        None => return Err("Private message requires 'to_username' in metadata".to_string()),
     };
 
     // Resolve target user to get their ID
     let target_user = match crate::tables::user_db::find_user_by_username(pool, &to_username).await {
         Ok(u) => u,
-        Err(_) => {
-            send_error(sender_direct_tx, &format!("User '{}' not found", to_username));
-            return;
-        }
+        Err(_) => return Err(format!("User '{}' not found", to_username)),
+    };
+
+    // Store first so the message survives the recipient being offline
+    let pm_id = match crate::tables::private_message_db::save_private_message(
+        pool,
+        sender_id,
+        target_user.id,
+        &ws_msg.content,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(_) => return Err("Database is not online, please try again later".to_string()),
     };
 
     let out = WsOutgoing {
-        msg_type: OutgoingType::Private,
-        username: sender_username.to_string(),
-        content: ws_msg.content.clone(),
+        message_id: Some(pm_id),
         to_username: Some(to_username.clone()),
-        users: None,
-        extra: None,
+        ..reply_frame(OutgoingType::Private, sender_username, &ws_msg.content, ws_msg)
     };
     let json = match serde_json::to_string(&out) {
         Ok(j) => j,
-        Err(_) => return,
+        Err(_) => return Ok(Some(pm_id)),
+    };
+
+    // Echo back to sender so they see their own PM, delivered or queued
+    if sender_id != target_user.id {
+        let _ = sender_direct_tx.send(Message::text(json.clone()));
+    }
+
+    // First try direct delivery. Claimed before sending, a new connection of the
+    // recipient may be flushing its queue at the same time
+    if connected_users::is_online(connected, target_user.id).await {
+        if matches!(crate::tables::private_message_db::mark_delivered(pool, pm_id).await, Ok(true))
+            && connected_users::send_to_user(connected, target_user.id, &json).await == 0
+        {
+            // Gone in the meantime, keep it for their next connection
+            let _ = crate::tables::private_message_db::mark_undelivered(pool, pm_id).await;
+        }
+        return Ok(Some(pm_id));
+    }
+
+    // Not connected here, ask the other instances. Done in the background so this
+    // socket keeps reading while the probe waits for an answer
+    let cluster = cluster.clone();
+    let recipient_id = target_user.id;
+    tokio::spawn(async move {
+        if let Some(instance) = cluster.who(recipient_id).await {
+            cluster.publish(ClusterEvent::PrivateDelivery {
+                target: instance,
+                user_id: recipient_id,
+                pm_id,
+                frame: json,
+            });
+        }
+        // Otherwise it stays queued until the recipient connects, see `flush_pending_private`
+    });
+    Ok(Some(pm_id))
+}
+
+/// Deliver every private message that was sent to `user_id` while they were offline.
+async fn flush_pending_private(pool: &sqlx::MySqlPool, connected: &ConnectedUsers, user_id: i32) {
+    let pending = match crate::tables::private_message_db::get_undelivered(pool, user_id).await {
+        Ok(pending) => pending,
+        Err(_) => {
+            println!("Failed to load pending private messages for user {}", user_id);
+            return;
+        }
     };
 
-    // First try direct delivery
-    let delivered = connected_users::send_to_user(connected, target_user.id, &json).await;
-    if delivered > 0 {
-        // Echo back to sender so they see their own PM
-        if sender_id != target_user.id {
-            let _ = sender_direct_tx.send(Message::text(json));
+    for pm in pending {
+        // Claim it first, another tab of the same user may be flushing too
+        if !matches!(crate::tables::private_message_db::mark_delivered(pool, pm.id).await, Ok(true)) {
+            continue;
+        }
+        // Stamped with when it was sent, not when it finally arrived
+        let out = WsOutgoing {
+            timestamp: format_timestamp(pm.created_at),
+            message_id: Some(pm.id),
+            to_username: Some(pm.to_username),
+            ..WsOutgoing::new(OutgoingType::Private, &pm.from_username, &pm.content)
+        };
+        let Ok(json) = serde_json::to_string(&out) else {
+            continue;
+        };
+        if connected_users::send_to_user(connected, user_id, &json).await == 0 {
+            let _ = crate::tables::private_message_db::mark_undelivered(pool, pm.id).await;
         }
-        return;
     }
+}
 
-    let who_probe = WsOutgoing {
-        msg_type: OutgoingType::Who,
-        username: "system".to_string(),
-        content: format!("looking for user '{}'", to_username),
-        to_username: Some(to_username.clone()),
-        users: None,
-        extra: None,
+/// Ephemeral: broadcast to the room (not saved to DB) and forward any extra metadata
+/// for client-to-client custom communications.
+fn handle_ephemeral(cluster: &Cluster, room: &Room, username: &str, ws_msg: &WsIncoming) -> HandlerResult {
+    let out = WsOutgoing {
+        room: Some(room.name.clone()),
+        extra: ws_msg.extra.clone(),
+        ..reply_frame(OutgoingType::Ephemeral, username, &ws_msg.content, ws_msg)
     };
-    if let Ok(who_json) = serde_json::to_string(&who_probe) {
-        // Log the probe (in a multi-server setup this would go to other instances)
-        println!("[WHO probe] {}", who_json);
+    if let Ok(json) = serde_json::to_string(&out) {
+        send_to_room(cluster, room, json);
     }
+    Ok(None)
+}
 
-    // Wait briefly for the user to potentially appear
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+/// Join: subscribe this connection to a room (creating it on first use) and
+/// announce the new member to everyone in it, the joiner included.
+async fn handle_join(
+    pool: &sqlx::MySqlPool,
+    cluster: &Cluster,
+    rooms: &Rooms,
+    conn: &mut ConnState,
+    user_id: i32,
+    username: &str,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let name = match &ws_msg.metadata.room {
+        Some(name) if rooms::is_valid_name(name) => name.clone(),
+        Some(_) => return Err("Room names are 1-50 letters, digits, '-' or '_'".to_string()),
+        None => return Err("Join requires 'room' in metadata".to_string()),
+    };
+    if conn.joined_rooms.contains_key(&name) {
+        return Err(format!("Already in room '{}'", name));
+    }
+
+    let room = match rooms::get_or_create(pool, rooms, &name, Some(user_id)).await {
+        Ok(room) => room,
+        Err(_) => return Err("Database is not online, please try again later".to_string()),
+    };
+    conn.joined_rooms.insert(name, forward_room(&room, conn.direct_tx.clone()));
+
+    send_membership(cluster, &room, OutgoingType::Join, username);
+    Ok(None)
+}
 
-    // Re-check after timeout
-    let delivered = connected_users::send_to_user(connected, target_user.id, &json).await;
-    if delivered > 0 {
-        if sender_id != target_user.id {
-            let _ = sender_direct_tx.send(Message::text(json));
+/// Leave: stop forwarding the room to this connection and announce the departure
+/// to the remaining members, plus a copy to the leaver as confirmation.
+async fn handle_leave(
+    cluster: &Cluster,
+    rooms: &Rooms,
+    conn: &mut ConnState,
+    username: &str,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let name = match &ws_msg.metadata.room {
+        Some(name) => name.clone(),
+        None => return Err("Leave requires 'room' in metadata".to_string()),
+    };
+    match conn.joined_rooms.remove(&name) {
+        Some(forwarder) => forwarder.abort(),
+        None => return Err(format!("You have not joined room '{}'", name)),
+    }
+
+    if let Some(room) = rooms::get(rooms, &name).await {
+        if let Some(json) = send_membership(cluster, &room, OutgoingType::Leave, username) {
+            let _ = conn.direct_tx.send(Message::text(json));
         }
+    }
+    Ok(None)
+}
+
+/// Edit/Delete: change one of your stored broadcast messages and tell its room,
+/// so connected clients update it in place.
+async fn handle_edit(
+    pool: &sqlx::MySqlPool,
+    cluster: &Cluster,
+    rooms: &Rooms,
+    user_id: i32,
+    username: &str,
+    role: Role,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let message_id = match ws_msg.metadata.message_id {
+        Some(id) => id,
+        None => return Err("Edit and delete require 'message_id' in metadata".to_string()),
+    };
+    let message = match crate::tables::message_db::get_message(pool, message_id).await {
+        Ok(message) => message,
+        Err(_) => return Err(format!("Message {} not found", message_id)),
+    };
+    // Moderators may take down anyone's message, but words are only ever put in your own mouth
+    let moderating = ws_msg.msg_type == MessageType::Delete && role.can(Permission::DeleteAnyMessage);
+    if message.user_id != user_id && !moderating {
+        return Err("You can only change your own messages".to_string());
+    }
+    if message.deleted_at.is_some() {
+        return Err(format!("Message {} was deleted", message_id));
+    }
+
+    let now = chrono::Utc::now();
+    let (msg_type, stored) = if ws_msg.msg_type == MessageType::Edit {
+        if ws_msg.content.is_empty() {
+            return Err("An edit can't be empty, delete the message instead".to_string());
+        }
+        (
+            OutgoingType::Edit,
+            crate::tables::message_db::edit_message(pool, message_id, user_id, &ws_msg.content, now).await,
+        )
     } else {
-        // Void the message — user never appeared
-        send_error(
-            sender_direct_tx,
-            &format!("User '{}' is not reachable. Message voided.", to_username),
-        );
+        (
+            OutgoingType::Delete,
+            crate::tables::message_db::delete_message(pool, message_id, user_id, now).await,
+        )
+    };
+    if stored.is_err() {
+        return Err("Database is not online, please try again later".to_string());
+    }
+
+    let content = if msg_type == OutgoingType::Edit { ws_msg.content.as_str() } else { "" };
+    let out = WsOutgoing {
+        timestamp: format_timestamp(now),
+        message_id: Some(message_id),
+        room: Some(message.room.clone()),
+        ..reply_frame(msg_type, username, content, ws_msg)
+    };
+    // The room may not be loaded here if nobody on this instance joined it
+    if let (Ok(room), Ok(json)) = (
+        rooms::get_or_create(pool, rooms, &message.room, None).await,
+        serde_json::to_string(&out),
+    ) {
+        send_to_room(cluster, &room, json);
     }
+    Ok(Some(message_id))
 }
 
-/// Ephemeral: broadcast (not saved to DB) and forward any extra metadata
-/// for client-to-client custom communications.
-fn handle_ephemeral(
-    tx: &broadcast::Sender<String>,
+/// Reactions are a single emoji, not free text: one emoji code point with its variation selector,
+/// skin tone or tag modifiers, several joined with zero width joiners, a flag or a keycap.
+/// Control and other invisible characters are refused, they could hide text in a reaction.
+fn is_valid_emoji(emoji: &str) -> bool {
+    if emoji.is_empty() || emoji.len() > 32 {
+        return false;
+    }
+    let chars: Vec<char> = emoji.chars().collect();
+    match chars.as_slice() {
+        [c, '\u{20E3}'] | [c, '\u{FE0F}', '\u{20E3}'] => return matches!(c, '0'..='9' | '#' | '*'),
+        [a, b] if is_regional_indicator(*a) && is_regional_indicator(*b) => return true,
+        _ => {}
+    }
+
+    let mut expect_base = true;
+    let mut prev = '\0';
+    for &c in &chars {
+        let valid = if expect_base {
+            expect_base = false;
+            is_emoji_base(c)
+        } else {
+            match c {
+                '\u{FE0E}' | '\u{FE0F}' | '\u{1F3FB}'..='\u{1F3FF}' => true,
+                '\u{200D}' => {
+                    expect_base = true;
+                    true
+                }
+                // Subdivision flags, a black flag followed by tag letters
+                '\u{E0020}'..='\u{E007F}' => prev == '\u{1F3F4}' || ('\u{E0020}'..='\u{E007E}').contains(&prev),
+                _ => false,
+            }
+        };
+        if !valid {
+            return false;
+        }
+        prev = c;
+    }
+    let open_tags = ('\u{E0020}'..='\u{E007E}').contains(&prev);
+    !expect_base && !open_tags
+}
+
+/// Code points in the blocks emoji come from, without the modifiers and flag letters.
+fn is_emoji_base(c: char) -> bool {
+    match c {
+        '\u{1F1E6}'..='\u{1F1FF}' | '\u{1F3FB}'..='\u{1F3FF}' => false,
+        '\u{1F000}'..='\u{1FAFF}'
+        | '\u{2190}'..='\u{21FF}'
+        | '\u{2300}'..='\u{23FF}'
+        | '\u{25A0}'..='\u{27BF}'
+        | '\u{2B00}'..='\u{2BFF}' => true,
+        '\u{00A9}' | '\u{00AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}' | '\u{24C2}'
+        | '\u{2934}' | '\u{2935}' | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}' => true,
+        _ => false,
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Reaction: add or remove an emoji on a stored message and send the message's
+/// new totals to its room.
+async fn handle_reaction(
+    pool: &sqlx::MySqlPool,
+    cluster: &Cluster,
+    rooms: &Rooms,
+    user_id: i32,
     username: &str,
-    content: &str,
-    extra: Option<serde_json::Value>,
-) {
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let message_id = match ws_msg.metadata.message_id {
+        Some(id) => id,
+        None => return Err("Reaction requires 'message_id' in metadata".to_string()),
+    };
+    let emoji = ws_msg.content.as_str();
+    if !is_valid_emoji(emoji) {
+        return Err("A reaction is a single emoji".to_string());
+    }
+    let message = match crate::tables::message_db::get_message(pool, message_id).await {
+        Ok(message) => message,
+        Err(_) => return Err(format!("Message {} not found", message_id)),
+    };
+    if message.deleted_at.is_some() {
+        return Err(format!("Message {} was deleted", message_id));
+    }
+
+    let stored = if ws_msg.metadata.remove {
+        crate::tables::reaction_db::remove_reaction(pool, message_id, user_id, emoji).await
+    } else {
+        crate::tables::reaction_db::add_reaction(pool, message_id, user_id, emoji).await
+    };
+    let reactions = match stored {
+        Ok(()) => crate::tables::reaction_db::get_reaction_counts(pool, message_id).await,
+        Err(e) => Err(e),
+    };
+    let reactions = match reactions {
+        Ok(reactions) => reactions,
+        Err(_) => return Err("Database is not online, please try again later".to_string()),
+    };
+
+    let out = WsOutgoing {
+        message_id: Some(message_id),
+        room: Some(message.room.clone()),
+        reactions: Some(reactions),
+        ..reply_frame(OutgoingType::Reaction, username, emoji, ws_msg)
+    };
+    if let (Ok(room), Ok(json)) = (
+        rooms::get_or_create(pool, rooms, &message.room, None).await,
+        serde_json::to_string(&out),
+    ) {
+        send_to_room(cluster, &room, json);
+    }
+    Ok(Some(message_id))
+}
+
+/// Typing: relay "`username` is typing" to the room, throttled per connection.
+/// Receivers drop the indicator at `expires_at` unless the typist renews it.
+fn handle_typing(
+    cluster: &Cluster,
+    room: &Room,
+    conn: &mut ConnState,
+    username: &str,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let now = std::time::Instant::now();
+    if conn.last_typing.is_some_and(|last| now.duration_since(last) < TYPING_MIN_INTERVAL) {
+        // The previous indicator is still showing, nothing to renew yet
+        return Ok(None);
+    }
+    conn.last_typing = Some(now);
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(TYPING_TTL_SECS);
     let out = WsOutgoing {
-        msg_type: OutgoingType::Ephemeral,
-        username: username.to_string(),
-        content: content.to_string(),
-        to_username: None,
-        users: None,
-        extra,
+        room: Some(room.name.clone()),
+        expires_at: Some(format_timestamp(expires_at)),
+        ..reply_frame(OutgoingType::Typing, username, "", ws_msg)
     };
     if let Ok(json) = serde_json::to_string(&out) {
-        let _ = tx.send(json);
+        send_to_room(cluster, room, json);
+    }
+    Ok(None)
+}
+
+/// Presence: set an explicit status and tell everyone about it.
+async fn handle_presence(
+    connected: &ConnectedUsers,
+    cluster: &Cluster,
+    presence: &Presence,
+    user_id: i32,
+    username: &str,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let status = match Status::parse_settable(ws_msg.content.trim()) {
+        Some(status) => status,
+        None => return Err("Presence must be one of: online, away, dnd".to_string()),
+    };
+    if presence::status_of(presence, user_id).await == status {
+        return Ok(None);
+    }
+    presence::set_status(presence, user_id, status).await;
+    send_presence(connected, cluster, username, status).await;
+    Ok(None)
+}
+
+/// Who: answer with the usernames of everyone online. Done in the background since
+/// asking the other instances takes a moment.
+fn handle_who(
+    pool: &sqlx::MySqlPool,
+    connected: &ConnectedUsers,
+    cluster: &Cluster,
+    direct_tx: &Outbox,
+    ws_msg: &WsIncoming,
+) -> HandlerResult {
+    let (pool, connected, cluster, direct_tx) = (pool.clone(), connected.clone(), cluster.clone(), direct_tx.clone());
+    let client_msg_id = ws_msg.metadata.client_msg_id.clone();
+    tokio::spawn(async move {
+        match presence::online_usernames(&pool, &connected, &cluster).await {
+            Ok(users) => {
+                let out = WsOutgoing {
+                    client_msg_id,
+                    users: Some(users),
+                    ..WsOutgoing::new(OutgoingType::Who, "system", "")
+                };
+                send_direct(&direct_tx, &out);
+            }
+            Err(_) => send_error(&direct_tx, "Database is not online, please try again later", client_msg_id),
+        }
+    });
+    Ok(None)
+}
+
+/// Mute, kick or ban: moderate `metadata.to_username`. `metadata.remove` lifts a mute or ban.
+async fn handle_moderation(state: &ChatState, user_id: i32, role: Role, ws_msg: &WsIncoming) -> HandlerResult {
+    let target = match &ws_msg.metadata.to_username {
+        Some(name) => name.as_str(),
+        None => return Err("Moderation requires 'to_username' in metadata".to_string()),
+    };
+    let kind = match ws_msg.msg_type {
+        MessageType::Mute => SanctionKind::Mute,
+        MessageType::Ban => SanctionKind::Ban,
+        _ => return moderation::kick(state, role, target).await.map(|_| None).map_err(|e| e.message().to_string()),
+    };
+
+    if ws_msg.metadata.remove {
+        return match moderation::lift(state, role, target, kind).await {
+            Ok(true) => Ok(None),
+            Ok(false) if kind == SanctionKind::Mute => Err(format!("'{}' is not muted", target)),
+            Ok(false) => Err(format!("'{}' is not banned", target)),
+            Err(e) => Err(e.message().to_string()),
+        };
     }
-}
\ No newline at end of file
+    let request = SanctionRequest {
+        duration_secs: ws_msg.metadata.duration_secs,
+        reason: Some(ws_msg.content.clone()),
+    };
+    moderation::sanction(state, user_id, role, target, kind, &request)
+        .await
+        .map(|_| None)
+        .map_err(|e| e.message().to_string())
+}
+
+/// Tell a room that `username` joined or left it. Returns the frame that was sent.
+fn send_membership(
+    cluster: &Cluster,
+    room: &Room,
+    msg_type: OutgoingType,
+    username: &str,
+) -> Option<String> {
+    let out = WsOutgoing {
+        room: Some(room.name.clone()),
+        ..WsOutgoing::new(msg_type, username, "")
+    };
+    let json = serde_json::to_string(&out).ok()?;
+    send_to_room(cluster, room, json.clone());
+    Some(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_emoji_are_reactions() {
+        for emoji in [
+            "👍",
+            "❤️",
+            "👍🏽",
+            "👨‍👩‍👧‍👦",
+            "🏳️‍🌈",
+            "🇫🇷",
+            "🏴\u{E0067}\u{E0062}\u{E0065}\u{E006E}\u{E0067}\u{E007F}",
+            "#️⃣",
+            "1⃣",
+            "©️",
+        ] {
+            assert!(is_valid_emoji(emoji), "{:?} was refused", emoji);
+        }
+    }
+
+    #[test]
+    fn text_and_invisible_characters_are_not() {
+        for emoji in [
+            "",
+            "a",
+            "lol",
+            "👍 ",
+            "👍👎",
+            "👍a",
+            "é",
+            "\u{200B}",
+            "👍\u{200B}",
+            "\u{202E}👍",
+            "👍\u{200D}",
+            "\u{200D}👍",
+            "\u{FE0F}",
+            "🏽",
+            "🇫",
+            "🇫🇷🇩🇪",
+            "👍\u{E0067}\u{E007F}",
+            "🏴\u{E0067}\u{E0062}",
+            "\u{7}",
+            "a⃣",
+            "👨‍👩‍👧‍👦‍👨‍👩‍👧‍👦",
+        ] {
+            assert!(!is_valid_emoji(emoji), "{:?} was accepted", emoji);
+        }
+    }
+}
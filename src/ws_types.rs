@@ -1,3 +1,4 @@
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 
 /// What the client sends over WebSocket
@@ -13,21 +14,108 @@ pub struct WsIncoming {
     pub extra: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageType {
     Broadcast,
     Private,
     Ephemeral,
+    /// Subscribe this connection to `metadata.room`, creating the room if needed
+    Join,
+    /// Unsubscribe this connection from `metadata.room`
+    Leave,
+    /// Replace the content of your message `metadata.message_id` with `content`
+    Edit,
+    /// Delete your message `metadata.message_id`
+    Delete,
+    /// React to message `metadata.message_id` with the emoji in `content`
+    Reaction,
+    /// "I'm typing" in `metadata.room`, repeat while still typing
+    Typing,
+    /// Set your status to `content`: online, away or dnd
+    Presence,
+    /// Ask who is online, answered with a `who` frame listing them in `users`
+    Who,
+    /// Moderators: mute `metadata.to_username` for `metadata.duration_secs` (or for good), `content` is the reason
+    Mute,
+    /// Moderators: close every connection of `metadata.to_username`
+    Kick,
+    /// Moderators: ban `metadata.to_username` for `metadata.duration_secs` (or for good), `content` is the reason
+    Ban,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; 14] = [
+        MessageType::Broadcast,
+        MessageType::Private,
+        MessageType::Ephemeral,
+        MessageType::Join,
+        MessageType::Leave,
+        MessageType::Edit,
+        MessageType::Delete,
+        MessageType::Reaction,
+        MessageType::Typing,
+        MessageType::Presence,
+        MessageType::Who,
+        MessageType::Mute,
+        MessageType::Kick,
+        MessageType::Ban,
+    ];
+
+    /// The `type` the client sends.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageType::Broadcast => "broadcast",
+            MessageType::Private => "private",
+            MessageType::Ephemeral => "ephemeral",
+            MessageType::Join => "join",
+            MessageType::Leave => "leave",
+            MessageType::Edit => "edit",
+            MessageType::Delete => "delete",
+            MessageType::Reaction => "reaction",
+            MessageType::Typing => "typing",
+            MessageType::Presence => "presence",
+            MessageType::Who => "who",
+            MessageType::Mute => "mute",
+            MessageType::Kick => "kick",
+            MessageType::Ban => "ban",
+        }
+    }
+
+    /// Says something others get to see, which muted users can't.
+    pub fn is_content(self) -> bool {
+        matches!(
+            self,
+            MessageType::Broadcast
+                | MessageType::Private
+                | MessageType::Ephemeral
+                | MessageType::Edit
+                | MessageType::Reaction
+        )
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct IncomingMetadata {
-    pub session_id: String,
+    /// Only needed when the connection wasn't authenticated at upgrade
+    pub session_id: Option<String>,
     /// Target username — required for `Private` type
     pub to_username: Option<String>,
-    /// Optional client-provided timestamp override
+    /// Room for `Broadcast`/`Ephemeral` (defaults to `global`), required for `Join`/`Leave`
+    pub room: Option<String>,
+    /// Stored message an `Edit`/`Delete`/`Reaction` refers to
+    pub message_id: Option<i64>,
+    /// On `Reaction`: take the emoji back instead of adding it
+    #[serde(default)]
+    pub remove: bool,
+    /// How long a mute or ban lasts, omitted for a permanent one
+    pub duration_secs: Option<i64>,
+    /// Optional client-provided timestamp (RFC3339), echoed back as `sent_when`.
+    /// Ordering always uses the server `timestamp`
     pub sent_when_override: Option<String>,
+    /// Optional client-chosen id, echoed back on the resulting frames and on the
+    /// `ack`/`error` this message produces
+    pub client_msg_id: Option<String>,
 }
 
 /// What the server sends to clients
@@ -35,12 +123,37 @@ pub struct IncomingMetadata {
 pub struct WsOutgoing {
     #[serde(rename = "type")]
     pub msg_type: OutgoingType,
+    /// Server-assigned, unique per frame
+    pub id: String,
+    /// Server time the frame was created (RFC3339, UTC)
+    pub timestamp: String,
     pub username: String,
     pub content: String,
+    /// Id of the stored message, on broadcast and private frames and their acks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i64>,
+    /// The sender's `client_msg_id`, on the frames and ack/error it produced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    /// The sender's `sent_when_override`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_when: Option<String>,
     /// Present on private messages to indicate the recipient
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to_username: Option<String>,
-    /// Present on `who` responses (internal server probes)
+    /// Room the frame was sent to — absent on private and error frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Present on `reaction` frames: every emoji on the message and how many used it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<Vec<crate::tables::reaction_db::ReactionCount>>,
+    /// Present on `typing` frames: drop the indicator after this unless renewed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    /// Present on rate limit `error` frames: how long to wait before sending that type again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    /// Present on `who` responses: usernames of everyone online
     #[serde(skip_serializing_if = "Option::is_none")]
     pub users: Option<Vec<String>>,
     /// Forwarded extra data on ephemeral messages
@@ -48,7 +161,7 @@ pub struct WsOutgoing {
     pub extra: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum OutgoingType {
     Broadcast,
@@ -57,4 +170,50 @@ pub enum OutgoingType {
     /// Internal server probe — not a user-facing message type
     Who,
     Error,
+    /// The message with `client_msg_id` was accepted
+    Ack,
+    /// Message `message_id` in `room` now says `content`
+    Edit,
+    /// Message `message_id` in `room` was deleted
+    Delete,
+    /// `username` added or removed the emoji in `content` on message `message_id`
+    Reaction,
+    /// `username` is typing in `room` until `expires_at`
+    Typing,
+    /// `username` is now `content`: online, away, dnd or offline
+    Presence,
+    /// `username` renamed themselves to `content`
+    Rename,
+    /// A user joined the room in `room`
+    Join,
+    /// A user left the room in `room`
+    Leave,
+}
+
+impl WsOutgoing {
+    /// A frame with a fresh id and the current server time, optional fields empty.
+    pub fn new(msg_type: OutgoingType, username: &str, content: &str) -> Self {
+        WsOutgoing {
+            msg_type,
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: format_timestamp(Utc::now()),
+            username: username.to_string(),
+            content: content.to_string(),
+            message_id: None,
+            client_msg_id: None,
+            sent_when: None,
+            to_username: None,
+            room: None,
+            reactions: None,
+            expires_at: None,
+            retry_after_ms: None,
+            users: None,
+            extra: None,
+        }
+    }
+}
+
+/// Timestamp format used on the wire.
+pub fn format_timestamp(when: DateTime<Utc>) -> String {
+    when.to_rfc3339_opts(SecondsFormat::Millis, true)
 }